@@ -1,38 +1,86 @@
-use std::sync::Arc;
+use std::{
+    borrow::Cow, collections::BTreeMap, future::Future, path::PathBuf, sync::Arc, time::Duration,
+};
 
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
 
-#[derive(Clone, Debug)]
+/// The kind of service a user, room, or message is from. Identifiers are only unique within a
+/// service.
+#[derive(
+    Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceType {
+    Matrix,
+    Irc,
+    /// Generated by `carrier-pigeon-fake-messages`
+    Fake,
+    /// Not known, e.g. for messages read from a file which doesn't say
+    #[default]
+    Unknown,
+}
+
+impl std::fmt::Display for ServiceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Matrix => "matrix",
+            Self::Irc => "irc",
+            Self::Fake => "fake",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct User {
     pub display_name: Arc<str>,
     pub identifier: Arc<str>,
-    // TODO: identify service type?
+    #[serde(default)]
+    pub service: ServiceType,
     // TODO: do we care about icons? any other display information?
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Room {
     pub display_name: Arc<str>,
     pub identifier: Arc<str>,
-    // TODO: identify service type?
+    #[serde(default)]
+    pub service: ServiceType,
     // TODO: parent (space)?
 }
 
-#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+/// Identifies a message. Keys are ordered by timestamp first, and keys from different services
+/// are different even if their identifiers are the same.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct MessageKey {
     pub timestamp: DateTime<Utc>,
     pub identifier: Arc<str>,
-    // TODO: identify service type?
+    #[serde(default)]
+    pub service: ServiceType,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Message {
     pub key: MessageKey,
     pub sender: User,
     // TODO: spaces
     pub room: Room,
-    // TODO: threads, replies
+    // TODO: threads
+    /// The identifier of the message this is a reply to, if any.
+    #[serde(default)]
+    pub reply_to: Option<Arc<str>>,
     pub body: MessageBody,
+    /// When the message was received locally, as opposed to the sender's timestamp in the key.
+    #[serde(default)]
+    pub received_at: Option<DateTime<Utc>>,
+    /// When the message was last edited, if it has been.
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// The users who reacted with each emoji.
+    #[serde(default)]
+    pub reactions: BTreeMap<Arc<str>, Vec<User>>,
 }
 
 impl Message {
@@ -41,12 +89,651 @@ impl Message {
     }
 }
 
+/// A change to the messages, as sent by a backend.
 #[derive(Clone, Debug)]
+pub enum StreamEvent {
+    /// A message which was sent
+    New(Message),
+    /// A new version of a message which was already sent, with the same identifier
+    Edit(Message),
+    /// A message which was deleted
+    Delete(MessageKey),
+}
+
+impl From<Message> for StreamEvent {
+    fn from(message: Message) -> Self {
+        Self::New(message)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum MessageBody {
     Text(RichText),
+    /// A notice about the room, such as a user joining. The affected user is the message's
+    /// sender.
+    System(SystemEvent),
+    File(Attachment),
+    Image(Attachment),
     // TODO: other message types
 }
 
-// TODO: rich text
+impl MessageBody {
+    pub fn is_system(&self) -> bool {
+        matches!(self, Self::System(_))
+    }
+
+    /// The body as unformatted text. System events are described without the affected user.
+    pub fn plain_text(&self) -> Cow<'_, str> {
+        match self {
+            Self::Text(text) => text.plain_text(),
+            Self::System(SystemEvent::Joined) => "joined".into(),
+            Self::System(SystemEvent::Left) => "left".into(),
+            Self::System(SystemEvent::Other(text)) => (**text).into(),
+            Self::File(attachment) | Self::Image(attachment) => (*attachment.filename).into(),
+        }
+    }
+
+    pub fn attachment(&self) -> Option<&Attachment> {
+        match self {
+            Self::File(attachment) | Self::Image(attachment) => Some(attachment),
+            Self::Text(_) | Self::System(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemEvent {
+    Joined,
+    Left,
+    /// Any other notice, described by the text
+    Other(Arc<str>),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Attachment {
+    /// Where to download the attachment from, for an [`AttachmentResolver`]
+    pub url: Arc<str>,
+    /// The file name suggested by the sender
+    pub filename: Arc<str>,
+    /// The size in bytes, if known
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+/// Formatted text, as a sequence of spans. Newlines in the spans' text separate lines.
+///
+/// Text without any formatting is (de)serialized as a plain string.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(from = "RichTextRepr", into = "RichTextRepr")]
+pub struct RichText(pub Vec<RichSpan>);
+
+impl RichText {
+    /// Text without any formatting.
+    pub fn from_plain(text: impl Into<Arc<str>>) -> Self {
+        Self(vec![RichSpan::plain(text)])
+    }
+
+    /// Whether none of the text is formatted.
+    pub fn is_plain(&self) -> bool {
+        self.0.iter().all(RichSpan::is_plain)
+    }
+
+    /// Moves the `>` markers at the start of each line, each of which may be followed by a space,
+    /// into the quote depth of the line's spans. Lines of fenced code blocks, and spans which are
+    /// already quoted, are left as they are.
+    pub fn parse_quotes(self) -> Self {
+        let mut spans = Vec::new();
+        // the formatting and text of the span being built
+        let mut pending: Option<(RichSpan, String)> = None;
+        let mut line_start = true;
+        let mut depth = 0;
+        let mut code_block = false;
+        for span in self.0 {
+            let quoted = span.quote > 0;
+            for part in span.text.split_inclusive('\n') {
+                let mut part = part;
+                if quoted {
+                    depth = span.quote;
+                } else if line_start {
+                    let fence = part.trim_start().starts_with("```");
+                    (depth, part) = if code_block || fence {
+                        (0, part)
+                    } else {
+                        quote_markers(part)
+                    };
+                    code_block ^= fence;
+                }
+                line_start = part.ends_with('\n');
+                let format = RichSpan {
+                    text: "".into(),
+                    quote: depth,
+                    ..span.clone()
+                };
+                match &mut pending {
+                    Some((pending_format, text)) if *pending_format == format => {
+                        text.push_str(part)
+                    }
+                    _ => {
+                        spans.extend(pending.take().map(|(format, text)| RichSpan {
+                            text: text.into(),
+                            ..format
+                        }));
+                        pending = Some((format, part.to_owned()));
+                    }
+                }
+            }
+        }
+        spans.extend(pending.map(|(format, text)| RichSpan {
+            text: text.into(),
+            ..format
+        }));
+        Self(spans)
+    }
+
+    /// The text without its formatting, except that quoted lines start with a `>` marker for
+    /// each blockquote they're nested in.
+    pub fn plain_text(&self) -> Cow<'_, str> {
+        match self.0.as_slice() {
+            [] => "".into(),
+            [span] if span.quote == 0 => (*span.text).into(),
+            spans if spans.iter().all(|span| span.quote == 0) => spans
+                .iter()
+                .map(|span| &*span.text)
+                .collect::<String>()
+                .into(),
+            spans => {
+                let mut text = String::new();
+                let mut line_start = true;
+                for span in spans {
+                    for part in span.text.split_inclusive('\n') {
+                        if line_start {
+                            text.push_str(&"> ".repeat(span.quote));
+                        }
+                        text.push_str(part);
+                        line_start = part.ends_with('\n');
+                    }
+                }
+                text.into()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum RichTextRepr {
+    Plain(Arc<str>),
+    Spans(Vec<RichSpan>),
+}
+
+impl From<RichTextRepr> for RichText {
+    fn from(repr: RichTextRepr) -> Self {
+        match repr {
+            RichTextRepr::Plain(text) => Self::from_plain(text),
+            RichTextRepr::Spans(spans) => Self(spans),
+        }
+    }
+}
+
+impl From<RichText> for RichTextRepr {
+    fn from(text: RichText) -> Self {
+        match text.0.as_slice() {
+            [span] if span.is_plain() => Self::Plain(span.text.clone()),
+            _ if text.is_plain() => Self::Plain(text.plain_text().into()),
+            _ => Self::Spans(text.0),
+        }
+    }
+}
+
+/// A run of text with the same formatting.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RichSpan {
+    pub text: Arc<str>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub bold: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub italic: bool,
+    /// Inline code
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub code: bool,
+    /// Where the text links to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<Arc<str>>,
+    /// How many blockquotes the text is nested in
+    #[serde(skip_serializing_if = "is_zero")]
+    pub quote: usize,
+}
+
+impl RichSpan {
+    pub fn plain(text: impl Into<Arc<str>>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        !self.bold && !self.italic && !self.code && self.link.is_none() && self.quote == 0
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// Strips the `>` markers from the start of a line, each of which may be followed by a space,
+/// returning how many there were and the rest of the line.
+fn quote_markers(line: &str) -> (usize, &str) {
+    let mut depth = 0;
+    let mut rest = line.trim_start();
+    while let Some(quoted) = rest.strip_prefix('>') {
+        depth += 1;
+        rest = quoted.strip_prefix(' ').unwrap_or(quoted);
+    }
+    if depth == 0 {
+        (0, line)
+    } else {
+        (depth, rest)
+    }
+}
+
+/// A source of the current time, which can be replaced in tests.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A destination for outgoing messages.
+pub trait MessageSink {
+    fn capabilities(&self) -> SinkCapabilities {
+        Default::default()
+    }
+
+    /// Sends a message with the given body to the room.
+    fn send(
+        &self,
+        room: &Room,
+        body: MessageBody,
+    ) -> impl Future<Output = Result<MessageKey, SendError>> + Send;
+}
+
+/// A source of attachment contents.
+pub trait AttachmentResolver {
+    fn fetch(&self, url: &str) -> impl Future<Output = Result<Vec<u8>, FetchError>> + Send;
+}
+
+/// Fetches `file://` URLs from the local filesystem.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileUrlResolver;
+
+impl AttachmentResolver for FileUrlResolver {
+    fn fetch(&self, url: &str) -> impl Future<Output = Result<Vec<u8>, FetchError>> + Send {
+        let path = url
+            .strip_prefix("file://")
+            .and_then(percent_decode)
+            .map(PathBuf::from)
+            .ok_or_else(|| FetchError::UnsupportedUrl(url.to_owned()));
+        async move { Ok(tokio::fs::read(path?).await?) }
+    }
+}
+
+/// Decodes the `%XX` escapes in a URL path, or returns `None` if one is invalid or the result
+/// isn't UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("unsupported URL `{0}`")]
+    UnsupportedUrl(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Limitations of a [`MessageSink`].
+#[derive(Clone, Debug, Default)]
+pub struct SinkCapabilities {
+    /// The maximum length of a message body, if any.
+    pub max_message_length: Option<usize>,
+    /// How the length of a message body is measured.
+    pub length_unit: LengthUnit,
+    /// Whether [`MessageBody::File`] and [`MessageBody::Image`] bodies can be sent.
+    pub attachments: bool,
+}
+
+impl SinkCapabilities {
+    /// How much of the maximum message length is left after `text`. Negative if `text` is over
+    /// the limit, or `None` if there is no limit.
+    pub fn remaining(&self, text: &str) -> Option<isize> {
+        self.max_message_length
+            .map(|max| max as isize - self.length_unit.measure(text) as isize)
+    }
+
+    pub fn check_length(&self, text: &str) -> Result<(), SendError> {
+        match self.max_message_length {
+            Some(max) if self.length_unit.measure(text) > max => Err(SendError::TooLong { max }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LengthUnit {
+    /// Unicode scalar values
+    #[default]
+    Chars,
+    /// UTF-8 bytes
+    Bytes,
+}
+
+impl LengthUnit {
+    pub fn measure(self, text: &str) -> usize {
+        match self {
+            Self::Chars => text.chars().count(),
+            Self::Bytes => text.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum SendError {
+    #[error("network error")]
+    Network,
+    #[error("rate limited{}", retry_after.map(|d| format!(", retry after {}s", d.as_secs())).unwrap_or_default())]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("not permitted to send messages here")]
+    NotPermitted,
+    #[error("message too long (max {max})")]
+    TooLong { max: usize },
+    #[error("{0}")]
+    Unknown(String),
+}
+
+impl SendError {
+    /// Whether the message should be kept as a draft, so that it can be retried or edited.
+    pub fn keeps_draft(&self) -> bool {
+        !matches!(self, Self::NotPermitted)
+    }
+}
+
+/// A [`MessageSink`] which passes messages to other sinks, each running in its own task, by the
+/// service of the room. Unlike most sinks, this can be cloned and shared freely.
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    /// The sink for rooms of each service
+    routes: BTreeMap<ServiceType, Route>,
+    /// The sink for rooms of any other service
+    fallback: Option<Route>,
+}
+
+/// A sink an [`Outbox`] passes messages to.
 #[derive(Clone, Debug)]
-pub struct RichText(pub Arc<str>);
+struct Route {
+    capabilities: SinkCapabilities,
+    sender: mpsc::UnboundedSender<OutgoingMessage>,
+}
+
+#[derive(Debug)]
+struct OutgoingMessage {
+    room: Room,
+    body: MessageBody,
+    result: oneshot::Sender<Result<MessageKey, SendError>>,
+}
+
+impl Route {
+    /// Creates a route to `sink`, and the task which sends its messages, one at a time. The task
+    /// finishes when every clone of the route has been dropped.
+    fn new<S>(sink: S) -> (Self, impl Future<Output = ()> + Send + 'static)
+    where
+        S: MessageSink + Send + Sync + 'static,
+    {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<OutgoingMessage>();
+        let route = Self {
+            capabilities: sink.capabilities(),
+            sender,
+        };
+        let task = async move {
+            while let Some(message) = receiver.recv().await {
+                let result = sink.send(&message.room, message.body).await;
+                // the sender may have stopped waiting for the result
+                let _ = message.result.send(result);
+            }
+        };
+        (route, task)
+    }
+}
+
+impl Outbox {
+    /// Creates an outbox which sends messages to any room with `sink`, and the task which sends
+    /// its messages, one at a time. The task finishes when every clone of the outbox has been
+    /// dropped.
+    pub fn new<S>(sink: S) -> (Self, impl Future<Output = ()> + Send + 'static)
+    where
+        S: MessageSink + Send + Sync + 'static,
+    {
+        let (route, task) = Route::new(sink);
+        let outbox = Self {
+            routes: BTreeMap::new(),
+            fallback: Some(route),
+        };
+        (outbox, task)
+    }
+
+    /// An outbox with nowhere to send messages, for when there is no backend which can send them.
+    pub fn closed() -> Self {
+        Self::default()
+    }
+
+    /// Sends messages to rooms of `service` with `sink` from now on, returning the task which
+    /// sends them, like [`new`](Self::new).
+    pub fn route<S>(
+        &mut self,
+        service: ServiceType,
+        sink: S,
+    ) -> impl Future<Output = ()> + Send + 'static
+    where
+        S: MessageSink + Send + Sync + 'static,
+    {
+        let (route, task) = Route::new(sink);
+        self.routes.insert(service, route);
+        task
+    }
+
+    /// The limitations of the sink messages to the room are sent with.
+    pub fn capabilities_for(&self, room: &Room) -> SinkCapabilities {
+        self.route_for(room)
+            .map(|route| route.capabilities.clone())
+            .unwrap_or_default()
+    }
+
+    fn route_for(&self, room: &Room) -> Option<&Route> {
+        self.routes.get(&room.service).or(self.fallback.as_ref())
+    }
+}
+
+impl MessageSink for Outbox {
+    /// The limitations of the sink for rooms of any service; see
+    /// [`capabilities_for`](Self::capabilities_for).
+    fn capabilities(&self) -> SinkCapabilities {
+        self.fallback
+            .as_ref()
+            .map(|route| route.capabilities.clone())
+            .unwrap_or_default()
+    }
+
+    fn send(
+        &self,
+        room: &Room,
+        body: MessageBody,
+    ) -> impl Future<Output = Result<MessageKey, SendError>> + Send {
+        let (result, receiver) = oneshot::channel();
+        let sent = self.route_for(room).map(|route| {
+            route.sender.send(OutgoingMessage {
+                room: room.clone(),
+                body,
+                result,
+            })
+        });
+        let service = room.service;
+        async move {
+            let no_backend =
+                || SendError::Unknown(format!("no {service} backend to send messages to"));
+            match sent {
+                Some(Ok(())) => {}
+                _ => return Err(no_backend()),
+            }
+            receiver.await.map_err(|_| no_backend())?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quoted(text: &str, quote: usize) -> RichSpan {
+        RichSpan {
+            text: text.into(),
+            quote,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_nested_quotes() {
+        let text = RichText::from_plain("> one\n> > two\n>three\nfour").parse_quotes();
+        assert_eq!(
+            text.0,
+            [
+                quoted("one\n", 1),
+                quoted("two\n", 2),
+                quoted("three\n", 1),
+                quoted("four", 0),
+            ]
+        );
+        assert_eq!(text.plain_text(), "> one\n> > two\n> three\nfour");
+    }
+
+    #[test]
+    fn parse_quotes_outside_code_blocks() {
+        let text = RichText::from_plain("```sh\n> out.txt\n```\n> quote").parse_quotes();
+        assert_eq!(
+            text.0,
+            [quoted("```sh\n> out.txt\n```\n", 0), quoted("quote", 1)]
+        );
+    }
+
+    #[test]
+    fn parse_quotes_keeps_formatting() {
+        let bold = RichSpan {
+            text: "bold".into(),
+            bold: true,
+            ..Default::default()
+        };
+        let text = RichText(vec![RichSpan::plain("> some "), bold.clone()]).parse_quotes();
+        assert_eq!(text.0, [quoted("some ", 1), RichSpan { quote: 1, ..bold }]);
+    }
+
+    #[test]
+    fn file_url_escapes() {
+        assert_eq!(
+            percent_decode("/tmp/my%20notes%2B.txt").as_deref(),
+            Some("/tmp/my notes+.txt")
+        );
+        assert_eq!(percent_decode("/tmp/100%").as_deref(), None);
+        assert_eq!(percent_decode("/tmp/%zz").as_deref(), None);
+        assert_eq!(percent_decode("/tmp/%ff").as_deref(), None);
+    }
+
+    #[test]
+    fn unquoted_text_stays_plain() {
+        let text = RichText::from_plain("no\nquotes").parse_quotes();
+        assert!(text.is_plain());
+        assert_eq!(text, RichText::from_plain("no\nquotes"));
+    }
+
+    /// Accepts every message, with the name of the sink as its identifier.
+    struct NamedSink(&'static str, usize);
+
+    impl MessageSink for NamedSink {
+        fn capabilities(&self) -> SinkCapabilities {
+            SinkCapabilities {
+                max_message_length: Some(self.1),
+                ..Default::default()
+            }
+        }
+
+        async fn send(&self, _room: &Room, _body: MessageBody) -> Result<MessageKey, SendError> {
+            Ok(MessageKey {
+                timestamp: DateTime::UNIX_EPOCH,
+                identifier: self.0.into(),
+                service: ServiceType::Unknown,
+            })
+        }
+    }
+
+    fn room(service: ServiceType) -> Room {
+        Room {
+            display_name: "general".into(),
+            identifier: "general".into(),
+            service,
+        }
+    }
+
+    #[tokio::test]
+    async fn outbox_routes_by_service() {
+        let mut outbox = Outbox::closed();
+        tokio::spawn(outbox.route(ServiceType::Matrix, NamedSink("matrix", 100)));
+        tokio::spawn(outbox.route(ServiceType::Irc, NamedSink("irc", 10)));
+        let body = || MessageBody::Text(RichText::from_plain("hi"));
+        for (service, name, max) in [
+            (ServiceType::Matrix, "matrix", 100),
+            (ServiceType::Irc, "irc", 10),
+        ] {
+            let room = room(service);
+            let key = outbox.send(&room, body()).await.unwrap();
+            assert_eq!(&*key.identifier, name);
+            assert_eq!(outbox.capabilities_for(&room).max_message_length, Some(max));
+        }
+        let fake = room(ServiceType::Fake);
+        assert!(outbox.send(&fake, body()).await.is_err());
+        assert_eq!(outbox.capabilities_for(&fake).max_message_length, None);
+    }
+
+    #[tokio::test]
+    async fn outbox_falls_back_to_its_sink() {
+        let (mut outbox, send_task) = Outbox::new(NamedSink("any", 100));
+        tokio::spawn(send_task);
+        tokio::spawn(outbox.route(ServiceType::Irc, NamedSink("irc", 10)));
+        let body = || MessageBody::Text(RichText::from_plain("hi"));
+        let key = outbox.send(&room(ServiceType::Fake), body()).await.unwrap();
+        assert_eq!(&*key.identifier, "any");
+        let key = outbox.send(&room(ServiceType::Irc), body()).await.unwrap();
+        assert_eq!(&*key.identifier, "irc");
+    }
+}
@@ -1,60 +1,234 @@
-use carrier_pigeon_common::{Message, MessageBody, MessageKey, RichText, Room, User};
-use chrono::Utc;
-use rand::prelude::{Rng, SliceRandom};
+use std::collections::{BTreeMap, VecDeque};
+
+use carrier_pigeon_common::{
+    Message, MessageBody, MessageKey, RichText, Room, ServiceType, StreamEvent, SystemEvent, User,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::{
+    prelude::{Rng, SliceRandom},
+    rngs::StdRng,
+    SeedableRng,
+};
 use uuid::Uuid;
 
 const ROOM_NAMES: &[&str] = &["general", "random", "memes"];
 
 const USER_NAMES: &[&str] = &["alice", "bob", "charlie", "dana"];
 
-pub async fn message_sender(channel: tokio::sync::mpsc::UnboundedSender<Message>) {
-    // set up rooms and users
-    let rooms = ROOM_NAMES
+const REACTIONS: &[&str] = &["👍", "❤️", "😂", "🎉"];
+
+/// The longest time to wait between messages
+const MAX_DELAY_MILLIS: u64 = 5000;
+
+/// How many of the most recent messages may be edited or deleted.
+const HISTORY_LEN: usize = 100;
+
+/// Sends random messages to the channel at random intervals, until the channel is closed. With
+/// probability `change_probability`, each one instead edits or deletes a recent message.
+///
+/// Before that, `seed_count` messages are sent immediately, with timestamps spread over the past
+/// hour.
+pub async fn message_sender(
+    channel: tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+    seed_count: usize,
+    change_probability: f64,
+) {
+    let rooms = rooms(Uuid::now_v7);
+    let users = users();
+    let mut history = VecDeque::with_capacity(HISTORY_LEN);
+
+    let now = Utc::now();
+    for _ in 0..seed_count {
+        let mut rng = rand::thread_rng();
+        let age = TimeDelta::milliseconds(rng.gen_range(0..60 * 60 * 1000));
+        let (message, _) = generate_message(&mut rng, Uuid::now_v7(), &rooms, &users, now - age);
+        remember(&mut history, &message);
+        if channel.send(message.into()).is_err() {
+            return;
+        }
+    }
+
+    loop {
+        // the generator isn't `Send`, so it can't be held across the sleep
+        let (event, millis) = {
+            let mut rng = rand::thread_rng();
+            match generate_change(&mut rng, &mut history, change_probability) {
+                Some(event) => (event, rng.gen_range(0..MAX_DELAY_MILLIS)),
+                None => {
+                    let (message, millis) =
+                        generate_message(&mut rng, Uuid::now_v7(), &rooms, &users, Utc::now());
+                    remember(&mut history, &message);
+                    (message.into(), millis)
+                }
+            }
+        };
+        if channel.send(event).is_err() {
+            return;
+        }
+        tokio::time::sleep(tokio::time::Duration::from_millis(millis)).await;
+    }
+}
+
+fn remember(history: &mut VecDeque<Message>, message: &Message) {
+    if history.len() == HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(message.clone());
+}
+
+/// With probability `change_probability`, edits or deletes a message from `history`. Only text
+/// messages are edited, with a new body.
+fn generate_change(
+    rng: &mut impl Rng,
+    history: &mut VecDeque<Message>,
+    change_probability: f64,
+) -> Option<StreamEvent> {
+    if history.is_empty() || !rng.gen_bool(change_probability) {
+        return None;
+    }
+    let index = rng.gen_range(0..history.len());
+    let message = &mut history[index];
+    if rng.gen() && !message.body.is_system() {
+        let now = Utc::now();
+        message.body = text_body(rng);
+        message.edited_at = Some(now);
+        Some(StreamEvent::Edit(Message {
+            key: MessageKey {
+                timestamp: now,
+                ..message.key()
+            },
+            ..message.clone()
+        }))
+    } else {
+        history
+            .remove(index)
+            .map(|message| StreamEvent::Delete(message.key))
+    }
+}
+
+/// Generates `count` messages, with timestamps a second apart starting at `start`. The same seed
+/// always generates the same messages, so they can be used as repeatable input.
+pub fn seeded_messages(seed: u64, count: usize, start: DateTime<Utc>) -> Vec<Message> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let rooms = rooms(|| Uuid::from_u128(rng.gen()));
+    let users = users();
+    (0..count)
+        .map(|i| {
+            let timestamp = start + TimeDelta::seconds(i as i64);
+            let identifier = Uuid::from_u128(rng.gen());
+            generate_message(&mut rng, identifier, &rooms, &users, timestamp).0
+        })
+        .collect()
+}
+
+fn rooms(mut identifier: impl FnMut() -> Uuid) -> Vec<Room> {
+    ROOM_NAMES
         .iter()
         .map(|name| Room {
             display_name: name.to_owned().into(),
-            identifier: Uuid::now_v7().to_string().into(),
+            identifier: identifier().to_string().into(),
+            service: ServiceType::Fake,
         })
-        .collect::<Vec<_>>();
-    let users = USER_NAMES
+        .collect()
+}
+
+fn users() -> Vec<User> {
+    USER_NAMES
         .iter()
         .map(|name| User {
             display_name: name.to_owned().into(),
             identifier: format!("@{name}:example.com").into(),
+            service: ServiceType::Fake,
         })
-        .collect::<Vec<_>>();
-
-    loop {
-        let (message, millis) = generate_message(&rooms, &users);
-        if channel.send(message).is_err() {
-            return;
-        }
-        tokio::time::sleep(tokio::time::Duration::from_millis(millis)).await;
-    }
+        .collect()
 }
 
-fn generate_message(rooms: &[Room], users: &[User]) -> (Message, u64) {
+fn text_body(rng: &mut impl Rng) -> MessageBody {
     const MIN_MESSAGE_WORDS: usize = 1;
     const MAX_MESSAGE_WORDS: usize = 15;
-    let mut rng = rand::thread_rng();
-    let timestamp = Utc::now();
-    let identifier = Uuid::now_v7().to_string().into();
+    let message_len = rng.gen_range(MIN_MESSAGE_WORDS..=MAX_MESSAGE_WORDS);
+    MessageBody::Text(RichText::from_plain(lipsum::lipsum_words_with_rng(
+        &mut *rng,
+        message_len,
+    )))
+}
+
+/// Generates a message, along with the number of milliseconds to wait before the next one.
+fn generate_message(
+    rng: &mut impl Rng,
+    identifier: Uuid,
+    rooms: &[Room],
+    users: &[User],
+    timestamp: DateTime<Utc>,
+) -> (Message, u64) {
+    const SYSTEM_MESSAGE_PROBABILITY: f64 = 0.1;
+    const REACTION_PROBABILITY: f64 = 0.2;
     let key = MessageKey {
         timestamp,
-        identifier,
+        identifier: identifier.to_string().into(),
+        service: ServiceType::Fake,
     };
-    let sender = users.choose(&mut rng).unwrap().clone();
-    let room = rooms.choose(&mut rng).unwrap().clone();
-    let message_len = rng.gen_range(MIN_MESSAGE_WORDS..=MAX_MESSAGE_WORDS);
-    let body = MessageBody::Text(RichText(
-        lipsum::lipsum_words_with_rng(&mut rng, message_len).into(),
-    ));
+    let sender = users.choose(rng).unwrap().clone();
+    let room = rooms.choose(rng).unwrap().clone();
+    let body = if rng.gen_bool(SYSTEM_MESSAGE_PROBABILITY) {
+        MessageBody::System(if rng.gen() {
+            SystemEvent::Joined
+        } else {
+            SystemEvent::Left
+        })
+    } else {
+        text_body(rng)
+    };
+    let mut reactions = BTreeMap::new();
+    if !body.is_system() && rng.gen_bool(REACTION_PROBABILITY) {
+        let emoji = *REACTIONS.choose(rng).unwrap();
+        let count = rng.gen_range(1..=users.len());
+        let reactors = users.choose_multiple(rng, count).cloned().collect();
+        reactions.insert(emoji.into(), reactors);
+    }
     let message = Message {
         key,
         sender,
         room,
+        reply_to: None,
         body,
+        received_at: None,
+        edited_at: None,
+        reactions,
     };
-    let millis = rng.gen_range(0..5000);
+    let millis = rng.gen_range(0..MAX_DELAY_MILLIS);
     (message, millis)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn seeded_messages_come_first() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let start = Utc::now();
+        // run the sender until its first delay
+        tokio::select! {
+            biased;
+            () = message_sender(tx, 50, 0.0) => panic!("the channel is still open"),
+            () = std::future::ready(()) => {}
+        }
+        let mut events = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        // the seeded messages, then the first of the trickle
+        assert_eq!(events.len(), 51);
+        for event in &events[..50] {
+            let StreamEvent::New(message) = event else {
+                panic!("expected a message, got {event:?}");
+            };
+            let age = start - message.key.timestamp;
+            assert!(
+                age >= TimeDelta::zero() && age <= TimeDelta::hours(1),
+                "{age}"
+            );
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use carrier_pigeon_common::{
+    LengthUnit, Message, MessageBody, MessageKey, MessageSink, RichText, Room, SendError,
+    ServiceType, SinkCapabilities, StreamEvent, SystemEvent, User,
+};
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
+use irc::{
+    client::{Client, ClientStream, Sender},
+    proto::{self, Command},
+};
+use uuid::Uuid;
+
+pub use irc::{client::data::Config, error::Error};
+
+/// An IRC line is at most 512 bytes, including the command, target, and the prefix the server
+/// adds when relaying it, so this leaves room for those.
+const MAX_MESSAGE_LENGTH: usize = 400;
+
+/// Connects and registers with the server, which also joins the configured channels.
+pub async fn connect(config: Config) -> Result<(IrcSink, ClientStream), Error> {
+    let mut client = Client::from_config(config).await?;
+    client.identify()?;
+    let stream = client.stream()?;
+    Ok((
+        IrcSink {
+            sender: client.sender(),
+        },
+        stream,
+    ))
+}
+
+/// Sends the messages received from the server to the channel, until either is closed.
+///
+/// The stream must be polled for anything to be sent, so this should run for as long as the
+/// [`IrcSink`] is used.
+pub async fn message_sender(
+    mut stream: ClientStream,
+    channel: tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+) -> Result<(), Error> {
+    while let Some(message) = stream.next().await.transpose()? {
+        tracing::trace!("{}", message.to_string().trim_end());
+        if let Some(message) = to_message(&message, Utc::now()) {
+            if channel.send(message.into()).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts a message from the server, if it is one that is shown. Channels are rooms and nicks
+/// are users, with private messages in a room named after the other user.
+///
+/// The message is timestamped with the server's `time` tag, if any, or `received_at`.
+pub fn to_message(message: &proto::Message, received_at: DateTime<Utc>) -> Option<Message> {
+    let nick = message.source_nickname()?;
+    let (room, body) = match &message.command {
+        Command::PRIVMSG(_, text) => (
+            message.response_target()?,
+            MessageBody::Text(RichText::from_plain(text.as_str())),
+        ),
+        Command::JOIN(channel, ..) => (channel.as_str(), MessageBody::System(SystemEvent::Joined)),
+        Command::PART(channel, _) => (channel.as_str(), MessageBody::System(SystemEvent::Left)),
+        _ => return None,
+    };
+    let timestamp = tag(message, "time")
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map_or(received_at, |time| time.to_utc());
+    let identifier = tag(message, "msgid")
+        .map(Into::into)
+        .unwrap_or_else(|| Uuid::now_v7().to_string().into());
+    Some(Message {
+        key: MessageKey {
+            timestamp,
+            identifier,
+            service: ServiceType::Irc,
+        },
+        sender: User {
+            display_name: nick.into(),
+            identifier: nick.into(),
+            service: ServiceType::Irc,
+        },
+        room: Room {
+            display_name: room.into(),
+            identifier: room.into(),
+            service: ServiceType::Irc,
+        },
+        reply_to: tag(message, "+draft/reply").map(Into::into),
+        body,
+        received_at: Some(received_at),
+        edited_at: None,
+        reactions: Default::default(),
+    })
+}
+
+fn tag<'a>(message: &'a proto::Message, name: &str) -> Option<&'a str> {
+    message
+        .tags
+        .iter()
+        .flatten()
+        .find(|proto::message::Tag(key, _)| key == name)?
+        .1
+        .as_deref()
+}
+
+/// Sends messages to IRC channels (or nicks) as `PRIVMSG`s.
+#[derive(Clone, Debug)]
+pub struct IrcSink {
+    sender: Sender,
+}
+
+impl MessageSink for IrcSink {
+    fn capabilities(&self) -> SinkCapabilities {
+        SinkCapabilities {
+            max_message_length: Some(MAX_MESSAGE_LENGTH),
+            length_unit: LengthUnit::Bytes,
+            attachments: false,
+        }
+    }
+
+    fn send(
+        &self,
+        room: &Room,
+        body: MessageBody,
+    ) -> impl std::future::Future<Output = Result<MessageKey, SendError>> + Send {
+        let result = match body {
+            MessageBody::Text(text) => {
+                // formatting isn't sent over IRC
+                let text = text.plain_text();
+                self.capabilities()
+                    .check_length(&text)
+                    .and_then(|()| {
+                        // IRC messages can't contain newlines, so each line is sent separately
+                        text.lines()
+                            .try_for_each(|line| self.sender.send_privmsg(&*room.identifier, line))
+                            .map_err(|err| {
+                                tracing::warn!("error sending message: {err}");
+                                SendError::Network
+                            })
+                    })
+                    .map(|()| MessageKey {
+                        timestamp: Utc::now(),
+                        identifier: Uuid::now_v7().to_string().into(),
+                        service: ServiceType::Irc,
+                    })
+            }
+            _ => Err(SendError::Unknown(
+                "only text messages can be sent over IRC".into(),
+            )),
+        };
+        std::future::ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<Message> {
+        let received_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        to_message(&line.parse().unwrap(), received_at)
+    }
+
+    #[test]
+    fn channel_privmsg() {
+        let message = parse(
+            "@time=2024-01-02T03:04:05.000Z;msgid=abc \
+             :alice!alice@example.com PRIVMSG #rust :hello, world\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            message.key.timestamp.to_rfc3339(),
+            "2024-01-02T03:04:05+00:00"
+        );
+        assert_eq!(&*message.key.identifier, "abc");
+        assert_eq!(message.key.service, ServiceType::Irc);
+        assert_eq!(&*message.sender.display_name, "alice");
+        assert_eq!(&*message.sender.identifier, "alice");
+        assert_eq!(&*message.room.identifier, "#rust");
+        assert_eq!(message.body.plain_text(), "hello, world");
+        assert_eq!(
+            message.received_at,
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+    }
+
+    #[test]
+    fn private_privmsg() {
+        let message = parse(":bob!bob@example.com PRIVMSG me :psst\r\n").unwrap();
+        // without a time tag, it was sent when it was received
+        assert_eq!(message.key.timestamp.timestamp(), 1_700_000_000);
+        // the room is named after the other user
+        assert_eq!(&*message.room.identifier, "bob");
+        assert_eq!(message.body.plain_text(), "psst");
+    }
+
+    #[test]
+    fn join_and_part() {
+        let joined = parse(":alice!alice@example.com JOIN #rust\r\n").unwrap();
+        assert!(matches!(
+            joined.body,
+            MessageBody::System(SystemEvent::Joined)
+        ));
+        assert_eq!(&*joined.room.identifier, "#rust");
+        let left = parse(":alice!alice@example.com PART #rust :bye\r\n").unwrap();
+        assert!(matches!(left.body, MessageBody::System(SystemEvent::Left)));
+    }
+
+    #[test]
+    fn other_commands_arent_shown() {
+        assert!(parse("PING :irc.example.com\r\n").is_none());
+        assert!(parse(":irc.example.com 001 me :Welcome\r\n").is_none());
+    }
+}
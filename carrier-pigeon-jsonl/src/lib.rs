@@ -0,0 +1,237 @@
+use std::{
+    collections::BTreeMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+use carrier_pigeon_common::{Message, StreamEvent};
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::mpsc,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("error reading {}: {error}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("error watching for changes: {0}")]
+    Watch(#[from] notify::Error),
+}
+
+/// Sends the messages in the `.jsonl` files in `dir` to the channel, in timestamp order, and then
+/// waits until the channel is closed. Each line of a file is a JSON [`Message`]; invalid lines are
+/// logged and skipped.
+///
+/// If `watch` is set, lines appended to the files (or to new files) are sent as they are written.
+pub async fn message_sender(
+    dir: PathBuf,
+    watch: bool,
+    channel: mpsc::UnboundedSender<StreamEvent>,
+) -> Result<(), Error> {
+    // start watching first, so nothing written during the initial read is missed
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = None;
+    if watch {
+        let mut new_watcher = notify::recommended_watcher(move |event| {
+            let _ = events_tx.send(event);
+        })?;
+        new_watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        watcher = Some(new_watcher);
+    }
+
+    let mut readers = BTreeMap::<PathBuf, LineReader>::new();
+    let mut messages = Vec::new();
+    let entries = std::fs::read_dir(&dir).map_err(|error| Error::Read {
+        path: dir.clone(),
+        error,
+    })?;
+    for entry in entries {
+        let path = entry
+            .map_err(|error| Error::Read {
+                path: dir.clone(),
+                error,
+            })?
+            .path();
+        if is_jsonl(&path) {
+            let reader = readers.entry(path.clone()).or_default();
+            messages.extend(reader.read_new(&path).await?);
+        }
+    }
+    messages.sort_by(|a, b| a.key.cmp(&b.key));
+    for message in messages {
+        if channel.send(message.into()).is_err() {
+            return Ok(());
+        }
+    }
+
+    if watcher.is_none() {
+        channel.closed().await;
+        return Ok(());
+    }
+    loop {
+        let event = tokio::select! {
+            // the watcher holds the sender, so this never returns `None`
+            Some(event) = events.recv() => event,
+            () = channel.closed() => return Ok(()),
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                tracing::warn!("error watching {}: {err}", dir.display());
+                continue;
+            }
+        };
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            continue;
+        }
+        for path in event.paths.into_iter().filter(|path| is_jsonl(path)) {
+            let reader = readers.entry(path.clone()).or_default();
+            for message in reader.read_new(&path).await? {
+                if channel.send(message.into()).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn is_jsonl(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "jsonl")
+}
+
+/// Reads the messages appended to a file since it was last read.
+#[derive(Debug, Default)]
+struct LineReader {
+    /// How many bytes have been read
+    offset: u64,
+    /// How many lines have been read, for error messages
+    lines: usize,
+}
+
+impl LineReader {
+    async fn read_new(&mut self, path: &Path) -> Result<Vec<Message>, Error> {
+        let buf = self
+            .read_from_offset(path)
+            .await
+            .map_err(|error| Error::Read {
+                path: path.to_owned(),
+                error,
+            })?;
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            let complete = line.ends_with(b"\n");
+            let result = serde_json::from_slice::<Message>(line);
+            if !complete && result.is_err() {
+                // probably still being written, so read it again with the rest of it
+                break;
+            }
+            consumed += line.len();
+            self.lines += 1;
+            match result {
+                Ok(message) => messages.push(message),
+                Err(_) if line.trim_ascii().is_empty() => {}
+                Err(err) => {
+                    tracing::warn!("{}:{}: invalid message: {err}", path.display(), self.lines)
+                }
+            }
+        }
+        self.offset += consumed as u64;
+        Ok(messages)
+    }
+
+    async fn read_from_offset(&mut self, path: &Path) -> std::io::Result<Vec<u8>> {
+        let mut file = tokio::fs::File::open(path).await?;
+        if file.metadata().await?.len() < self.offset {
+            // the file was truncated, so start again
+            *self = Self::default();
+        }
+        file.seek(SeekFrom::Start(self.offset)).await?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use carrier_pigeon_common::{MessageBody, MessageKey, RichText, Room, ServiceType, User};
+
+    use super::*;
+
+    fn message(identifier: &str, seconds: i64) -> Message {
+        Message {
+            key: MessageKey {
+                timestamp: chrono::DateTime::from_timestamp(seconds, 0).unwrap(),
+                identifier: identifier.into(),
+                service: ServiceType::Fake,
+            },
+            sender: User {
+                display_name: "alice".into(),
+                identifier: "@alice:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            room: Room {
+                display_name: "general".into(),
+                identifier: "!general:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            reply_to: None,
+            body: MessageBody::Text(RichText::from_plain(identifier)),
+            received_at: None,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    fn line(message: &Message) -> String {
+        serde_json::to_string(message).unwrap() + "\n"
+    }
+
+    fn identifier(event: StreamEvent) -> String {
+        match event {
+            StreamEvent::New(message) => message.key.identifier.to_string(),
+            event => panic!("expected a new message, got {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_messages_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        // written out of order, and with a line which isn't a message
+        let contents = line(&message("second", 20)) + "not json\n" + &line(&message("first", 10));
+        std::fs::write(dir.path().join("messages.jsonl"), contents).unwrap();
+        std::fs::write(dir.path().join("ignored.txt"), line(&message("other", 0))).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(message_sender(dir.path().to_owned(), false, tx));
+        assert_eq!(identifier(rx.recv().await.unwrap()), "first");
+        assert_eq!(identifier(rx.recv().await.unwrap()), "second");
+        assert!(rx.try_recv().is_err());
+        // it waits until the channel is closed
+        drop(rx);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn partial_lines_are_read_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("messages.jsonl");
+        let first = line(&message("first", 10));
+        let second = line(&message("second", 20));
+        let (start, end) = second.split_at(10);
+        std::fs::write(&path, first.clone() + start).unwrap();
+        let mut reader = LineReader::default();
+        let messages = reader.read_new(&path).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        std::fs::write(&path, first + start + end).unwrap();
+        let messages = reader.read_new(&path).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(&*messages[0].key.identifier, "second");
+    }
+}
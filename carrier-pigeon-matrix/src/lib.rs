@@ -1 +1,291 @@
+use carrier_pigeon_common::{
+    Attachment, Message, MessageBody, MessageKey, MessageSink, RichText, Room, SendError,
+    ServiceType, StreamEvent, User,
+};
+use chrono::{DateTime, Utc};
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{
+        events::room::{
+            message::{
+                MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+            },
+            MediaSource,
+        },
+        RoomId, UserId,
+    },
+    Client, ClientBuildError, LoopCtrl,
+};
 
+pub use matrix_sdk::{ruma::OwnedUserId, Error};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("error finding homeserver: {0}")]
+    Build(#[from] ClientBuildError),
+    #[error("error logging in: {0}")]
+    Login(#[from] Error),
+}
+
+/// Logs in with a password, finding the homeserver from the user ID's server name.
+pub async fn connect(user_id: &UserId, password: &str) -> Result<MatrixSink, ConnectError> {
+    let client = Client::builder()
+        .server_name(user_id.server_name())
+        .build()
+        .await?;
+    client
+        .matrix_auth()
+        .login_username(user_id, password)
+        .initial_device_display_name("carrier-pigeon")
+        .await?;
+    Ok(MatrixSink { client })
+}
+
+/// Syncs the joined rooms, sending their messages to the channel until it is closed.
+///
+/// Encrypted rooms aren't supported yet, so only their unencrypted events are received.
+pub async fn message_sender(
+    sink: MatrixSink,
+    channel: tokio::sync::mpsc::UnboundedSender<StreamEvent>,
+) -> Result<(), Error> {
+    let client = sink.client;
+    let sender = channel.clone();
+    client.add_event_handler(
+        move |event: OriginalSyncRoomMessageEvent, room: matrix_sdk::Room| {
+            let channel = sender.clone();
+            async move {
+                let room_name = match room.display_name().await {
+                    Ok(name) => name.to_string(),
+                    Err(err) => {
+                        tracing::warn!("error getting room name: {err}");
+                        room.room_id().to_string()
+                    }
+                };
+                let sender_name = match room.get_member_no_sync(&event.sender).await {
+                    Ok(Some(member)) => member.name().to_owned(),
+                    Ok(None) => event.sender.localpart().to_owned(),
+                    Err(err) => {
+                        tracing::warn!("error getting sender name: {err}");
+                        event.sender.localpart().to_owned()
+                    }
+                };
+                let room = Room {
+                    display_name: room_name.into(),
+                    identifier: room.room_id().as_str().into(),
+                    service: ServiceType::Matrix,
+                };
+                let sender = User {
+                    display_name: sender_name.into(),
+                    identifier: event.sender.as_str().into(),
+                    service: ServiceType::Matrix,
+                };
+                // if the channel is closed, the sync loop stops after this response
+                let _ = channel.send(to_message(&event, room, sender, Utc::now()).into());
+            }
+        },
+    );
+    client
+        .sync_with_callback(SyncSettings::default(), |_| {
+            let closed = channel.is_closed();
+            async move {
+                if closed {
+                    LoopCtrl::Break
+                } else {
+                    LoopCtrl::Continue
+                }
+            }
+        })
+        .await
+}
+
+/// Converts a room message event, given the room it was sent in and its sender. The identifier
+/// is the event ID, and the timestamp is the homeserver's.
+pub fn to_message(
+    event: &OriginalSyncRoomMessageEvent,
+    room: Room,
+    sender: User,
+    received_at: DateTime<Utc>,
+) -> Message {
+    let body = match &event.content.msgtype {
+        MessageType::File(content) => MessageBody::File(Attachment {
+            url: media_url(&content.source).into(),
+            filename: content.filename().into(),
+            size: content
+                .info
+                .as_ref()
+                .and_then(|info| info.size)
+                .map(u64::from),
+        }),
+        MessageType::Image(content) => MessageBody::Image(Attachment {
+            url: media_url(&content.source).into(),
+            filename: content.filename().into(),
+            size: content
+                .info
+                .as_ref()
+                .and_then(|info| info.size)
+                .map(u64::from),
+        }),
+        MessageType::Emote(content) => MessageBody::Text(RichText::from_plain(format!(
+            "* {} {}",
+            sender.display_name, content.body
+        ))),
+        // formatted bodies are shown as their plain text fallback, as are other message types
+        msgtype => MessageBody::Text(RichText::from_plain(msgtype.body())),
+    };
+    let timestamp =
+        DateTime::from_timestamp_millis(event.origin_server_ts.0.into()).unwrap_or(received_at);
+    Message {
+        key: MessageKey {
+            timestamp,
+            identifier: event.event_id.as_str().into(),
+            service: ServiceType::Matrix,
+        },
+        sender,
+        room,
+        reply_to: match &event.content.relates_to {
+            Some(Relation::Reply(reply)) => Some(reply.in_reply_to.event_id.as_str().into()),
+            _ => None,
+        },
+        body,
+        received_at: Some(received_at),
+        edited_at: None,
+        reactions: Default::default(),
+    }
+}
+
+/// The `mxc://` URL of a file, which is encrypted for an encrypted source.
+fn media_url(source: &MediaSource) -> &str {
+    match source {
+        MediaSource::Plain(url) => url.as_str(),
+        MediaSource::Encrypted(file) => file.url.as_str(),
+    }
+}
+
+/// Sends text messages to Matrix rooms, by room ID.
+#[derive(Clone, Debug)]
+pub struct MatrixSink {
+    client: Client,
+}
+
+impl MessageSink for MatrixSink {
+    fn send(
+        &self,
+        room: &Room,
+        body: MessageBody,
+    ) -> impl std::future::Future<Output = Result<MessageKey, SendError>> + Send {
+        let client = self.client.clone();
+        let room_id = RoomId::parse(&*room.identifier);
+        async move {
+            let MessageBody::Text(text) = body else {
+                return Err(SendError::Unknown(
+                    "only text messages can be sent to Matrix".into(),
+                ));
+            };
+            let room_id = room_id.map_err(|err| SendError::Unknown(err.to_string()))?;
+            let room = client.get_room(&room_id).ok_or(SendError::NotPermitted)?;
+            let result = room
+                .send(RoomMessageEventContent::text_plain(text.plain_text()))
+                .await
+                .map_err(|err| {
+                    tracing::warn!("error sending message: {err}");
+                    SendError::Network
+                })?;
+            Ok(MessageKey {
+                timestamp: Utc::now(),
+                identifier: result.response.event_id.as_str().into(),
+                service: ServiceType::Matrix,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room() -> Room {
+        Room {
+            display_name: "general".into(),
+            identifier: "!general:example.com".into(),
+            service: ServiceType::Matrix,
+        }
+    }
+
+    fn sender() -> User {
+        User {
+            display_name: "alice".into(),
+            identifier: "@alice:example.com".into(),
+            service: ServiceType::Matrix,
+        }
+    }
+
+    fn convert(event: serde_json::Value) -> Message {
+        let event = serde_json::from_value(event).unwrap();
+        let received_at = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        to_message(&event, room(), sender(), received_at)
+    }
+
+    #[test]
+    fn text_reply() {
+        let message = convert(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$answer:example.com",
+            "sender": "@alice:example.com",
+            "origin_server_ts": 1_699_999_999_123_u64,
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@bob:example.com> question\n\nanswer",
+                "m.relates_to": {
+                    "m.in_reply_to": { "event_id": "$question:example.com" }
+                }
+            }
+        }));
+        assert_eq!(&*message.key.identifier, "$answer:example.com");
+        assert_eq!(message.key.service, ServiceType::Matrix);
+        assert_eq!(message.key.timestamp.timestamp_millis(), 1_699_999_999_123);
+        assert_eq!(
+            message.received_at,
+            DateTime::from_timestamp(1_700_000_000, 0)
+        );
+        assert_eq!(message.reply_to.as_deref(), Some("$question:example.com"));
+        assert_eq!(
+            message.body.plain_text(),
+            "> <@bob:example.com> question\n\nanswer"
+        );
+    }
+
+    #[test]
+    fn file() {
+        let message = convert(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$file:example.com",
+            "sender": "@alice:example.com",
+            "origin_server_ts": 1_699_999_999_000_u64,
+            "content": {
+                "msgtype": "m.file",
+                "body": "notes.txt",
+                "url": "mxc://example.com/notes",
+                "info": { "size": 1234 }
+            }
+        }));
+        let MessageBody::File(attachment) = &message.body else {
+            panic!("expected a file, got {:?}", message.body);
+        };
+        assert_eq!(&*attachment.url, "mxc://example.com/notes");
+        assert_eq!(&*attachment.filename, "notes.txt");
+        assert_eq!(attachment.size, Some(1234));
+        assert_eq!(message.reply_to, None);
+    }
+
+    #[test]
+    fn emote() {
+        let message = convert(serde_json::json!({
+            "type": "m.room.message",
+            "event_id": "$wave:example.com",
+            "sender": "@alice:example.com",
+            "origin_server_ts": 1_699_999_999_000_u64,
+            "content": { "msgtype": "m.emote", "body": "waves" }
+        }));
+        assert_eq!(message.body.plain_text(), "* alice waves");
+    }
+}
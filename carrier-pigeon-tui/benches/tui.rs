@@ -0,0 +1,42 @@
+//! Benchmarks for key sequence resolution and the message list. These need the `bench` feature:
+//!
+//! ```sh
+//! cargo bench -p carrier-pigeon-tui --features bench
+//! ```
+//!
+//! `cargo test -p carrier-pigeon-tui --features bench --benches` runs each benchmark once, to check
+//! that they work. The workloads themselves are also run once by the crate's tests.
+
+use carrier_pigeon_tui::bench::{self, MessageListView};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ratatui::buffer::Buffer;
+
+mod workloads;
+
+fn keymap_resolution(c: &mut Criterion) {
+    let (keymap, presses) = workloads::keymap_input();
+    c.bench_function("resolve 10k key presses", |b| {
+        b.iter(|| workloads::resolve(&keymap, &presses))
+    });
+}
+
+fn message_list(c: &mut Criterion) {
+    let messages = workloads::messages();
+    c.bench_function("insert 10k messages", |b| {
+        b.iter_batched(
+            || messages.clone(),
+            workloads::insert,
+            BatchSize::LargeInput,
+        )
+    });
+
+    let mut view = MessageListView::default();
+    view.extend(messages);
+    let mut buffer = Buffer::empty(workloads::AREA);
+    c.bench_function("redraw 10k messages", |b| {
+        b.iter(|| workloads::redraw(&mut view, &mut buffer))
+    });
+}
+
+criterion_group!(benches, keymap_resolution, message_list);
+criterion_main!(benches);
@@ -0,0 +1,78 @@
+//! The inputs and workloads of the benchmarks, shared with the test which checks that each of them
+//! runs. The module including this needs the crate's `bench` module in scope.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use carrier_pigeon_common::Message;
+use chrono::DateTime;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
+
+use super::bench::{parse_key_sequence, KeyEvent, Keymap, KeymapHandler, MessageListView};
+
+pub const SEED: u64 = 42;
+pub const KEYMAP_SIZE: usize = 10_000;
+pub const KEY_PRESSES: usize = 10_000;
+pub const MESSAGE_COUNT: usize = 10_000;
+/// The area the list is rendered to.
+pub const AREA: Rect = Rect::new(0, 0, 80, 50);
+
+/// The keys bindings are made of.
+const KEYS: &[&str] = &[
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s",
+    "t", "u", "v", "w", "x", "y", "z", "<C-a>", "<C-w>", "<Tab>", "<Esc>", "<CR>", "0", "5",
+];
+
+/// A keymap of random bindings of one to four keys, and random key presses made of the same
+/// keys.
+pub fn keymap_input() -> (Keymap<usize>, Vec<KeyEvent>) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let sequence = |rng: &mut StdRng, len: usize| {
+        let keys = (0..len)
+            .map(|_| *KEYS.choose(rng).unwrap())
+            .collect::<String>();
+        parse_key_sequence(&keys).expect("the keys are valid")
+    };
+    let keys = (0..KEYMAP_SIZE)
+        .map(|action| {
+            let len = rng.gen_range(1..=4);
+            (sequence(&mut rng, len), (action, rng.gen_bool(0.1)))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let presses = sequence(&mut rng, KEY_PRESSES);
+    let keymap = Keymap {
+        keys,
+        timeout: Duration::from_secs(1),
+        counts: true,
+    };
+    (keymap, presses)
+}
+
+/// Feeds the key presses to a new handler, returning how many actions they resolved to.
+pub fn resolve(keymap: &Keymap<usize>, presses: &[KeyEvent]) -> usize {
+    let mut handler = KeymapHandler::default();
+    presses
+        .iter()
+        .map(|&event| std::hint::black_box(handler.next(keymap, event)).1.len())
+        .sum()
+}
+
+pub fn messages() -> Vec<Message> {
+    let start = DateTime::from_timestamp(1_700_000_000, 0).expect("the timestamp is in range");
+    carrier_pigeon_fake_messages::seeded_messages(SEED, MESSAGE_COUNT, start)
+}
+
+/// Inserts the messages one at a time.
+pub fn insert(messages: Vec<Message>) -> MessageListView {
+    let mut view = MessageListView::default();
+    for message in messages {
+        view.insert(message);
+    }
+    view
+}
+
+/// Lays out the whole list again and renders it.
+pub fn redraw(view: &mut MessageListView, buffer: &mut Buffer) {
+    view.invalidate();
+    view.render(buffer.area, buffer);
+}
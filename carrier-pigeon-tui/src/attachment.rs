@@ -0,0 +1,132 @@
+use std::path::{Path, PathBuf};
+
+use carrier_pigeon_common::{Attachment, AttachmentResolver, FetchError};
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SaveError {
+    #[error("error fetching attachment: {0}")]
+    Fetch(#[from] FetchError),
+    #[error("error writing {}: {error}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+}
+
+/// Fetches an attachment and saves it in `dir`, returning the path it was saved to.
+///
+/// Only the last component of the sender's file name is used, and existing files are never
+/// overwritten.
+pub async fn save_attachment(
+    resolver: &impl AttachmentResolver,
+    attachment: &Attachment,
+    dir: &Path,
+) -> Result<PathBuf, SaveError> {
+    let contents = resolver.fetch(&attachment.url).await?;
+    let filename = Path::new(&*attachment.filename)
+        .file_name()
+        .unwrap_or("attachment".as_ref());
+    let path = dir.join(filename);
+    let write = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await?;
+        file.write_all(&contents).await?;
+        // tokio only finishes writing in the background, unless flushed
+        file.flush().await
+    };
+    match write.await {
+        Ok(()) => Ok(path),
+        Err(error) => Err(SaveError::Write { path, error }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resolves only `fake://cat`, to the same bytes every time.
+    struct FakeResolver;
+
+    impl AttachmentResolver for FakeResolver {
+        fn fetch(
+            &self,
+            url: &str,
+        ) -> impl std::future::Future<Output = Result<Vec<u8>, FetchError>> + Send {
+            let result = match url {
+                "fake://cat" => Ok(b"meow".to_vec()),
+                _ => Err(FetchError::UnsupportedUrl(url.to_owned())),
+            };
+            std::future::ready(result)
+        }
+    }
+
+    fn attachment(url: &str, filename: &str) -> Attachment {
+        Attachment {
+            url: url.into(),
+            filename: filename.into(),
+            size: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn saves_fetched_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = save_attachment(
+            &FakeResolver,
+            &attachment("fake://cat", "cat.txt"),
+            dir.path(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.path().join("cat.txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"meow");
+    }
+
+    #[tokio::test]
+    async fn only_file_name_is_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let attachment = attachment("fake://cat", "../../cat.txt");
+        let path = save_attachment(&FakeResolver, &attachment, dir.path())
+            .await
+            .unwrap();
+        assert_eq!(path, dir.path().join("cat.txt"));
+    }
+
+    #[tokio::test]
+    async fn existing_file_isnt_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cat.txt"), "woof").unwrap();
+        let result = save_attachment(
+            &FakeResolver,
+            &attachment("fake://cat", "cat.txt"),
+            dir.path(),
+        )
+        .await;
+        assert!(matches!(result, Err(SaveError::Write { .. })), "{result:?}");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("cat.txt")).unwrap(),
+            "woof"
+        );
+    }
+
+    #[tokio::test]
+    async fn fetch_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let result = save_attachment(
+            &FakeResolver,
+            &attachment("fake://dog", "dog.txt"),
+            dir.path(),
+        )
+        .await;
+        assert!(
+            matches!(result, Err(SaveError::Fetch(FetchError::UnsupportedUrl(_)))),
+            "{result:?}"
+        );
+        assert!(!dir.path().join("dog.txt").exists());
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::{
+    keymap::{KeyEvent, KeySequence},
+    CommandEvent, ConfigError, InsertEvent, MainEvent, SearchEvent,
+};
+
+/// Key bindings for each mode, e.g. from a `keys.toml` like
+///
+/// ```toml
+/// [main]
+/// "<C-n>" = "select-next"
+/// "<C-p>" = "select-prev"
+/// "<C-s>" = { enter-mode = "search" }
+/// ```
+///
+/// The main view and insert mode can switch to the `normal`, `insert`, `command`, or `search`
+/// mode with `enter-mode`. A binding can also be written as `{ action = "...", nowait = true }`,
+/// in which case it's taken as soon as its keys are pressed, even if they start a longer binding.
+/// Writing the same key sequence twice in one mode (e.g. as `G` and `<S-g>`) is an error.
+///
+/// The bindings given for a mode replace all of its default bindings, while modes which aren't
+/// given keep theirs. Recording and replaying macros (`q` and `@` followed by a register) is
+/// always bound in the main view.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    main: Option<ModeBindings<MainEvent>>,
+    insert: Option<ModeBindings<InsertEvent>>,
+    command: Option<ModeBindings<CommandEvent>>,
+    search: Option<ModeBindings<SearchEvent>>,
+}
+
+impl KeyBindings {
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Loads the key bindings file, or the default bindings if it doesn't exist.
+    pub fn from_path(path: &Path) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::from_toml(&text).map_err(|source| ConfigError::Parse {
+                path: path.to_owned(),
+                source,
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(source) => Err(ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            }),
+        }
+    }
+
+    pub(crate) fn main(&self) -> Option<Bindings<MainEvent>> {
+        self.main.as_ref().map(|bindings| bindings.0.clone())
+    }
+
+    pub(crate) fn insert(&self) -> Option<Bindings<InsertEvent>> {
+        self.insert.as_ref().map(|bindings| bindings.0.clone())
+    }
+
+    pub(crate) fn command(&self) -> Option<Bindings<CommandEvent>> {
+        self.command.as_ref().map(|bindings| bindings.0.clone())
+    }
+
+    pub(crate) fn search(&self) -> Option<Bindings<SearchEvent>> {
+        self.search.as_ref().map(|bindings| bindings.0.clone())
+    }
+}
+
+/// The keys of a [`Keymap`](crate::keymap::Keymap).
+pub(crate) type Bindings<A> = BTreeMap<Vec<KeyEvent>, (A, bool)>;
+
+/// The bindings of one mode, as they're written in the key bindings file.
+#[derive(Clone, Debug)]
+struct ModeBindings<A>(Bindings<A>);
+
+impl<'de, A: Deserialize<'de>> Deserialize<'de> for ModeBindings<A> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let written = BTreeMap::<String, Binding<A>>::deserialize(deserializer)?;
+        let mut bindings = Bindings::new();
+        // how each key sequence was written, to report duplicates
+        let mut texts = BTreeMap::new();
+        for (text, binding) in written {
+            let KeySequence(keys) = KeySequence::parse(&text).map_err(D::Error::custom)?;
+            if let Some(first) = texts.insert(keys.clone(), text.clone()) {
+                return Err(D::Error::custom(format!(
+                    "key sequences {first:?} and {text:?} are the same"
+                )));
+            }
+            bindings.insert(
+                keys,
+                match binding {
+                    Binding::Action(action) => (action, false),
+                    Binding::WithOptions { action, nowait } => (action, nowait),
+                },
+            );
+        }
+        Ok(Self(bindings))
+    }
+}
+
+/// The action bound to a key sequence, e.g. `"select-next"`, optionally with options, e.g.
+/// `{ action = "select-next", nowait = true }`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Binding<A> {
+    Action(A),
+    WithOptions {
+        action: A,
+        #[serde(default)]
+        nowait: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{keymap::parse_key_sequence, Mode};
+
+    fn keys(text: &str) -> Vec<KeyEvent> {
+        parse_key_sequence(text).unwrap()
+    }
+
+    #[test]
+    fn bindings_and_options() {
+        let bindings = KeyBindings::from_toml(
+            r#"
+            [main]
+            "j" = "select-next"
+            "g" = { action = "select-first", nowait = true }
+            "<C-s>" = { enter-mode = "search" }
+            "#,
+        )
+        .unwrap();
+        let main = bindings.main().unwrap();
+        assert!(matches!(main[&keys("j")], (MainEvent::SelectNext, false)));
+        assert!(matches!(main[&keys("g")], (MainEvent::SelectFirst, true)));
+        assert!(matches!(
+            main[&keys("<C-s>")],
+            (MainEvent::EnterMode(Mode::Search), false)
+        ));
+        assert!(bindings.insert().is_none());
+    }
+
+    #[test]
+    fn duplicate_sequences() {
+        let err = KeyBindings::from_toml(
+            r#"
+            [main]
+            "G" = "select-last"
+            "<S-g>" = "select-first"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.message().contains("are the same"), "{err}");
+    }
+}
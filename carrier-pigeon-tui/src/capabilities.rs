@@ -0,0 +1,212 @@
+use ratatui::{
+    style::{Color, Style},
+    text::Text,
+};
+
+/// What the terminal can display, so that rendering can degrade gracefully.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Whether RGB colors can be shown. Otherwise, colors are reduced to the 16 ANSI colors.
+    pub truecolor: bool,
+    /// Whether non-ASCII symbols can be shown.
+    pub unicode: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            truecolor: true,
+            unicode: true,
+        }
+    }
+}
+
+/// The 16 ANSI colors, with their usual (xterm) values.
+const ANSI_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+impl Capabilities {
+    /// Detects the capabilities from the environment: truecolor from `COLORTERM`, and Unicode
+    /// from the locale (the first of `LC_ALL`, `LC_CTYPE`, and `LANG` which is set).
+    pub fn detect() -> Self {
+        Self::from_env(|name| std::env::var(name).ok())
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        let truecolor = var("COLORTERM")
+            .is_some_and(|colorterm| matches!(colorterm.as_str(), "truecolor" | "24bit"));
+        let unicode = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .into_iter()
+            .find_map(|name| var(name).filter(|value| !value.is_empty()))
+            .is_some_and(|locale| {
+                let locale = locale.to_ascii_lowercase();
+                locale.contains("utf-8") || locale.contains("utf8")
+            });
+        Self { truecolor, unicode }
+    }
+
+    /// Replaces a color the terminal can't show with the nearest one it can.
+    pub fn color(self, color: Color) -> Color {
+        if self.truecolor {
+            return color;
+        }
+        let rgb = match color {
+            Color::Rgb(r, g, b) => (r, g, b),
+            Color::Indexed(index @ 16..) => indexed_rgb(index),
+            color => return color,
+        };
+        nearest_ansi(rgb)
+    }
+
+    pub fn style(self, style: Style) -> Style {
+        Style {
+            fg: style.fg.map(|color| self.color(color)),
+            bg: style.bg.map(|color| self.color(color)),
+            underline_color: style.underline_color.map(|color| self.color(color)),
+            ..style
+        }
+    }
+
+    /// Replaces the colors in each span of the text, as in [`Capabilities::style`].
+    pub fn downgrade_text(self, text: &mut Text) {
+        if self.truecolor {
+            return;
+        }
+        text.style = self.style(text.style);
+        for line in &mut text.lines {
+            line.style = self.style(line.style);
+            for span in &mut line.spans {
+                span.style = self.style(span.style);
+            }
+        }
+    }
+
+    /// Marks where text has been shortened.
+    pub fn ellipsis(self) -> &'static str {
+        if self.unicode {
+            "…"
+        } else {
+            "..."
+        }
+    }
+}
+
+/// The usual value of a color in the 256-color palette, other than the 16 ANSI colors.
+fn indexed_rgb(index: u8) -> (u8, u8, u8) {
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        16..=231 => {
+            let index = index - 16;
+            (
+                CUBE_LEVELS[usize::from(index / 36)],
+                CUBE_LEVELS[usize::from(index / 6 % 6)],
+                CUBE_LEVELS[usize::from(index % 6)],
+            )
+        }
+        // the grayscale ramp
+        _ => {
+            let level = 8 + 10 * index.saturating_sub(232);
+            (level, level, level)
+        }
+    }
+}
+
+fn nearest_ansi((r, g, b): (u8, u8, u8)) -> Color {
+    let distance = |(r2, g2, b2): (u8, u8, u8)| {
+        [(r, r2), (g, g2), (b, b2)]
+            .into_iter()
+            .map(|(a, b)| (i32::from(a) - i32::from(b)).pow(2))
+            .sum::<i32>()
+    };
+    ANSI_COLORS
+        .into_iter()
+        .min_by_key(|&(_, rgb)| distance(rgb))
+        .map_or(Color::Reset, |(color, _)| color)
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::text::Line;
+
+    use super::*;
+
+    const BASIC: Capabilities = Capabilities {
+        truecolor: false,
+        unicode: false,
+    };
+
+    fn from_env(vars: &[(&str, &str)]) -> Capabilities {
+        Capabilities::from_env(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(from_env(&[]), BASIC);
+        assert_eq!(
+            from_env(&[("COLORTERM", "truecolor"), ("LANG", "en_US.UTF-8")]),
+            Capabilities::default()
+        );
+        // the first locale variable which is set wins, even if a later one is UTF-8
+        assert!(!from_env(&[("LC_ALL", "C"), ("LANG", "en_US.UTF-8")]).unicode);
+        assert!(from_env(&[("LC_ALL", ""), ("LC_CTYPE", "C.utf8")]).unicode);
+        assert!(!from_env(&[("COLORTERM", "yes")]).truecolor);
+    }
+
+    #[test]
+    fn colors_map_to_nearest_ansi() {
+        assert_eq!(BASIC.color(Color::Rgb(250, 10, 10)), Color::LightRed);
+        assert_eq!(BASIC.color(Color::Rgb(0, 0, 200)), Color::Blue);
+        assert_eq!(BASIC.color(Color::Rgb(120, 130, 125)), Color::DarkGray);
+        // from the color cube and the grayscale ramp
+        assert_eq!(BASIC.color(Color::Indexed(196)), Color::LightRed);
+        assert_eq!(BASIC.color(Color::Indexed(232)), Color::Black);
+        // the ANSI colors themselves are kept
+        assert_eq!(BASIC.color(Color::Indexed(9)), Color::Indexed(9));
+        assert_eq!(BASIC.color(Color::Cyan), Color::Cyan);
+        assert_eq!(
+            Capabilities::default().color(Color::Rgb(250, 10, 10)),
+            Color::Rgb(250, 10, 10)
+        );
+    }
+
+    #[test]
+    fn downgrade_text() {
+        let mut text = Text::from(Line::styled(
+            "hi",
+            Style::new()
+                .fg(Color::Rgb(0, 250, 0))
+                .bg(Color::Indexed(231)),
+        ));
+        BASIC.downgrade_text(&mut text);
+        assert_eq!(
+            text.lines[0].style,
+            Style::new().fg(Color::LightGreen).bg(Color::White)
+        );
+    }
+
+    #[test]
+    fn glyphs() {
+        assert_eq!(BASIC.ellipsis(), "...");
+        assert_eq!(Capabilities::default().ellipsis(), "…");
+    }
+}
@@ -0,0 +1,32 @@
+use std::io::Write;
+
+use base64::Engine;
+
+/// Copies text to the system clipboard, using the OSC 52 escape sequence. This works over SSH,
+/// but only in terminals which support it.
+pub fn copy(text: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    write_copy(&mut stdout, text)?;
+    stdout.flush()
+}
+
+/// Writes the escape sequence which copies the text.
+fn write_copy(mut writer: impl Write, text: &str) -> std::io::Result<()> {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(text);
+    write!(writer, "\x1b]52;c;{encoded}\x07")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_escape_sequence() {
+        let mut output = Vec::new();
+        write_copy(&mut output, "1970-01-01T00:00:00+00:00").unwrap();
+        assert_eq!(
+            output,
+            b"\x1b]52;c;MTk3MC0wMS0wMVQwMDowMDowMCswMDowMA==\x07"
+        );
+    }
+}
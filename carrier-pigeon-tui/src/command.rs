@@ -0,0 +1,209 @@
+use std::path::PathBuf;
+
+use crate::config::NotifyLevel;
+
+/// A command entered in command mode.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Command {
+    Quit,
+    /// Deletes the given number of messages, starting with the selected one
+    Delete(usize),
+    /// Selects the message with the given identifier or, if there is none and it is a number, the
+    /// message with that (1-based) position in the list
+    Goto(String),
+    /// Writes the metadata of the listed messages to a CSV file
+    ExportCsv(PathBuf),
+    /// Sets the notification level for the selected message's room
+    Notify(NotifyLevel),
+    /// Shows a summary of the messages
+    Stats,
+    /// Copies all the messages to the clipboard
+    YankAll,
+    /// Adds or removes a reaction to the selected message
+    React(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum CommandError {
+    #[error("unknown command `{0}`")]
+    Unknown(String),
+    #[error("invalid argument `{arg}` for `{command}`")]
+    InvalidArgument { command: String, arg: String },
+    #[error("too many arguments for `{0}`")]
+    TooManyArguments(String),
+    #[error("too few arguments for `{0}`")]
+    MissingArgument(String),
+    #[error("no message selected")]
+    NoSelection,
+    #[error("nothing recorded in register `{0}`")]
+    EmptyRegister(char),
+    #[error("no shown message has the identifier `{0}`")]
+    NoSuchMessage(String),
+    #[error("nothing to undo")]
+    NothingToUndo,
+    #[error("no search to repeat")]
+    NoSearch,
+    #[error("no matches for `{0}`")]
+    NoMatches(String),
+    #[error("macros are nested too deeply")]
+    MacroTooDeep,
+    #[error("error writing {}: {error}", path.display())]
+    Write { path: PathBuf, error: String },
+}
+
+/// An error in one of several commands on a command line.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+#[error("command {}: {error}", index + 1)]
+pub struct CommandLineError {
+    /// The index of the failing command
+    pub index: usize,
+    #[source]
+    pub error: CommandError,
+}
+
+pub fn parse_command(input: &str) -> Result<Command, CommandError> {
+    let mut words = input.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let command = match name {
+        "q" | "quit" => Command::Quit,
+        "d" | "delete" => Command::Delete(match words.next() {
+            Some(arg) => arg.parse().map_err(|_| CommandError::InvalidArgument {
+                command: name.into(),
+                arg: arg.into(),
+            })?,
+            None => 1,
+        }),
+        "goto" => match words.next() {
+            Some(arg) => Command::Goto(arg.into()),
+            None => return Err(CommandError::MissingArgument(name.into())),
+        },
+        "stats" => Command::Stats,
+        "yank-all" => Command::YankAll,
+        "export-csv" => match words.next() {
+            Some(path) => Command::ExportCsv(path.into()),
+            None => return Err(CommandError::MissingArgument(name.into())),
+        },
+        "react" => match words.next() {
+            Some(emoji) => Command::React(emoji.into()),
+            None => return Err(CommandError::MissingArgument(name.into())),
+        },
+        "notify" => match words.next() {
+            Some(arg) => {
+                Command::Notify(arg.parse().map_err(|()| CommandError::InvalidArgument {
+                    command: name.into(),
+                    arg: arg.into(),
+                })?)
+            }
+            None => return Err(CommandError::MissingArgument(name.into())),
+        },
+        _ => return Err(CommandError::Unknown(name.into())),
+    };
+    match words.next() {
+        Some(_) => Err(CommandError::TooManyArguments(name.into())),
+        None => Ok(command),
+    }
+}
+
+/// Parses a `;`-separated list of commands, along with the index of each in the list. Empty
+/// commands are ignored, but still counted, so that the indices match what was typed.
+pub fn parse_command_line(input: &str) -> Result<Vec<(usize, Command)>, CommandLineError> {
+    input
+        .split(';')
+        .enumerate()
+        .filter(|(_, command)| !command.trim().is_empty())
+        .map(|(index, command)| {
+            parse_command(command)
+                .map(|command| (index, command))
+                .map_err(|error| CommandLineError { index, error })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goto() {
+        assert_eq!(parse_command("goto 12"), Ok(Command::Goto("12".into())));
+        assert_eq!(parse_command("goto $abc"), Ok(Command::Goto("$abc".into())));
+        assert_eq!(
+            parse_command("goto"),
+            Err(CommandError::MissingArgument("goto".into()))
+        );
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse_command("q"), Ok(Command::Quit));
+        assert_eq!(parse_command("  quit  "), Ok(Command::Quit));
+        assert_eq!(parse_command("d"), Ok(Command::Delete(1)));
+        assert_eq!(parse_command("delete 3"), Ok(Command::Delete(3)));
+        assert_eq!(
+            parse_command("export-csv out.csv"),
+            Ok(Command::ExportCsv("out.csv".into()))
+        );
+        assert_eq!(parse_command("react 👍"), Ok(Command::React("👍".into())));
+        assert_eq!(
+            parse_command("notify all"),
+            Ok(Command::Notify(NotifyLevel::All))
+        );
+    }
+
+    #[test]
+    fn command_errors() {
+        assert_eq!(
+            parse_command("frobnicate"),
+            Err(CommandError::Unknown("frobnicate".into()))
+        );
+        assert_eq!(
+            parse_command("delete two"),
+            Err(CommandError::InvalidArgument {
+                command: "delete".into(),
+                arg: "two".into()
+            })
+        );
+        assert_eq!(
+            parse_command("quit now"),
+            Err(CommandError::TooManyArguments("quit".into()))
+        );
+        assert_eq!(
+            parse_command("notify loudly"),
+            Err(CommandError::InvalidArgument {
+                command: "notify".into(),
+                arg: "loudly".into()
+            })
+        );
+    }
+
+    #[test]
+    fn command_line() {
+        assert_eq!(
+            parse_command_line("d 2; goto 5"),
+            Ok(vec![
+                (0, Command::Delete(2)),
+                (1, Command::Goto("5".into()))
+            ])
+        );
+        assert_eq!(parse_command_line(" ; "), Ok(vec![]));
+    }
+
+    #[test]
+    fn command_line_error_index() {
+        // empty commands still count towards the index
+        for line in ["q;;nope", ";q;nope", "q; ;nope"] {
+            assert_eq!(
+                parse_command_line(line),
+                Err(CommandLineError {
+                    index: 2,
+                    error: CommandError::Unknown("nope".into())
+                }),
+                "{line:?}"
+            );
+        }
+        assert_eq!(
+            parse_command_line(";;nope").unwrap_err().to_string(),
+            "command 3: unknown command `nope`"
+        );
+    }
+}
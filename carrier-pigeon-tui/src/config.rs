@@ -0,0 +1,592 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc};
+use ratatui::style::{Color, Style};
+use serde::{de::Error, Deserialize, Deserializer};
+use tokio::{
+    sync::{broadcast, watch},
+    time::Duration,
+};
+
+/// The default configuration file, with comments describing each option.
+pub const DEFAULT_CONFIG: &str = include_str!("default-config.toml");
+
+/// User-configurable settings for the TUI.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Deleting more than this many messages at once asks for confirmation first.
+    pub bulk_delete_threshold: usize,
+    /// After this long without any keypresses, the message list scrolls to the latest message
+    /// and follows new messages, until the next keypress.
+    #[serde(deserialize_with = "option_duration_secs")]
+    pub idle_follow_timeout: Option<Duration>,
+    /// Label messages with their room whenever the room changes from the previous message.
+    pub room_chips: bool,
+    /// Show a colored badge with the sender's initial before their name.
+    pub sender_avatars: bool,
+    /// How to get the user's attention, e.g. on an unmapped key.
+    pub bell: BellMode,
+    /// Which key submits the message being composed. The other one inserts a newline.
+    pub submit_key: SubmitKey,
+    /// Keep the message being composed when leaving insert mode, rather than discarding it.
+    pub keep_draft: bool,
+    /// Render older messages progressively dimmer.
+    pub age_fade: Option<AgeFade>,
+    /// How to order messages.
+    pub message_order: MessageOrder,
+    /// When messages are marked as read.
+    pub mark_read: ReadPolicy,
+    /// What moving past the first or last message does.
+    pub boundary_behavior: BoundaryBehavior,
+    /// How to show the sender of each message.
+    pub sender_display: SenderDisplay,
+    /// Where to show the reactions to each message.
+    pub reaction_display: ReactionDisplay,
+    /// How to show when each message was sent.
+    pub timestamp_format: TimestampFormat,
+    /// Indent replies under the messages they reply to.
+    pub reply_tree: bool,
+    /// Separate messages from different days (or weeks, or months).
+    pub date_separators: Option<SeparatorGranularity>,
+    /// How wide messages are in reading mode (toggled with `zw`), in columns.
+    pub reading_width: u16,
+    /// When messages have fewer than this many columns, show each on a single line, cut off at
+    /// the edge.
+    pub compact_below: Option<u16>,
+    /// Redraw the screen at most this many times a second, or as often as needed if 0.
+    pub max_fps: u16,
+    /// Select messages by clicking them, and move the selection with the scroll wheel. This stops
+    /// the terminal from selecting text with the mouse.
+    pub mouse: bool,
+    /// Show the command line, search, prompts and messages in place of the status line, rather
+    /// than on a row of their own.
+    pub merge_status_line: bool,
+    /// Show a sidebar listing the rooms, this many columns wide.
+    pub room_list_width: Option<u16>,
+    /// Where to save attachments. Defaults to the current directory.
+    pub download_dir: Option<PathBuf>,
+    /// Load the messages from this file at startup, and save them to it on exit.
+    pub history_file: Option<PathBuf>,
+    /// Start forwarded messages with who sent them, and in which room.
+    pub forward_prefix: bool,
+    /// After this long without any keypresses, hide the messages behind a lock screen.
+    #[serde(deserialize_with = "option_duration_secs")]
+    pub lock_timeout: Option<Duration>,
+    /// If set, the lock screen only unlocks once this is entered, rather than on any key.
+    pub lock_passphrase: Option<String>,
+    /// Which new messages to notify about (using the bell), unless overridden for the room.
+    pub notify: NotifyLevel,
+    /// Words which count as a mention of the user, matched case-insensitively.
+    pub mention_keywords: Vec<String>,
+    /// Notification levels for individual rooms, by room identifier.
+    pub room_notify: BTreeMap<String, NotifyLevel>,
+}
+
+impl Config {
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Loads the config file, or the default config if it doesn't exist.
+    pub fn from_path(path: &Path) -> Result<Self, ConfigError> {
+        Ok(Self::read(path)?.unwrap_or_default())
+    }
+
+    /// Loads the config file, if it exists.
+    fn read(path: &Path) -> Result<Option<Self>, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::from_toml(&text)
+                .map(Some)
+                .map_err(|source| ConfigError::Parse {
+                    path: path.to_owned(),
+                    source,
+                }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(source) => Err(ConfigError::Read {
+                path: path.to_owned(),
+                source,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("error reading config file {}", path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("invalid config file {}", path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+}
+
+/// A shared handle to the active config, which can be replaced while the TUI is running. The TUI
+/// picks up a new config before its next render, and shows any warnings about reloading it.
+#[derive(Clone, Debug)]
+pub struct ConfigHandle {
+    sender: Arc<watch::Sender<Arc<Config>>>,
+    warnings: broadcast::Sender<String>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Config) -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(Arc::new(config))),
+            warnings: broadcast::Sender::new(WARNING_CAPACITY),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.sender.borrow().clone()
+    }
+
+    /// Returns a receiver which is notified whenever a new config is published.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    /// Returns a receiver for warnings about reloading the config, which don't stop it from being
+    /// used.
+    pub fn warnings(&self) -> broadcast::Receiver<String> {
+        self.warnings.subscribe()
+    }
+
+    pub fn publish(&self, config: Config) {
+        self.sender.send_replace(Arc::new(config));
+    }
+
+    /// Reloads the config file and publishes it. If the file is invalid, the current config is
+    /// kept. If it no longer exists, the current config is also kept, rather than replaced with
+    /// the defaults, and a warning is sent.
+    pub fn reload_from(&self, path: &Path) -> Result<(), ConfigError> {
+        match Config::read(path)? {
+            Some(config) => self.publish(config),
+            None => {
+                // there may be no one listening
+                let _ = self.warnings.send(format!(
+                    "{} doesn't exist, keeping the current config",
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many warnings are kept for a receiver which hasn't read them yet.
+const WARNING_CAPACITY: usize = 16;
+
+impl From<Config> for ConfigHandle {
+    fn from(config: Config) -> Self {
+        Self::new(config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bulk_delete_threshold: 3,
+            idle_follow_timeout: None,
+            room_chips: false,
+            sender_avatars: false,
+            bell: BellMode::None,
+            submit_key: SubmitKey::Enter,
+            keep_draft: false,
+            age_fade: None,
+            message_order: MessageOrder::SenderTimestamp,
+            mark_read: ReadPolicy::OnSelect,
+            boundary_behavior: BoundaryBehavior::Stop,
+            sender_display: SenderDisplay::NameWithId,
+            reaction_display: ReactionDisplay::SeparateLine,
+            timestamp_format: TimestampFormat::Utc,
+            reply_tree: false,
+            date_separators: None,
+            reading_width: 80,
+            compact_below: None,
+            max_fps: 30,
+            mouse: true,
+            merge_status_line: false,
+            room_list_width: None,
+            download_dir: None,
+            history_file: None,
+            forward_prefix: true,
+            lock_timeout: None,
+            lock_passphrase: None,
+            notify: NotifyLevel::None,
+            mention_keywords: Vec::new(),
+            room_notify: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BellMode {
+    #[default]
+    None,
+    /// Ring the terminal bell
+    Audible,
+    /// Briefly invert the screen
+    Visual,
+    /// Both ring the terminal bell and invert the screen
+    Both,
+}
+
+impl BellMode {
+    pub fn audible(self) -> bool {
+        matches!(self, Self::Audible | Self::Both)
+    }
+
+    pub fn visual(self) -> bool {
+        matches!(self, Self::Visual | Self::Both)
+    }
+}
+
+/// Note that many terminals can't distinguish `Shift-Enter` from `Enter`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SubmitKey {
+    /// `Enter` submits, and `Shift-Enter` inserts a newline
+    #[default]
+    Enter,
+    /// `Shift-Enter` submits, and `Enter` inserts a newline
+    ShiftEnter,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageOrder {
+    /// Order by the sender's timestamp
+    #[default]
+    SenderTimestamp,
+    /// Order by when messages were received, in case the senders' clocks are unreliable
+    ReceivedOrder,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadPolicy {
+    /// A room is read up to the latest message which has been selected
+    #[default]
+    OnSelect,
+    /// A room is read up to its latest message when a message in it is selected after one in
+    /// another room, or it is focused
+    OnView,
+    /// Rooms are only marked as read explicitly (with `mr`)
+    Manual,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BoundaryBehavior {
+    /// The selection stays on the first or last message
+    #[default]
+    Stop,
+    /// The selection wraps around to the other end of the list
+    Wrap,
+    /// The selection stays on the first or last message, and the bell is rung
+    Bell,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SeparatorGranularity {
+    Day,
+    /// Weeks start on Monday
+    Week,
+    Month,
+}
+
+impl SeparatorGranularity {
+    /// The first day of the period containing the date.
+    pub fn period_start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Day => date,
+            Self::Week => date.week(chrono::Weekday::Mon).first_day(),
+            Self::Month => date.with_day(1).unwrap_or(date),
+        }
+    }
+
+    /// Describes the period starting on `start`, for its separator.
+    pub fn label(self, start: NaiveDate) -> String {
+        match self {
+            Self::Day => start.format("%A, %-d %B %Y").to_string(),
+            Self::Week => start.format("Week of %-d %B %Y").to_string(),
+            Self::Month => start.format("%B %Y").to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampFormat {
+    /// The date and time in UTC, e.g. `2024-05-01 14:03:27 UTC`
+    #[default]
+    Utc,
+    /// The date and time in the local time zone, e.g. `2024-05-01 16:03:27 +02:00`
+    Local,
+    /// Just the time of day in the local time zone, e.g. `16:03`
+    Time,
+    /// How long ago, e.g. `2m ago`
+    Relative,
+}
+
+impl TimestampFormat {
+    /// Formats the time, or just the time of day if `short`.
+    pub fn format(self, time: DateTime<Utc>, now: DateTime<Utc>, short: bool) -> String {
+        match self {
+            Self::Utc if short => time.format("%H:%M").to_string(),
+            Self::Utc => time.to_string(),
+            Self::Local if !short => time.with_timezone(&Local).to_string(),
+            Self::Local | Self::Time => time.with_timezone(&Local).format("%H:%M").to_string(),
+            Self::Relative => relative_time(now - time),
+        }
+    }
+
+    /// The date of the time, in the time zone it is shown in.
+    pub fn date(self, time: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Self::Utc => time.date_naive(),
+            Self::Local | Self::Time | Self::Relative => time.with_timezone(&Local).date_naive(),
+        }
+    }
+}
+
+/// Formats an age in the largest whole unit, e.g. "2m ago".
+fn relative_time(age: chrono::TimeDelta) -> String {
+    match age.num_seconds() {
+        ..0 => "in the future".into(),
+        secs @ 0..60 => format!("{secs}s ago"),
+        secs @ 60..3600 => format!("{}m ago", secs / 60),
+        secs @ 3600..86400 => format!("{}h ago", secs / 3600),
+        secs => format!("{}d ago", secs / 86400),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SenderDisplay {
+    /// The sender's display name, e.g. `alice`
+    NameOnly,
+    /// The sender's identifier, e.g. `@alice:example.com`
+    IdOnly,
+    /// Both, e.g. `alice (@alice:example.com)`
+    #[default]
+    NameWithId,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReactionDisplay {
+    /// After the body, e.g. `nice 👍 3`
+    Inline,
+    /// On a line of their own, under the body
+    #[default]
+    SeparateLine,
+    Hidden,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NotifyLevel {
+    /// Notify about every message
+    All,
+    /// Notify about messages which mention the user
+    MentionsOnly,
+    #[default]
+    None,
+}
+
+impl FromStr for NotifyLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "mentions-only" => Ok(Self::MentionsOnly),
+            "none" => Ok(Self::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How messages fade with age.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct AgeFade {
+    /// Messages younger than this are rendered at full brightness.
+    #[serde(deserialize_with = "duration_secs")]
+    pub start: Duration,
+    /// Messages older than this are rendered at the dimmest level.
+    #[serde(deserialize_with = "duration_secs")]
+    pub end: Duration,
+    #[serde(default)]
+    pub curve: FadeCurve,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FadeCurve {
+    /// Fade at a constant rate between `start` and `end`
+    #[default]
+    Linear,
+    /// Fade quickly at first, then more slowly
+    Logarithmic,
+}
+
+impl AgeFade {
+    /// The number of distinct fade levels, not counting full brightness.
+    pub const LEVELS: u8 = 8;
+
+    /// How faded a message of the given age is, from `0` (full brightness) to [`Self::LEVELS`].
+    pub fn level(&self, age: Duration) -> u8 {
+        if age <= self.start {
+            return 0;
+        }
+        if age >= self.end {
+            return Self::LEVELS;
+        }
+        let age = (age - self.start).as_secs_f64();
+        let range = (self.end - self.start).as_secs_f64();
+        let fraction = match self.curve {
+            FadeCurve::Linear => age / range,
+            FadeCurve::Logarithmic => age.ln_1p() / range.ln_1p(),
+        };
+        (fraction * f64::from(Self::LEVELS)).ceil() as u8
+    }
+
+    /// The style for a message of the given age.
+    pub fn style(&self, age: Duration) -> Style {
+        match self.level(age) {
+            0 => Style::new(),
+            // the 256-color palette has a grayscale ramp from 232 (darkest) to 255 (lightest)
+            level => Style::new().fg(Color::Indexed(255 - 2 * level)),
+        }
+    }
+}
+
+fn duration_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Duration::try_from_secs_f64(f64::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+fn option_duration_secs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    Option::<f64>::deserialize(deserializer)?
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_file() {
+        let config = Config::from_toml(DEFAULT_CONFIG).unwrap();
+        assert_eq!(format!("{config:?}"), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert_eq!(
+            format!("{:?}", Config::from_path(&path).unwrap()),
+            format!("{:?}", Config::default())
+        );
+        std::fs::write(&path, "bulk-delete-threshold = 10\nmouse = true\n").unwrap();
+        let config = Config::from_path(&path).unwrap();
+        assert_eq!(config.bulk_delete_threshold, 10);
+        assert!(config.mouse);
+        std::fs::write(&path, "no-such-option = 1\n").unwrap();
+        assert!(matches!(
+            Config::from_path(&path),
+            Err(ConfigError::Parse { .. })
+        ));
+    }
+
+    #[test]
+    fn publish_is_observed() {
+        let handle = ConfigHandle::new(Config::default());
+        let mut receiver = handle.subscribe();
+        handle.publish(Config {
+            bulk_delete_threshold: 7,
+            ..Config::default()
+        });
+        assert!(receiver.has_changed().unwrap());
+        assert_eq!(receiver.borrow_and_update().bulk_delete_threshold, 7);
+        assert_eq!(handle.current().bulk_delete_threshold, 7);
+    }
+
+    #[test]
+    fn reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let handle = ConfigHandle::new(Config::default());
+        let mut warnings = handle.warnings();
+
+        std::fs::write(&path, "bulk-delete-threshold = 10\n").unwrap();
+        handle.reload_from(&path).unwrap();
+        assert_eq!(handle.current().bulk_delete_threshold, 10);
+
+        // an invalid file is an error, and the current config is kept
+        std::fs::write(&path, "bulk-delete-threshold = \"ten\"\n").unwrap();
+        assert!(handle.reload_from(&path).is_err());
+        assert_eq!(handle.current().bulk_delete_threshold, 10);
+        assert!(warnings.try_recv().is_err());
+
+        // a deleted file is only a warning, and the current config is kept
+        std::fs::remove_file(&path).unwrap();
+        handle.reload_from(&path).unwrap();
+        assert_eq!(handle.current().bulk_delete_threshold, 10);
+        assert!(warnings.try_recv().unwrap().contains("doesn't exist"));
+    }
+
+    #[test]
+    fn age_fade_levels() {
+        let fade = |curve| AgeFade {
+            start: Duration::from_secs(60),
+            end: Duration::from_secs(3600),
+            curve,
+        };
+        let linear = fade(FadeCurve::Linear);
+        let logarithmic = fade(FadeCurve::Logarithmic);
+        for (secs, linear_level, logarithmic_level) in [
+            (0, 0, 0),
+            (60, 0, 0),
+            (61, 1, 1),
+            (120, 1, 5),
+            (600, 2, 7),
+            (1830, 4, 8),
+            (3600, 8, 8),
+            (7200, 8, 8),
+        ] {
+            let age = Duration::from_secs(secs);
+            assert_eq!(linear.level(age), linear_level, "{secs}s");
+            assert_eq!(logarithmic.level(age), logarithmic_level, "{secs}s");
+        }
+        assert_eq!(linear.style(Duration::ZERO), Style::new());
+        assert_eq!(
+            linear.style(Duration::from_secs(1830)),
+            Style::new().fg(Color::Indexed(247))
+        );
+        assert_eq!(
+            linear.style(Duration::from_secs(3600)),
+            Style::new().fg(Color::Indexed(239))
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use carrier_pigeon_common::Message;
+use chrono::SecondsFormat;
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::Widget,
+};
+use unicode_width::UnicodeWidthStr;
+
+use crate::room_list::elide;
+
+/// The most lines the details footer takes up.
+pub const MAX_DETAILS_LINES: usize = 3;
+
+/// A footer showing the full metadata of a message.
+#[derive(Debug)]
+pub struct MessageDetails {
+    lines: Vec<Line<'static>>,
+}
+
+impl MessageDetails {
+    /// Lays out the details of `message` (or a placeholder if no message is selected) to fit in
+    /// `width` columns.
+    pub fn new(message: Option<&Message>, width: usize, ellipsis: &str) -> Self {
+        let Some(message) = message else {
+            return Self {
+                lines: vec![Line::raw("no message selected").italic()],
+            };
+        };
+        let mut fields = vec![
+            (
+                "sent",
+                message
+                    .key
+                    .timestamp
+                    .to_rfc3339_opts(SecondsFormat::Millis, true),
+            ),
+            ("room", message.room.identifier.to_string()),
+            ("sender", message.sender.identifier.to_string()),
+            ("id", message.key.identifier.to_string()),
+            ("service", message.key.service.to_string()),
+        ];
+        if let Some(reply_to) = &message.reply_to {
+            fields.push(("reply to", reply_to.to_string()));
+        }
+        if let Some(received_at) = message.received_at {
+            fields.push((
+                "received",
+                received_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ));
+        }
+        if let Some(edited_at) = message.edited_at {
+            fields.push((
+                "edited",
+                edited_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            ));
+        }
+        Self {
+            lines: layout_fields(&fields, width, ellipsis),
+        }
+    }
+
+    pub fn height(&self) -> u16 {
+        self.lines.len() as u16
+    }
+}
+
+impl Widget for MessageDetails {
+    fn render(self, area: Rect, buffer: &mut Buffer) {
+        Text::from(self.lines).render(area, buffer);
+    }
+}
+
+/// Packs the `name: value` fields into at most [`MAX_DETAILS_LINES`] lines of `width` columns, in
+/// order. A field which doesn't fit on a line of its own is elided, and fields which don't fit in
+/// the lines at all are left out.
+fn layout_fields(fields: &[(&str, String)], width: usize, ellipsis: &str) -> Vec<Line<'static>> {
+    const SEPARATOR: &str = "  ";
+    let mut lines = Vec::<Line>::new();
+    for (name, value) in fields {
+        let label = format!("{name}: ");
+        let field_width = label.width() + value.width();
+        let fits = lines
+            .last()
+            .is_some_and(|line| line.width() + SEPARATOR.width() + field_width <= width);
+        if fits {
+            lines.last_mut().unwrap().spans.push(SEPARATOR.into());
+        } else if lines.len() < MAX_DETAILS_LINES {
+            lines.push(Line::default());
+        } else {
+            break;
+        }
+        let line = lines.last_mut().expect("a line was just pushed");
+        let value = elide(value, width.saturating_sub(label.width()), ellipsis);
+        line.spans.push(Span::styled(label, Style::new().dim()));
+        line.spans.push(value.into());
+    }
+    lines
+}
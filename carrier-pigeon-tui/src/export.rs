@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use carrier_pigeon_common::Message;
+
+/// Writes one CSV row of metadata per message, with a header row.
+pub fn write_csv<'a>(
+    messages: impl IntoIterator<Item = &'a Message>,
+    writer: impl Write,
+) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer.write_record([
+        "timestamp",
+        "room",
+        "sender_id",
+        "body_length",
+        "body_plain",
+    ])?;
+    for message in messages {
+        let body = message.body.plain_text();
+        writer.write_record([
+            &*message.key.timestamp.to_rfc3339(),
+            &message.room.display_name,
+            &message.sender.identifier,
+            &body.chars().count().to_string(),
+            &body,
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use carrier_pigeon_common::{MessageBody, MessageKey, RichText, Room, ServiceType, User};
+
+    use super::*;
+
+    fn message(identifier: &str, seconds: i64, sender: &str, text: &str) -> Message {
+        Message {
+            key: MessageKey {
+                timestamp: chrono::DateTime::from_timestamp(seconds, 0).unwrap(),
+                identifier: identifier.into(),
+                service: ServiceType::Fake,
+            },
+            sender: User {
+                display_name: sender.into(),
+                identifier: format!("@{sender}:example.com").into(),
+                service: ServiceType::Fake,
+            },
+            room: Room {
+                display_name: "general".into(),
+                identifier: "!general:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            reply_to: None,
+            body: MessageBody::Text(RichText::from_plain(text)),
+            received_at: None,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let messages = [
+            message("a", 0, "alice", "hello"),
+            message("b", 60, "bob", "one, \"two\"\nthree"),
+        ];
+        let mut output = Vec::new();
+        write_csv(&messages, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"one, \"\"two\"\"\nthree\""), "{output}");
+        let mut reader = csv::Reader::from_reader(output.as_bytes());
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec![
+                "timestamp",
+                "room",
+                "sender_id",
+                "body_length",
+                "body_plain"
+            ]
+        );
+        let rows = reader
+            .records()
+            .map(|record| record.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            vec![
+                "1970-01-01T00:00:00+00:00",
+                "general",
+                "@alice:example.com",
+                "5",
+                "hello"
+            ]
+        );
+        assert_eq!(
+            rows[1],
+            vec![
+                "1970-01-01T00:01:00+00:00",
+                "general",
+                "@bob:example.com",
+                "16",
+                "one, \"two\"\nthree"
+            ]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+
+use carrier_pigeon_common::{RichSpan, RichText};
+use ratatui::{
+    style::{Style, Stylize},
+    text::{Line, Span},
+};
+
+/// Renders message bodies, highlighting fenced code blocks and marking quotes with a bar for each
+/// level of nesting. Formatted text keeps its formatting, except within code blocks.
+///
+/// Highlighted code blocks are cached, since highlighting is much more expensive than rendering.
+/// Only the [`CACHE_CAPACITY`] most recently drawn blocks are kept.
+#[derive(Debug, Default)]
+pub struct Highlighter {
+    /// The lines of each block, by code and language, and when they were last drawn
+    cache: HashMap<(String, String), (Vec<Line<'static>>, u64)>,
+    /// How many blocks have been drawn
+    draws: u64,
+}
+
+/// How many highlighted code blocks are cached.
+const CACHE_CAPACITY: usize = 256;
+
+impl Highlighter {
+    pub fn body_lines(&mut self, text: &RichText) -> Vec<Line<'static>> {
+        let mut lines = Vec::new();
+        // the language and code of the current code block
+        let mut code_block: Option<(String, String)> = None;
+        for line in rich_lines(text) {
+            let plain = line.iter().map(|piece| piece.text).collect::<String>();
+            // quoted lines can't start or end a code block
+            let quote = line.first().map_or(0, |piece| piece.span.quote);
+            let fence = match quote {
+                0 => plain.trim_start().strip_prefix("```"),
+                _ => None,
+            };
+            match (&mut code_block, fence) {
+                (None, None) => lines.push(render_line(&line)),
+                (None, Some(language)) => {
+                    code_block = Some((language.trim().to_owned(), String::new()))
+                }
+                (Some((language, code)), Some(_)) => {
+                    lines.extend_from_slice(self.code_lines(language, code));
+                    code_block = None;
+                }
+                (Some((_, code)), None) => {
+                    if !code.is_empty() {
+                        code.push('\n');
+                    }
+                    code.push_str(&plain);
+                }
+            }
+        }
+        // an unterminated code block extends to the end of the text
+        if let Some((language, code)) = code_block {
+            lines.extend_from_slice(self.code_lines(&language, &code));
+        }
+        lines
+    }
+
+    fn code_lines(&mut self, language: &str, code: &str) -> &[Line<'static>] {
+        let key = (code.to_owned(), language.to_owned());
+        if self.cache.len() >= CACHE_CAPACITY && !self.cache.contains_key(&key) {
+            let least_recent = self
+                .cache
+                .iter()
+                .min_by_key(|(_, (_, drawn))| *drawn)
+                .map(|(key, _)| key.clone());
+            if let Some(least_recent) = least_recent {
+                self.cache.remove(&least_recent);
+            }
+        }
+        self.draws += 1;
+        let (lines, drawn) = self.cache.entry(key).or_insert_with(|| {
+            let lines = highlight(language, code).unwrap_or_else(|| fallback(code));
+            (lines, 0)
+        });
+        *drawn = self.draws;
+        lines
+    }
+}
+
+/// The part of a span which is on one line.
+#[derive(Debug)]
+struct Piece<'a> {
+    span: &'a RichSpan,
+    text: &'a str,
+    /// Whether this is the end of the span
+    last: bool,
+}
+
+/// Splits formatted text into lines at each newline in its spans. A newline at the end of the
+/// text doesn't start another line.
+fn rich_lines(text: &RichText) -> Vec<Vec<Piece<'_>>> {
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    for span in &text.0 {
+        let mut parts = span.text.split_inclusive('\n').peekable();
+        while let Some(part) = parts.next() {
+            let newline = part.ends_with('\n');
+            line.push(Piece {
+                span,
+                text: part.trim_end_matches(['\r', '\n']),
+                last: parts.peek().is_none(),
+            });
+            if newline {
+                lines.push(std::mem::take(&mut line));
+            }
+        }
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Renders a line of formatted text, with a bar for each blockquote it's nested in.
+fn render_line(line: &[Piece]) -> Line<'static> {
+    let mut spans = Vec::new();
+    if let Some(first) = line.first() {
+        spans.extend(quote_bars(first.span.quote));
+    }
+    for piece in line {
+        if !piece.text.is_empty() {
+            spans.push(Span::styled(piece.text.to_owned(), span_style(piece.span)));
+        }
+        // the link's target is shown after its text, unless they're the same
+        let link = piece.span.link.as_deref().filter(|_| piece.last);
+        if let Some(link) = link.filter(|link| **link != *piece.span.text) {
+            spans.push(Span::styled(format!(" <{link}>"), Style::new().dim()));
+        }
+    }
+    Line::from(spans)
+}
+
+fn span_style(span: &RichSpan) -> Style {
+    let mut style = Style::new();
+    if span.bold {
+        style = style.bold();
+    }
+    if span.italic {
+        style = style.italic();
+    }
+    if span.code {
+        style = style.on_dark_gray();
+    }
+    if span.link.is_some() {
+        style = style.underlined();
+    }
+    style
+}
+
+/// A bar for each level of nesting of a blockquote. The bars are whitespace, so that they are
+/// repeated when the line is wrapped.
+fn quote_bars(depth: usize) -> impl Iterator<Item = Span<'static>> {
+    (0..depth).flat_map(|_| {
+        [
+            Span::styled(" ", Style::new().on_dark_gray()),
+            Span::raw(" "),
+        ]
+    })
+}
+
+/// Renders code without highlighting.
+fn fallback(code: &str) -> Vec<Line<'static>> {
+    code.lines()
+        .map(|line| Line::from(Span::styled(line.to_owned(), Style::new().dim())))
+        .collect()
+}
+
+#[cfg(feature = "syntax-highlighting")]
+fn highlight(language: &str, code: &str) -> Option<Vec<Line<'static>>> {
+    use std::sync::LazyLock;
+
+    use ratatui::style::{Color, Modifier};
+    use syntect::{
+        easy::HighlightLines,
+        highlighting::{FontStyle, Theme, ThemeSet},
+        parsing::SyntaxSet,
+        util::LinesWithEndings,
+    };
+
+    static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+    static THEME: LazyLock<Theme> = LazyLock::new(|| {
+        ThemeSet::load_defaults()
+            .themes
+            .remove("base16-ocean.dark")
+            .unwrap_or_default()
+    });
+
+    let syntax = SYNTAXES.find_syntax_by_token(language)?;
+    let mut highlighter = HighlightLines::new(syntax, &THEME);
+    LinesWithEndings::from(code)
+        .map(|line| {
+            let spans = highlighter
+                .highlight_line(line, &SYNTAXES)
+                .inspect_err(|err| tracing::warn!("error highlighting {language} code: {err}"))
+                .ok()?
+                .into_iter()
+                .map(|(style, text)| {
+                    let mut modifier = Modifier::empty();
+                    modifier.set(Modifier::BOLD, style.font_style.contains(FontStyle::BOLD));
+                    modifier.set(
+                        Modifier::ITALIC,
+                        style.font_style.contains(FontStyle::ITALIC),
+                    );
+                    modifier.set(
+                        Modifier::UNDERLINED,
+                        style.font_style.contains(FontStyle::UNDERLINE),
+                    );
+                    let fg = style.foreground;
+                    Span::styled(
+                        text.trim_end_matches(['\r', '\n']).to_owned(),
+                        Style::new()
+                            .fg(Color::Rgb(fg.r, fg.g, fg.b))
+                            .add_modifier(modifier),
+                    )
+                })
+                .collect::<Line>();
+            Some(spans)
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "syntax-highlighting"))]
+fn highlight(_language: &str, _code: &str) -> Option<Vec<Line<'static>>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::style::Modifier;
+
+    use super::*;
+
+    fn line_text(line: &Line) -> String {
+        line.spans.iter().map(|span| &*span.content).collect()
+    }
+
+    #[test]
+    fn code_block_in_formatted_text() {
+        let text = RichText(vec![
+            RichSpan {
+                text: "look:".into(),
+                bold: true,
+                ..Default::default()
+            },
+            RichSpan::plain("\n```nonexistent-language\nlet x = 1;\n```\nneat"),
+        ]);
+        let lines = Highlighter::default().body_lines(&text);
+        let texts = lines.iter().map(line_text).collect::<Vec<_>>();
+        assert_eq!(texts, ["look:", "let x = 1;", "neat"]);
+        assert!(lines[0].spans[0]
+            .style
+            .add_modifier
+            .contains(Modifier::BOLD));
+        assert_eq!(lines[1].spans.len(), 1);
+        assert!(lines[1].spans[0].style.add_modifier.contains(Modifier::DIM));
+    }
+
+    #[test]
+    fn link_target_after_span() {
+        let text = RichText(vec![
+            RichSpan {
+                text: "the\ndocs".into(),
+                link: Some("https://example.com".into()),
+                ..Default::default()
+            },
+            RichSpan::plain("!"),
+        ]);
+        let lines = Highlighter::default().body_lines(&text);
+        let texts = lines.iter().map(line_text).collect::<Vec<_>>();
+        assert_eq!(texts, ["the", "docs <https://example.com>!"]);
+    }
+
+    #[test]
+    fn nested_quote_bars() {
+        let text = RichText::from_plain("> one\n> > two\nthree").parse_quotes();
+        let lines = Highlighter::default().body_lines(&text);
+        let texts = lines.iter().map(line_text).collect::<Vec<_>>();
+        assert_eq!(texts, ["  one", "    two", "three"]);
+        let bars = |line: &Line| {
+            line.spans
+                .iter()
+                .filter(|span| span.style.bg == Some(ratatui::style::Color::DarkGray))
+                .count()
+        };
+        assert_eq!(lines.iter().map(bars).collect::<Vec<_>>(), [1, 2, 0]);
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let mut highlighter = Highlighter::default();
+        highlighter.code_lines("", "first");
+        for i in 0..CACHE_CAPACITY {
+            highlighter.code_lines("", &i.to_string());
+            // keep the first block in use
+            highlighter.code_lines("", "first");
+        }
+        assert_eq!(highlighter.cache.len(), CACHE_CAPACITY);
+        let cached = |code: &str| {
+            highlighter
+                .cache
+                .contains_key(&(code.to_owned(), "".to_owned()))
+        };
+        assert!(cached("first"));
+        assert!(!cached("0"));
+        assert!(cached(&(CACHE_CAPACITY - 1).to_string()));
+    }
+
+    #[test]
+    fn unknown_language_is_dim() {
+        let lines = Highlighter::default()
+            .code_lines("nonexistent-language", "x")
+            .to_vec();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].spans.len(), 1);
+        assert!(lines[0].spans[0].style.add_modifier.contains(Modifier::DIM));
+    }
+
+    #[cfg(feature = "syntax-highlighting")]
+    #[test]
+    fn rust_is_highlighted() {
+        let lines = Highlighter::default()
+            .code_lines("rust", "fn main() {}")
+            .to_vec();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].spans.len() > 1);
+        let styles = lines[0]
+            .spans
+            .iter()
+            .map(|span| span.style)
+            .collect::<std::collections::HashSet<_>>();
+        assert!(styles.len() > 1);
+    }
+}
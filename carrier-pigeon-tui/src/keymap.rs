@@ -1,7 +1,8 @@
-use std::{cmp, collections::BTreeMap};
+use std::{cmp, collections::BTreeMap, fmt};
 
 use crossterm::event::KeyModifiers;
-use tokio::{sync::mpsc, time::Duration};
+use serde::{de::Error, Deserialize, Deserializer};
+use tokio::time::{Duration, Instant};
 
 // in order to resolve a key event, we need to know
 // - what mode (keymap) we are in
@@ -16,6 +17,25 @@ pub fn parse_key_sequence(input: &str) -> Result<Vec<KeyEvent>, nom::error::Erro
     nom::multi::many1(parse_key)(input).finish().map(|(_, k)| k)
 }
 
+/// A key sequence as it is written in a config file, e.g. `gg` or `<C-w>`.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct KeySequence(pub Vec<KeyEvent>);
+
+impl KeySequence {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        parse_key_sequence(text)
+            .map(Self)
+            .map_err(|err| format!("invalid key sequence {text:?}: can't parse {:?}", err.input))
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySequence {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        Self::parse(&text).map_err(D::Error::custom)
+    }
+}
+
 fn parse_key(input: &str) -> nom::IResult<&str, KeyEvent> {
     use nom::{
         branch::alt,
@@ -25,7 +45,6 @@ fn parse_key(input: &str) -> nom::IResult<&str, KeyEvent> {
         sequence::{delimited, separated_pair},
     };
 
-    let key = alt((KeyCode::parse_char, KeyCode::parse_special));
     let modifiers = nom::multi::fold_many1(
         map(one_of("ACMS"), |c| match c {
             'A' => KeyModifiers::ALT,
@@ -40,14 +59,16 @@ fn parse_key(input: &str) -> nom::IResult<&str, KeyEvent> {
 
     let bracketed = alt((
         map(
-            separated_pair(modifiers, tag("-"), key),
-            |(modifiers, code)| KeyEvent { modifiers, code },
+            separated_pair(modifiers, tag("-"), KeyCode::parse_bracketed),
+            |(modifiers, code)| KeyEvent::new(code, modifiers),
         ),
-        map(KeyCode::parse_special, KeyEvent::from),
+        map(KeyCode::parse_bracketed, KeyEvent::from),
     ));
     alt((
         delimited(tag("<"), bracketed, tag(">")),
         map(KeyCode::parse_char, KeyEvent::from),
+        // as in Vim, a `<` which doesn't start a key name is just a `<`
+        map(tag("<"), |_| KeyCode::Char('<').into()),
     ))(input)
 }
 
@@ -57,6 +78,25 @@ pub struct KeyEvent {
     pub modifiers: KeyModifiers,
 }
 
+impl KeyEvent {
+    /// Shift is folded into a character key, since terminals differ in whether they report `G` as
+    /// `G` or as `<S-g>`. Both of those, and `<S-G>`, are the same key.
+    pub fn new(code: KeyCode, mut modifiers: KeyModifiers) -> Self {
+        let code = match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => {
+                modifiers.remove(KeyModifiers::SHIFT);
+                let mut upper = c.to_uppercase();
+                match (upper.next(), upper.next()) {
+                    (Some(upper), None) => KeyCode::Char(upper),
+                    _ => code,
+                }
+            }
+            _ => code,
+        };
+        Self { code, modifiers }
+    }
+}
+
 impl From<KeyCode> for KeyEvent {
     fn from(code: KeyCode) -> Self {
         Self {
@@ -66,6 +106,58 @@ impl From<KeyCode> for KeyEvent {
     }
 }
 
+impl From<crossterm::event::KeyEvent> for KeyEvent {
+    fn from(event: crossterm::event::KeyEvent) -> Self {
+        Self::new(event.code.into(), event.modifiers)
+    }
+}
+
+/// Formats the key as it is written in a key sequence, so that it parses back to the same key.
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.code {
+            KeyCode::Char(' ') => "Space".to_owned(),
+            KeyCode::Char('<') => "lt".to_owned(),
+            KeyCode::Char(c) if self.modifiers.is_empty() => return write!(f, "{c}"),
+            KeyCode::Char(c) => c.to_string(),
+            KeyCode::Backspace => "BS".to_owned(),
+            KeyCode::Delete => "Del".to_owned(),
+            KeyCode::Enter => "CR".to_owned(),
+            KeyCode::Left => "Left".to_owned(),
+            KeyCode::Right => "Right".to_owned(),
+            KeyCode::Up => "Up".to_owned(),
+            KeyCode::Down => "Down".to_owned(),
+            KeyCode::Home => "Home".to_owned(),
+            KeyCode::End => "End".to_owned(),
+            KeyCode::PageUp => "PageUp".to_owned(),
+            KeyCode::PageDown => "PageDown".to_owned(),
+            KeyCode::Tab => "Tab".to_owned(),
+            KeyCode::Insert => "Ins".to_owned(),
+            KeyCode::Escape => "Esc".to_owned(),
+            KeyCode::F(n) => format!("F{n}"),
+            KeyCode::Unknown => "Unknown".to_owned(),
+        };
+        f.write_str("<")?;
+        let prefixes = [
+            (KeyModifiers::ALT, 'A'),
+            (KeyModifiers::CONTROL, 'C'),
+            (KeyModifiers::META, 'M'),
+            (KeyModifiers::SHIFT, 'S'),
+        ];
+        let mut modified = false;
+        for (modifier, prefix) in prefixes {
+            if self.modifiers.contains(modifier) {
+                write!(f, "{prefix}")?;
+                modified = true;
+            }
+        }
+        if modified {
+            f.write_str("-")?;
+        }
+        write!(f, "{name}>")
+    }
+}
+
 // manually impl `Ord` since `KeyModifiers` isn't `Ord`
 // https://github.com/crossterm-rs/crossterm/pull/951
 impl Ord for KeyEvent {
@@ -109,21 +201,35 @@ pub enum KeyCode {
     Insert,
     Escape,
     F(u8),
+    /// Any key which crossterm reports but which isn't otherwise represented here. It can be
+    /// bound as `<Unknown>`, e.g. to ignore such keys.
     Unknown,
 }
 
 impl KeyCode {
+    /// A key which is written as the character it types. The only whitespace allowed is a space.
     fn parse_char(input: &str) -> nom::IResult<&str, Self> {
         nom::combinator::map(
-            nom::character::complete::satisfy(nom_unicode::is_alphanumeric),
+            nom::character::complete::satisfy(|c| c != '<' && (c == ' ' || !c.is_whitespace())),
             Self::Char,
         )(input)
     }
 
+    /// A key inside `<...>`. It is a single character only if that's the whole key, so that e.g.
+    /// `<S-CR>` isn't read as `<S-C`, and `<5>` is the character rather than `<F5>`.
+    fn parse_bracketed(input: &str) -> nom::IResult<&str, Self> {
+        use nom::{bytes::complete::tag, combinator::peek, sequence::terminated};
+        nom::branch::alt((
+            terminated(Self::parse_char, peek(tag(">"))),
+            Self::parse_special,
+        ))(input)
+    }
+
     fn parse_special(input: &str) -> nom::IResult<&str, Self> {
         use nom::{
             bytes::complete::tag,
             combinator::{map, value},
+            sequence::preceded,
         };
         nom::branch::alt((
             value(Self::Backspace, tag("BS")),
@@ -140,7 +246,10 @@ impl KeyCode {
             value(Self::Tab, tag("Tab")),
             value(Self::Insert, tag("Ins")),
             value(Self::Escape, tag("Esc")),
-            map(nom::character::complete::u8, Self::F),
+            value(Self::Char(' '), tag("Space")),
+            value(Self::Char('<'), tag("lt")),
+            value(Self::Unknown, tag("Unknown")),
+            map(preceded(tag("F"), nom::character::complete::u8), Self::F),
         ))(input)
     }
 }
@@ -172,60 +281,31 @@ impl From<crossterm::event::KeyCode> for KeyCode {
 
 #[derive(Clone, Debug)]
 pub struct Keymap<A> {
-    pub keys: BTreeMap<Vec<KeyEvent>, A>,
+    /// The action for each key sequence, and whether it resolves immediately rather than
+    /// waiting to see whether a longer binding follows.
+    pub keys: BTreeMap<Vec<KeyEvent>, (A, bool)>,
     pub timeout: Duration,
+    /// Whether a leading run of digits is interpreted as a count for the following action.
+    pub counts: bool,
 }
 
-impl<A: Clone> Keymap<A> {
-    pub async fn run(
-        &mut self,
-        keys_rx: &mut mpsc::UnboundedReceiver<KeyEvent>,
-        passthru_callback: impl FnMut(&[KeyEvent]),
-        mut action_callback: impl FnMut(A),
-    ) {
-        let mut buffer = KeyBuffer::new(passthru_callback);
-        loop {
-            let event = if buffer.is_empty() {
-                Ok(keys_rx.recv().await)
-            } else {
-                tokio::time::timeout(self.timeout, keys_rx.recv()).await
-            };
-            match event {
-                Ok(Some(event)) => {
-                    // We store what is essentially a rolling window of recent keypresses. with
-                    // each new keypress, we check that window against our keymap to see if it is
-                    // a valid prefix to any mapping. If it is, we then check if it is a complete
-                    // mapping (not just a prefix), and then return the mapped action. If it is
-                    // not a valid prefix, we drop the least recent keypress, and repeat.
-                    //
-                    // In this manner, except for the most recent keypress, the buffer is always a
-                    // valid prefix of at least one mapping, so its size is limited by the length
-                    // of the longest mapping.
-                    buffer.push(event);
-                    let (skipped, action) = (0..buffer.len())
-                        .find_map(|i| self.get(&buffer[i..]).map(|action| (i, action)))
-                        .unwrap_or((buffer.len(), None));
-                    buffer.passthru_n(skipped);
-                    if let Some(action) = action {
-                        buffer.clear();
-                        action_callback(action);
-                    }
-                }
-                Ok(None) => {
-                    tracing::info!("key events stream stopped, shutting down");
-                    break;
-                }
-                Err(_) => {
-                    buffer.passthru_all();
-                }
-            }
-        }
-    }
+/// The result of looking up a key sequence in a [`Keymap`].
+#[derive(Clone, Debug)]
+enum Lookup<A> {
+    /// The key sequence is not a prefix of any binding
+    None,
+    /// The key sequence is a prefix of at least one longer binding, and may also be bound to an
+    /// action itself
+    Prefix(Option<A>),
+    /// The key sequence resolves to the action
+    Action(A),
+}
 
+impl<A: Clone> Keymap<A> {
     fn entries_with_prefix<'s, 'p>(
         &'s self,
         prefix: &'p [KeyEvent],
-    ) -> impl Iterator<Item = (&'s Vec<KeyEvent>, &'s A)> + use<'s, 'p, A> {
+    ) -> impl Iterator<Item = (&'s Vec<KeyEvent>, &'s (A, bool))> + use<'s, 'p, A> {
         use std::ops::Bound;
 
         self.keys
@@ -233,61 +313,328 @@ impl<A: Clone> Keymap<A> {
             .take_while(move |(k, _)| k.starts_with(prefix))
     }
 
-    /// Finds the action corresponding to the provided key sequence.
-    ///
-    /// ## Return values
-    /// - `Some(Some(action))`: the key sequence is mapped to the action
-    /// - `Some(None)`: the key sequence is a prefix to at least one action
-    /// - `None`: the key sequence is not a prefix to any action
-    fn get(&self, keys: &[KeyEvent]) -> Option<Option<A>> {
-        self.entries_with_prefix(keys)
-            .next()
-            .map(|(k, v)| (k == keys).then_some(v.clone()))
+    /// The bindings which continue the key sequence, as the rest of their keys and their actions.
+    pub fn continuations(&self, prefix: &[KeyEvent]) -> Vec<(&[KeyEvent], &A)> {
+        self.entries_with_prefix(prefix)
+            .filter(|(keys, _)| keys.len() > prefix.len())
+            .map(|(keys, (action, _))| (&keys[prefix.len()..], action))
+            .collect()
+    }
+
+    /// Finds the action corresponding to the provided key sequence. A key sequence which is both
+    /// bound and a prefix of a longer binding only resolves immediately if its binding is marked
+    /// to do so.
+    fn get(&self, keys: &[KeyEvent]) -> Lookup<A> {
+        let mut entries = self.entries_with_prefix(keys);
+        match entries.next() {
+            None => Lookup::None,
+            Some((k, (action, immediate))) if k == keys => {
+                if *immediate || entries.next().is_none() {
+                    Lookup::Action(action.clone())
+                } else {
+                    Lookup::Prefix(Some(action.clone()))
+                }
+            }
+            Some(_) => Lookup::Prefix(None),
+        }
     }
 }
 
-struct KeyBuffer<F: FnMut(&[KeyEvent])> {
+/// Resolves a stream of key events into actions, according to a [`Keymap`].
+#[derive(Debug, Default)]
+pub struct KeymapHandler {
     buffer: Vec<KeyEvent>,
-    passthru_callback: F,
+    count: Option<usize>,
+    deadline: Option<Instant>,
 }
 
-impl<F: FnMut(&[KeyEvent])> KeyBuffer<F> {
-    pub fn new(passthru_callback: F) -> Self {
-        Self {
-            buffer: Vec::new(),
-            passthru_callback,
-        }
+impl KeymapHandler {
+    /// Processes a new key event.
+    ///
+    /// Returns the keys which are no longer part of any pending sequence (and so should be passed
+    /// thru), and the resolved actions along with their counts. There may be more than one action
+    /// if the key ends a pending sequence which is bound itself, and then begins another.
+    pub fn next<A: Clone>(
+        &mut self,
+        keymap: &Keymap<A>,
+        event: KeyEvent,
+    ) -> (Vec<KeyEvent>, Vec<(A, Option<usize>)>) {
+        let mut passthru = Vec::new();
+        let mut actions = Vec::new();
+        self.push(keymap, event, &mut passthru, &mut actions);
+        self.deadline = (!self.buffer.is_empty()).then(|| Instant::now() + keymap.timeout);
+        (passthru, actions)
     }
 
-    /// Call the callback on the first `n` items, then remove them from the buffer.
-    pub fn passthru_n(&mut self, n: usize) {
-        (self.passthru_callback)(&self.buffer[..n]);
-        self.buffer.drain(n..).for_each(drop);
+    fn push<A: Clone>(
+        &mut self,
+        keymap: &Keymap<A>,
+        event: KeyEvent,
+        passthru: &mut Vec<KeyEvent>,
+        actions: &mut Vec<(A, Option<usize>)>,
+    ) {
+        // a count starts with a digit which isn't bound, but once it has started, any digit
+        // continues it, even a bound one such as `0`
+        let counting = self.count.is_some() || matches!(keymap.get(&[event]), Lookup::None);
+        if keymap.counts && self.buffer.is_empty() && counting {
+            if let Some(digit) = count_digit(event, self.count.is_some()) {
+                self.count = Some(
+                    self.count
+                        .unwrap_or(0)
+                        .saturating_mul(10)
+                        .saturating_add(digit),
+                );
+                return;
+            }
+        }
+        // if the pending keys are bound, but were waiting for a longer binding which this key
+        // doesn't continue, they resolve now, and this key starts afresh
+        if let Lookup::Prefix(Some(action)) = keymap.get(&self.buffer) {
+            self.buffer.push(event);
+            let continued = !matches!(keymap.get(&self.buffer), Lookup::None);
+            self.buffer.pop();
+            if !continued {
+                self.buffer.clear();
+                actions.push((action, self.count.take()));
+                return self.push(keymap, event, passthru, actions);
+            }
+        }
+        // We store what is essentially a rolling window of recent keypresses. with each new
+        // keypress, we check that window against our keymap to see if it is a valid prefix to any
+        // mapping. If it is, we then check if it is a complete mapping (not just a prefix), and
+        // then return the mapped action. If it is not a valid prefix, we drop the least recent
+        // keypress, and repeat.
+        //
+        // In this manner, except for the most recent keypress, the buffer is always a valid prefix
+        // of at least one mapping, so its size is limited by the length of the longest mapping.
+        self.buffer.push(event);
+        let (skipped, action) = (0..self.buffer.len())
+            .find_map(|i| match keymap.get(&self.buffer[i..]) {
+                Lookup::None => None,
+                Lookup::Prefix(_) => Some((i, None)),
+                Lookup::Action(action) => Some((i, Some(action))),
+            })
+            .unwrap_or((self.buffer.len(), None));
+        passthru.extend(self.buffer.drain(..skipped));
+        if skipped > 0 {
+            // the count belonged to the keys that were skipped
+            self.count = None;
+        }
+        if let Some(action) = action {
+            self.buffer.clear();
+            actions.push((action, self.count.take()));
+        }
     }
 
-    /// Call the callback on the whole buffer, then clear it.
-    pub fn passthru_all(&mut self) {
-        (self.passthru_callback)(&self.buffer);
-        self.buffer.clear();
+    /// The keys of the pending key sequence, which are a prefix of at least one binding.
+    pub fn pending(&self) -> &[KeyEvent] {
+        &self.buffer
     }
-}
 
-impl<F: FnMut(&[KeyEvent])> std::ops::Deref for KeyBuffer<F> {
-    type Target = Vec<KeyEvent>;
+    /// The time at which the pending key sequence (if any) times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
 
-    fn deref(&self) -> &Self::Target {
-        &self.buffer
+    /// Ends the pending key sequence. If the sequence is bound itself, returns its action along
+    /// with its count; otherwise returns the keys, which should be passed thru.
+    pub fn timeout<A: Clone>(
+        &mut self,
+        keymap: &Keymap<A>,
+    ) -> (Vec<KeyEvent>, Option<(A, Option<usize>)>) {
+        self.deadline = None;
+        let count = self.count.take();
+        let keys = std::mem::take(&mut self.buffer);
+        match keymap.get(&keys) {
+            Lookup::Prefix(Some(action)) | Lookup::Action(action) => {
+                (Vec::new(), Some((action, count)))
+            }
+            Lookup::Prefix(None) | Lookup::None => (keys, None),
+        }
     }
 }
 
-impl<F: FnMut(&[KeyEvent])> std::ops::DerefMut for KeyBuffer<F> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.buffer
+/// Interprets the key as a digit of a count. `0` is only a valid digit if it is not the first one.
+fn count_digit(event: KeyEvent, continued: bool) -> Option<usize> {
+    match event {
+        KeyEvent {
+            code: KeyCode::Char(c @ '0'..='9'),
+            modifiers,
+        } if modifiers.is_empty() && (continued || c != '0') => c.to_digit(10).map(|d| d as usize),
+        _ => None,
     }
 }
 
-impl<F: FnMut(&[KeyEvent])> Drop for KeyBuffer<F> {
-    fn drop(&mut self) {
-        self.passthru_all();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap(bindings: &[(&str, char, bool)], counts: bool) -> Keymap<char> {
+        Keymap {
+            keys: bindings
+                .iter()
+                .map(|&(keys, action, nowait)| {
+                    (parse_key_sequence(keys).unwrap(), (action, nowait))
+                })
+                .collect(),
+            timeout: Duration::from_secs(1),
+            counts,
+        }
+    }
+
+    /// Presses each key in turn, returning the keys passed thru and the actions.
+    fn press(
+        handler: &mut KeymapHandler,
+        keymap: &Keymap<char>,
+        keys: &str,
+    ) -> (Vec<KeyEvent>, Vec<(char, Option<usize>)>) {
+        let mut passthru = Vec::new();
+        let mut actions = Vec::new();
+        for key in parse_key_sequence(keys).unwrap() {
+            let (keys, new_actions) = handler.next(keymap, key);
+            passthru.extend(keys);
+            actions.extend(new_actions);
+        }
+        (passthru, actions)
+    }
+
+    #[test]
+    fn counts() {
+        let keymap = keymap(&[("j", 'j', false), ("dd", 'd', false)], true);
+        let mut handler = KeymapHandler::default();
+        assert_eq!(press(&mut handler, &keymap, "3j").1, [('j', Some(3))]);
+        assert_eq!(press(&mut handler, &keymap, "12dd").1, [('d', Some(12))]);
+        assert_eq!(press(&mut handler, &keymap, "j").1, [('j', None)]);
+        // a count is only taken from the start of the sequence, and goes with the skipped keys
+        let (passthru, actions) = press(&mut handler, &keymap, "2xj");
+        assert_eq!(passthru, parse_key_sequence("x").unwrap());
+        assert_eq!(actions, [('j', None)]);
+    }
+
+    #[test]
+    fn zero_is_bindable() {
+        let keymap = keymap(&[("0", '0', false), ("j", 'j', false)], true);
+        let mut handler = KeymapHandler::default();
+        assert_eq!(press(&mut handler, &keymap, "0").1, [('0', None)]);
+        assert_eq!(press(&mut handler, &keymap, "10j").1, [('j', Some(10))]);
+    }
+
+    #[test]
+    fn bound_digits_arent_counts() {
+        let keymap = keymap(&[("1", '1', false), ("j", 'j', false)], true);
+        let mut handler = KeymapHandler::default();
+        assert_eq!(press(&mut handler, &keymap, "1").1, [('1', None)]);
+    }
+
+    #[test]
+    fn counts_disabled() {
+        let keymap = keymap(&[("j", 'j', false)], false);
+        let mut handler = KeymapHandler::default();
+        let (passthru, actions) = press(&mut handler, &keymap, "3j");
+        assert_eq!(passthru, parse_key_sequence("3").unwrap());
+        assert_eq!(actions, [('j', None)]);
+    }
+
+    #[test]
+    fn nowait_prefix() {
+        let waiting = keymap(&[("d", 'x', false), ("dd", 'd', false)], false);
+        let mut handler = KeymapHandler::default();
+        assert!(press(&mut handler, &waiting, "d").1.is_empty());
+        assert_eq!(handler.pending(), parse_key_sequence("d").unwrap());
+        assert_eq!(press(&mut handler, &waiting, "d").1, [('d', None)]);
+
+        let nowait = keymap(&[("d", 'x', true), ("dd", 'd', false)], false);
+        let mut handler = KeymapHandler::default();
+        assert_eq!(press(&mut handler, &nowait, "d").1, [('x', None)]);
+        assert!(handler.pending().is_empty());
+        assert_eq!(press(&mut handler, &nowait, "d").1, [('x', None)]);
+    }
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    /// Parses the sequence, checking that writing it out again gives the same keys.
+    fn parse_round_trip(keys: &str) -> Vec<KeyEvent> {
+        let parsed = parse_key_sequence(keys).unwrap();
+        let written = parsed.iter().map(ToString::to_string).collect::<String>();
+        assert_eq!(parse_key_sequence(&written).unwrap(), parsed, "{written}");
+        parsed
+    }
+
+    #[test]
+    fn space_and_less_than() {
+        let space = KeyEvent::from(KeyCode::Char(' '));
+        let lt = KeyEvent::from(KeyCode::Char('<'));
+        assert_eq!(parse_round_trip("<Space>"), [space]);
+        assert_eq!(
+            parse_round_trip("<C-Space>"),
+            [key(KeyCode::Char(' '), KeyModifiers::CONTROL)]
+        );
+        let g = KeyEvent::from(KeyCode::Char('g'));
+        let w = KeyEvent::from(KeyCode::Char('w'));
+        assert_eq!(parse_round_trip("g<Space>w"), [g, space, w]);
+        assert_eq!(parse_round_trip("g w"), [g, space, w]);
+        assert_eq!(parse_round_trip("a b"), parse_round_trip("a<Space>b"));
+        assert_eq!(parse_round_trip("<lt>"), [lt]);
+        // a `<` which doesn't start a key name is just a `<`
+        assert_eq!(parse_round_trip("<g"), [lt, g]);
+        assert_eq!(parse_round_trip("<"), [lt]);
+    }
+
+    #[test]
+    fn function_keys_need_an_f() {
+        assert_eq!(parse_round_trip("<F10>"), [KeyEvent::from(KeyCode::F(10))]);
+        assert_eq!(parse_round_trip("<F5>"), [KeyEvent::from(KeyCode::F(5))]);
+        assert_eq!(
+            parse_round_trip("<C-F5>"),
+            [key(KeyCode::F(5), KeyModifiers::CONTROL)]
+        );
+        // a bracketed digit is the digit, the same as a bare one
+        let five = KeyEvent::from(KeyCode::Char('5'));
+        assert_eq!(parse_round_trip("<5>"), [five]);
+        assert_eq!(parse_round_trip("5"), [five]);
+        assert_eq!(
+            parse_round_trip("<F>"),
+            [KeyEvent::from(KeyCode::Char('F'))]
+        );
+        assert_eq!(
+            parse_round_trip("<S-CR>"),
+            [key(KeyCode::Enter, KeyModifiers::SHIFT)]
+        );
+    }
+
+    #[test]
+    fn shift_is_folded_into_characters() {
+        let upper_g = KeyEvent::from(KeyCode::Char('G'));
+        assert_eq!(parse_round_trip("G"), [upper_g]);
+        assert_eq!(parse_round_trip("<S-g>"), [upper_g]);
+        assert_eq!(parse_round_trip("<S-G>"), [upper_g]);
+        // other modifiers are kept
+        assert_eq!(
+            parse_round_trip("<CS-g>"),
+            [key(KeyCode::Char('G'), KeyModifiers::CONTROL)]
+        );
+        // however the terminal reports it
+        for event in [
+            crossterm::event::KeyEvent::from(crossterm::event::KeyCode::Char('G')),
+            crossterm::event::KeyEvent::new(
+                crossterm::event::KeyCode::Char('G'),
+                KeyModifiers::SHIFT,
+            ),
+            crossterm::event::KeyEvent::new(
+                crossterm::event::KeyCode::Char('g'),
+                KeyModifiers::SHIFT,
+            ),
+        ] {
+            assert_eq!(KeyEvent::from(event), upper_g, "{event:?}");
+        }
+        // and a binding to `G` is pressed with any of them
+        let keymap = keymap(&[("G", 'G', false)], false);
+        let mut handler = KeymapHandler::default();
+        assert_eq!(
+            press(&mut handler, &keymap, "<S-g>G<S-G>").1,
+            [('G', None), ('G', None), ('G', None)]
+        );
     }
 }
@@ -1,148 +1,3226 @@
-use carrier_pigeon_common::Message;
-use crossterm::event::{Event, KeyCode, KeyEvent};
-use ratatui::{buffer::Buffer, layout::Rect, widgets::Widget};
-use tokio::sync::mpsc;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use carrier_pigeon_common::{
+    FileUrlResolver, Message, MessageBody, MessageKey, MessageSink, Outbox, RichText, Room,
+    StreamEvent, User,
+};
+use chrono::Utc;
+use crossterm::event::{Event, KeyEventKind, MouseButton, MouseEvent, MouseEventKind};
+use futures::{
+    stream::{Stream, StreamExt},
+    FutureExt,
+};
+use ratatui::{
+    buffer::Buffer,
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{Block, Clear, Widget},
+};
+use serde::Deserialize;
+use tokio::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::CancellationToken;
+
+mod attachment;
+mod bindings;
+mod capabilities;
+mod clipboard;
+mod command;
+mod config;
+mod details;
+mod export;
+mod highlight;
 mod keymap;
 mod message_list;
+mod room_list;
+mod snapshot;
+mod text_input;
+mod toast;
+mod wrap;
+
+/// The benchmarks' inputs and workloads, which the tests run once to check that they work.
+#[cfg(test)]
+#[path = "../benches/workloads/mod.rs"]
+mod bench_workloads;
 
-use keymap::Keymap;
+pub use bindings::KeyBindings;
+use capabilities::Capabilities;
+use command::{Command, CommandError, CommandLineError};
+pub use config::{
+    AgeFade, BellMode, BoundaryBehavior, Config, ConfigError, ConfigHandle, FadeCurve,
+    MessageOrder, NotifyLevel, ReactionDisplay, ReadPolicy, SenderDisplay, SeparatorGranularity,
+    SubmitKey, TimestampFormat, DEFAULT_CONFIG,
+};
+use keymap::{KeyCode, KeyEvent, Keymap, KeymapHandler};
 use message_list::MessageListView;
+use room_list::RoomPicker;
+pub use snapshot::buffer_to_snapshot;
+use text_input::TextInput;
+use toast::Toast;
+
+/// The internals used by the benchmarks. These aren't a stable API.
+#[cfg(any(test, feature = "bench"))]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::{
+        keymap::{parse_key_sequence, KeyEvent, Keymap, KeymapHandler},
+        message_list::MessageListView,
+    };
+}
+
+/// Runs the TUI until the user quits, the message channel is closed, or `cancel` is cancelled.
+/// Messages the user sends go to `outbox`, and the messages sent by `user` are marked as theirs.
+///
+/// If `announce` is given, each new message which is notified about is also described on it in a
+/// line of plain text, e.g. for a screen reader to read aloud.
+pub async fn run(
+    messages: mpsc::UnboundedReceiver<StreamEvent>,
+    outbox: Outbox,
+    user: User,
+    config: ConfigHandle,
+    key_bindings: KeyBindings,
+    announce: Option<mpsc::UnboundedSender<String>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    run_with_stream(
+        UnboundedReceiverStream::new(messages),
+        outbox,
+        user,
+        config,
+        key_bindings,
+        announce,
+        cancel,
+    )
+    .await
+}
+
+/// Runs the TUI until the user quits, the message stream ends, or `cancel` is cancelled.
+pub async fn run_with_stream(
+    messages: impl Stream<Item = StreamEvent>,
+    outbox: Outbox,
+    user: User,
+    config: ConfigHandle,
+    key_bindings: KeyBindings,
+    announce: Option<mpsc::UnboundedSender<String>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    let mut terminal = ratatui::init();
+    let _guard = TerminalGuard;
+    // the panic hook `init` installs restores the rest of the terminal
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        set_mouse_capture(false);
+        hook(info);
+    }));
+    if config.subscribe().borrow().mouse {
+        set_mouse_capture(true);
+    }
+    run_inner(
+        &mut terminal,
+        crossterm::event::EventStream::new(),
+        messages,
+        outbox,
+        user,
+        config,
+        key_bindings,
+        announce,
+        cancel,
+    )
+    .await
+}
+
+/// Restores the terminal when dropped, whether `run_inner` returns or panics.
+struct TerminalGuard;
 
-pub async fn run(messages: mpsc::UnboundedReceiver<Message>) -> std::io::Result<()> {
-    let terminal = ratatui::init();
-    let res = run_inner(terminal, messages).await;
-    ratatui::restore();
-    res
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        set_mouse_capture(false);
+        ratatui::restore();
+    }
+}
+
+/// Enables or disables reporting mouse events, logging any error.
+fn set_mouse_capture(enabled: bool) {
+    let result = if enabled {
+        crossterm::execute!(std::io::stdout(), crossterm::event::EnableMouseCapture)
+    } else {
+        crossterm::execute!(std::io::stdout(), crossterm::event::DisableMouseCapture)
+    };
+    if let Err(err) = result {
+        tracing::warn!("error setting mouse capture: {err}");
+    }
+}
+
+/// Where the audible bell is written, which is the terminal except in tests.
+struct BellOutput(Box<dyn std::io::Write + Send>);
+
+impl std::fmt::Debug for BellOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("BellOutput")
+    }
+}
+
+impl Default for BellOutput {
+    fn default() -> Self {
+        Self(Box::new(std::io::stdout()))
+    }
 }
 
 #[derive(Debug)]
 struct State {
     stopped: bool,
+    /// The user running carrier-pigeon
+    user: User,
+    config: Arc<Config>,
+    /// What the terminal can display
+    capabilities: Capabilities,
     messages: MessageListView,
     main_keys: Keymap<MainEvent>,
+    insert_keys: Keymap<InsertEvent>,
+    command_keys: Keymap<CommandEvent>,
+    search_keys: Keymap<SearchEvent>,
+    key_handler: KeymapHandler,
     mode: Mode,
+    confirmation: Option<Confirmation>,
+    /// The message being composed
+    draft: TextInput,
+    command_line: TextInput,
+    search: TextInput,
+    /// The message which was selected before searching, to return to if the search is cancelled
+    pre_search: Option<MessageKey>,
+    /// Whether the search being entered matches nothing
+    search_failed: bool,
+    last_input: Instant,
+    /// Whether follow mode was enabled due to inactivity
+    idle_follow: bool,
+    /// The current toast, and when it was shown
+    toast: Option<(Toast, Instant)>,
+    /// Where background tasks report their results
+    background_toasts: mpsc::UnboundedSender<Toast>,
+    /// Where sending a draft in the background reports what happened to it
+    sent_drafts: mpsc::UnboundedSender<SentDraft>,
+    /// Whether the screen is inverted for a visual bell
+    flash: bool,
+    /// Where the audible bell is rung
+    bell_output: BellOutput,
+    /// The mode to return to when the lock screen is unlocked
+    unlocked_mode: Mode,
+    /// The passphrase being entered on the lock screen
+    passphrase: TextInput,
+    /// Notification levels for individual rooms, by room identifier
+    room_notify: BTreeMap<Arc<str>, NotifyLevel>,
+    /// Whether the footer with the selected message's details is shown
+    show_details: bool,
+    /// Where messages the user sends go
+    outbox: Outbox,
+    /// The message being forwarded, and the picker for the room to forward it to
+    forward: Option<(Message, RoomPicker)>,
+    /// Recorded key sequences, by register
+    macros: BTreeMap<char, Vec<KeyEvent>>,
+    /// The register being recorded into, and the keys recorded so far
+    recording: Option<(char, Vec<KeyEvent>)>,
+    /// How many macros are being replayed, each within the last
+    replay_depth: usize,
+    /// Where to announce the new messages which are notified about, e.g. for a screen reader
+    announce_tx: Option<mpsc::UnboundedSender<String>>,
+    /// The bindings which replace the defaults, kept to rebuild the insert keymap
+    key_bindings: KeyBindings,
 }
 
-const DEFAULT_KEY_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_millis(500);
+const DEFAULT_KEY_TIMEOUT: Duration = Duration::from_millis(500);
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// The most messages copied at once; terminals limit the size of the clipboard escape sequence.
+const MAX_YANK_MESSAGES: usize = 500;
+/// The most messages which are inserted between redraws, so that a burst of messages (e.g. the
+/// initial load) doesn't redraw the list for each one.
+const MAX_MESSAGE_BATCH: usize = 1000;
+/// How deeply macros can replay other macros (or themselves) before giving up.
+const MAX_REPLAY_DEPTH: usize = 100;
+/// The registers macros can be recorded into.
+const MACRO_REGISTERS: std::ops::RangeInclusive<char> = 'a'..='z';
 
-impl Default for State {
-    fn default() -> Self {
-        Self {
+impl State {
+    fn new(
+        config: Arc<Config>,
+        background_toasts: mpsc::UnboundedSender<Toast>,
+        sent_drafts: mpsc::UnboundedSender<SentDraft>,
+        outbox: Outbox,
+        user: User,
+        key_bindings: KeyBindings,
+    ) -> Self {
+        let main_keys = key_bindings.main().unwrap_or_else(|| {
+            [
+                ("q", MainEvent::Quit),
+                ("k", MainEvent::SelectPrev),
+                ("j", MainEvent::SelectNext),
+                ("gg", MainEvent::SelectFirst),
+                ("G", MainEvent::SelectLast),
+                ("<C-d>", MainEvent::HalfPageDown),
+                ("<C-u>", MainEvent::HalfPageUp),
+                ("<C-f>", MainEvent::PageDown),
+                ("<C-b>", MainEvent::PageUp),
+                ("dd", MainEvent::DeleteSelected),
+                ("u", MainEvent::Undo),
+                ("]a", MainEvent::NextFromSender),
+                ("[a", MainEvent::PrevFromSender),
+                ("i", MainEvent::Compose),
+                (":", MainEvent::EnterCommand),
+                ("/", MainEvent::Search),
+                ("n", MainEvent::NextMatch),
+                ("N", MainEvent::PrevMatch),
+                ("zs", MainEvent::ToggleCollapseSystem),
+                ("zg", MainEvent::ToggleGrouping),
+                ("zc", MainEvent::ToggleCollapseRoom),
+                ("za", MainEvent::ToggleFold),
+                ("yy", MainEvent::YankSelected),
+                ("yt", MainEvent::YankTimestamp),
+                ("gs", MainEvent::SaveAttachment),
+                ("gf", MainEvent::Forward),
+                ("<Tab>", MainEvent::ToggleRoomFocus),
+                ("<Esc>", MainEvent::ClearFilters),
+                ("mr", MainEvent::MarkRoomRead),
+                ("zw", MainEvent::ToggleReadingMode),
+                ("zd", MainEvent::ToggleDetails),
+                ("zz", MainEvent::CenterSelected),
+                ("gp", MainEvent::ReplyParent),
+                ("gn", MainEvent::NextReply),
+            ]
+            .into_iter()
+            .map(|(s, a)| (keymap::parse_key_sequence(s).unwrap(), (a, false)))
+            .collect()
+        });
+        let mut state = Self {
             stopped: false,
+            user,
+            config: config.clone(),
+            capabilities: Capabilities::detect(),
             messages: Default::default(),
             main_keys: Keymap {
-                keys: [
-                    ("q", MainEvent::Quit),
-                    ("j", MainEvent::SelectPrev),
-                    ("k", MainEvent::SelectNext),
-                    ("gg", MainEvent::SelectFirst),
-                    ("G", MainEvent::SelectLast),
-                    ("dd", MainEvent::DeleteSelected),
-                ]
-                .into_iter()
-                .map(|(s, a)| (keymap::parse_key_sequence(s).unwrap(), a))
-                .collect(),
+                keys: main_keys
+                    .into_iter()
+                    .chain(MACRO_REGISTERS.flat_map(|register| {
+                        [
+                            ('q', MainEvent::Record(register)),
+                            ('@', MainEvent::Replay(register)),
+                        ]
+                        .map(|(prefix, action)| {
+                            let keys = [prefix, register].map(|c| KeyCode::Char(c).into());
+                            (keys.into(), (action, false))
+                        })
+                    }))
+                    .collect(),
+                timeout: DEFAULT_KEY_TIMEOUT,
+                counts: true,
+            },
+            insert_keys: insert_keymap(config.submit_key, &key_bindings),
+            command_keys: Keymap {
+                keys: key_bindings.command().unwrap_or_else(|| {
+                    [
+                        ("<Esc>", CommandEvent::Cancel),
+                        ("<CR>", CommandEvent::Execute),
+                    ]
+                    .into_iter()
+                    .map(|(s, a)| (keymap::parse_key_sequence(s).unwrap(), (a, false)))
+                    .collect()
+                }),
                 timeout: DEFAULT_KEY_TIMEOUT,
+                counts: false,
             },
+            search_keys: Keymap {
+                keys: key_bindings.search().unwrap_or_else(|| {
+                    [
+                        ("<Esc>", SearchEvent::Cancel),
+                        ("<CR>", SearchEvent::Confirm),
+                    ]
+                    .into_iter()
+                    .map(|(s, a)| (keymap::parse_key_sequence(s).unwrap(), (a, false)))
+                    .collect()
+                }),
+                timeout: DEFAULT_KEY_TIMEOUT,
+                counts: false,
+            },
+            key_bindings,
+            key_handler: Default::default(),
             mode: Mode::Main,
+            confirmation: None,
+            draft: Default::default(),
+            command_line: Default::default(),
+            search: Default::default(),
+            pre_search: None,
+            search_failed: false,
+            last_input: Instant::now(),
+            idle_follow: false,
+            toast: None,
+            background_toasts,
+            sent_drafts,
+            flash: false,
+            bell_output: BellOutput::default(),
+            unlocked_mode: Mode::Main,
+            passphrase: Default::default(),
+            room_notify: BTreeMap::new(),
+            show_details: false,
+            outbox,
+            forward: None,
+            macros: BTreeMap::new(),
+            recording: None,
+            replay_depth: 0,
+            announce_tx: None,
+        };
+        state.messages.capabilities = state.capabilities;
+        state.messages.own_user = Some(state.user.identifier.clone());
+        state.set_config(config);
+        state
+    }
+
+    /// Applies a new config. Settings which only affect new messages (such as the ordering) don't
+    /// change existing ones.
+    fn set_config(&mut self, config: Arc<Config>) {
+        self.messages.room_chips = config.room_chips;
+        self.messages.sender_avatars = config.sender_avatars;
+        self.messages.age_fade = config.age_fade;
+        self.messages.order = config.message_order;
+        self.messages.sender_display = config.sender_display;
+        self.messages.reaction_display = config.reaction_display;
+        self.messages.timestamp_format = config.timestamp_format;
+        self.messages.reply_tree = config.reply_tree;
+        self.messages.read_policy = config.mark_read;
+        self.messages.boundary = config.boundary_behavior;
+        self.messages.date_separators = config.date_separators;
+        self.messages.reading_width = config.reading_width;
+        self.messages.compact_below = config.compact_below.map(usize::from);
+        self.messages.invalidate();
+        if config.mouse != self.config.mouse {
+            set_mouse_capture(config.mouse);
+        }
+        if config.submit_key != self.config.submit_key {
+            self.insert_keys = insert_keymap(config.submit_key, &self.key_bindings);
         }
+        // levels set with `:notify` are kept, unless the config overrides them
+        self.room_notify.extend(
+            config
+                .room_notify
+                .iter()
+                .map(|(room, level)| (room.as_str().into(), *level)),
+        );
+        self.config = config;
+    }
+}
+
+/// The insert mode keymap, which is the default one for the submit key unless the insert mode
+/// bindings are given.
+fn insert_keymap(submit_key: SubmitKey, key_bindings: &KeyBindings) -> Keymap<InsertEvent> {
+    let (submit, newline) = match submit_key {
+        SubmitKey::Enter => ("<CR>", "<S-CR>"),
+        SubmitKey::ShiftEnter => ("<S-CR>", "<CR>"),
+    };
+    Keymap {
+        keys: key_bindings.insert().unwrap_or_else(|| {
+            [
+                ("<Esc>", InsertEvent::Leave),
+                (submit, InsertEvent::Submit),
+                (newline, InsertEvent::Newline),
+            ]
+            .into_iter()
+            .map(|(s, a)| (keymap::parse_key_sequence(s).unwrap(), (a, false)))
+            .collect()
+        }),
+        timeout: DEFAULT_KEY_TIMEOUT,
+        counts: false,
     }
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+/// The modes which can be entered with an `enter-mode` binding are named as in the status line.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
 enum Mode {
     /// Main view, with the message list selected
     #[default]
+    #[serde(rename = "normal")]
     Main,
+    /// Waiting for the user to confirm or cancel an action
+    #[serde(skip)]
+    Confirm,
+    /// Composing a message
+    Insert,
+    /// Entering a command
+    Command,
+    /// Entering a search, which selects matching messages as it is typed
+    Search,
+    /// Messages are hidden until the user unlocks the screen
+    #[serde(skip)]
+    Locked,
+    /// Choosing a room to forward a message to
+    #[serde(skip)]
+    Forward,
 }
 
-#[derive(Debug, Clone)]
+impl Mode {
+    /// The command which enters the mode from a key binding.
+    fn enter_command(self) -> AppCommand {
+        match self {
+            Self::Search => AppCommand::StartSearch,
+            mode => AppCommand::SetMode(mode),
+        }
+    }
+
+    /// The name shown in the status line.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Main => "NORMAL",
+            Self::Confirm => "CONFIRM",
+            Self::Insert => "INSERT",
+            Self::Command => "COMMAND",
+            Self::Search => "SEARCH",
+            Self::Locked => "LOCKED",
+            Self::Forward => "FORWARD",
+        }
+    }
+
+    fn style(self) -> Style {
+        let color = match self {
+            Self::Main => Color::Blue,
+            Self::Confirm => Color::Red,
+            Self::Insert => Color::Green,
+            Self::Command => Color::Yellow,
+            Self::Search => Color::Magenta,
+            Self::Locked => Color::Gray,
+            Self::Forward => Color::Cyan,
+        };
+        Style::new().black().bg(color).bold()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum MainEvent {
     Quit,
     SelectPrev,
     SelectNext,
     SelectFirst,
     SelectLast,
+    HalfPageDown,
+    HalfPageUp,
+    PageDown,
+    PageUp,
     DeleteSelected,
+    Undo,
+    NextFromSender,
+    PrevFromSender,
+    ReplyParent,
+    NextReply,
+    Compose,
+    EnterCommand,
+    Search,
+    NextMatch,
+    PrevMatch,
+    ToggleCollapseSystem,
+    ToggleGrouping,
+    ToggleCollapseRoom,
+    ToggleFold,
+    ToggleReadingMode,
+    ToggleDetails,
+    CenterSelected,
+    YankSelected,
+    YankTimestamp,
+    SaveAttachment,
+    Forward,
+    ToggleRoomFocus,
+    ClearFilters,
+    MarkRoomRead,
+    EnterMode(Mode),
+    /// Starts recording keys into a register
+    #[serde(skip)]
+    Record(char),
+    /// Replays the keys recorded in a register
+    #[serde(skip)]
+    Replay(char),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum InsertEvent {
+    Leave,
+    Submit,
+    Newline,
+    EnterMode(Mode),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum CommandEvent {
+    Cancel,
+    Execute,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SearchEvent {
+    Cancel,
+    Confirm,
+}
+
+/// Everything the user can do, however it was input. Key bindings and commands are translated into
+/// these, and [`State::dispatch`] carries them out.
+#[derive(Debug, Clone)]
+enum AppCommand {
+    Quit,
+    SelectPrev(usize),
+    SelectNext(usize),
+    SelectFirst,
+    SelectLast,
+    /// Selects the message with the identifier, and briefly highlights it. If there is no such
+    /// message and the identifier is a number, selects the message with that (1-based) position
+    /// instead.
+    Goto(String),
+    /// Scrolls the list and the selection by a page (or half a page), `count` times
+    ScrollPage {
+        forward: bool,
+        half: bool,
+        count: usize,
+    },
+    /// Moves to another message from the selected message's sender, `count` times
+    SelectSameSender {
+        forward: bool,
+        count: usize,
+    },
+    /// Selects the message the selected message replies to
+    SelectReplyParent,
+    /// Cycles through the replies to a message, starting from the message itself
+    SelectNextReply,
+    /// Deletes the given number of messages, starting with the selected one
+    DeleteSelected(usize),
+    /// Restores the given number of deleted messages, most recent first
+    Undo(usize),
+    /// Switches to another mode, discarding any unfinished input of the current one except for
+    /// the draft
+    SetMode(Mode),
+    /// Enters search mode, remembering the selection to return to if the search is cancelled
+    StartSearch,
+    /// Selects the first message matching the query, after where the search started (or the
+    /// selection, outside of search mode)
+    Search(String),
+    /// Moves to another match of the last search, `count` times
+    SelectMatch {
+        forward: bool,
+        count: usize,
+    },
+    /// Leaves search mode, returning to where the search started unless it is confirmed
+    EndSearch {
+        confirm: bool,
+    },
+    ToggleCollapseSystem,
+    ToggleGrouping,
+    /// Collapses or expands the selected message's room, when grouping by room
+    ToggleCollapseRoom,
+    ToggleFold,
+    ToggleReadingMode,
+    /// Shows or hides the footer with the selected message's details
+    ToggleDetails,
+    /// Scrolls the selected message to the middle of the screen
+    CenterSelected,
+    ToggleRoomFocus,
+    /// Shows all the messages again, keeping the selection, and clears the search highlight
+    ClearFilters,
+    /// Marks all the messages in the selected message's room as read
+    MarkRoomRead,
+    /// Records the following keys into a register, until `q` is pressed in the main view
+    StartRecording(char),
+    /// Replays the keys recorded in a register, `count` times
+    ReplayMacro {
+        register: char,
+        count: usize,
+    },
+    YankSelected,
+    YankTimestamp,
+    YankAll,
+    SaveAttachment,
+    /// Chooses a room to forward the selected message to
+    ForwardSelected,
+    /// Sets the notification level for the selected message's room
+    SetRoomNotify(NotifyLevel),
+    ShowStats,
+    ExportCsv(PathBuf),
+    SubmitDraft,
+    /// Toggles a reaction to the selected message
+    React(Arc<str>),
+}
+
+impl MainEvent {
+    fn command(self, count: usize) -> AppCommand {
+        match self {
+            Self::Quit => AppCommand::Quit,
+            Self::SelectPrev => AppCommand::SelectPrev(count),
+            Self::SelectNext => AppCommand::SelectNext(count),
+            Self::SelectFirst => AppCommand::SelectFirst,
+            Self::SelectLast => AppCommand::SelectLast,
+            Self::HalfPageDown => AppCommand::ScrollPage {
+                forward: true,
+                half: true,
+                count,
+            },
+            Self::HalfPageUp => AppCommand::ScrollPage {
+                forward: false,
+                half: true,
+                count,
+            },
+            Self::PageDown => AppCommand::ScrollPage {
+                forward: true,
+                half: false,
+                count,
+            },
+            Self::PageUp => AppCommand::ScrollPage {
+                forward: false,
+                half: false,
+                count,
+            },
+            Self::DeleteSelected => AppCommand::DeleteSelected(count),
+            Self::Undo => AppCommand::Undo(count),
+            Self::NextFromSender => AppCommand::SelectSameSender {
+                forward: true,
+                count,
+            },
+            Self::PrevFromSender => AppCommand::SelectSameSender {
+                forward: false,
+                count,
+            },
+            Self::ReplyParent => AppCommand::SelectReplyParent,
+            Self::NextReply => AppCommand::SelectNextReply,
+            Self::Compose => AppCommand::SetMode(Mode::Insert),
+            Self::EnterCommand => AppCommand::SetMode(Mode::Command),
+            Self::EnterMode(mode) => mode.enter_command(),
+            Self::Search => AppCommand::StartSearch,
+            Self::NextMatch => AppCommand::SelectMatch {
+                forward: true,
+                count,
+            },
+            Self::PrevMatch => AppCommand::SelectMatch {
+                forward: false,
+                count,
+            },
+            Self::ToggleCollapseSystem => AppCommand::ToggleCollapseSystem,
+            Self::ToggleGrouping => AppCommand::ToggleGrouping,
+            Self::ToggleCollapseRoom => AppCommand::ToggleCollapseRoom,
+            Self::ToggleFold => AppCommand::ToggleFold,
+            Self::ToggleReadingMode => AppCommand::ToggleReadingMode,
+            Self::ToggleDetails => AppCommand::ToggleDetails,
+            Self::CenterSelected => AppCommand::CenterSelected,
+            Self::YankSelected => AppCommand::YankSelected,
+            Self::YankTimestamp => AppCommand::YankTimestamp,
+            Self::SaveAttachment => AppCommand::SaveAttachment,
+            Self::Forward => AppCommand::ForwardSelected,
+            Self::ToggleRoomFocus => AppCommand::ToggleRoomFocus,
+            Self::ClearFilters => AppCommand::ClearFilters,
+            Self::MarkRoomRead => AppCommand::MarkRoomRead,
+            Self::Record(register) => AppCommand::StartRecording(register),
+            Self::Replay(register) => AppCommand::ReplayMacro { register, count },
+        }
+    }
+}
+
+impl From<Command> for AppCommand {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Quit => Self::Quit,
+            Command::Delete(count) => Self::DeleteSelected(count),
+            Command::Goto(target) => Self::Goto(target),
+            Command::ExportCsv(path) => Self::ExportCsv(path),
+            Command::Notify(level) => Self::SetRoomNotify(level),
+            Command::Stats => Self::ShowStats,
+            Command::YankAll => Self::YankAll,
+            Command::React(emoji) => Self::React(emoji.into()),
+        }
+    }
+}
+
+/// What happened to a draft which was sent in the background.
+#[derive(Debug)]
+enum SentDraft {
+    /// It was sent, as this message
+    Sent(Box<Message>),
+    /// It failed to send, but should be kept
+    Failed(String),
+}
+
+/// An action which is waiting on confirmation from the user.
+#[derive(Debug)]
+struct Confirmation {
+    prompt: String,
+    command: AppCommand,
 }
 
 impl State {
     fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Key(event) if event.kind != KeyEventKind::Release => {
+                let key = KeyEvent::from(event);
+                if key.code == KeyCode::Unknown {
+                    tracing::debug!("unknown key: {event:?}");
+                }
+                self.handle_key_event(key)
+            }
+            // the list is laid out again for the new size in the next draw, which scrolls it to
+            // keep the selected message in view
+            Event::Resize(..) => self.messages.invalidate(),
+            Event::Mouse(event) => self.handle_mouse_event(event),
+            _ => tracing::debug!("{event:?}"),
+        }
+    }
+
+    /// Clicking a message selects it, and scrolling moves the selection, in the main view.
+    fn handle_mouse_event(&mut self, event: MouseEvent) {
+        if self.mode != Mode::Main {
+            return;
+        }
+        // just moving the mouse doesn't count as input
+        match event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                self.note_input();
+                if let Some(key) = self.messages.key_at(event.column, event.row) {
+                    self.messages.select_key(&key);
+                }
+            }
+            MouseEventKind::ScrollDown => {
+                self.note_input();
+                self.messages.select_next();
+            }
+            MouseEventKind::ScrollUp => {
+                self.note_input();
+                self.messages.select_prev();
+            }
+            _ => {}
+        }
+    }
+
+    /// Stops following new messages after being idle, and delays doing so again.
+    fn note_input(&mut self) {
+        self.last_input = Instant::now();
+        if self.idle_follow {
+            self.idle_follow = false;
+            self.messages.set_follow(false);
+        }
+    }
+
+    fn handle_key_event(&mut self, event: KeyEvent) {
+        if self.replay_depth == 0 {
+            if let Some((register, keys)) = &mut self.recording {
+                // `q` stops recording, unless it is part of a key sequence
+                if self.mode == Mode::Main
+                    && event == KeyEvent::from(KeyCode::Char('q'))
+                    && self.key_handler.deadline().is_none()
+                {
+                    let register = *register;
+                    let keys = std::mem::take(keys);
+                    self.recording = None;
+                    self.macros.insert(register, keys);
+                    self.show_toast(Toast::info(format!("recorded @{register}")));
+                    return;
+                }
+                keys.push(event);
+            }
+        }
+        self.note_input();
         match self.mode {
-            Mode::Main => self.handle_main_event(event),
+            Mode::Main => {
+                let (passthru, actions) = self.key_handler.next(&self.main_keys, event);
+                if !passthru.is_empty() {
+                    tracing::debug!("unmapped keys: {passthru:?}");
+                    self.ring_bell();
+                }
+                for (action, count) in actions {
+                    self.handle_main_event(action, count.unwrap_or(1));
+                }
+            }
+            Mode::Confirm => self.handle_confirm_key(event),
+            Mode::Insert => {
+                let (passthru, actions) = self.key_handler.next(&self.insert_keys, event);
+                self.insert_keys_passthru(&passthru);
+                for (action, _) in actions {
+                    self.handle_insert_event(action);
+                }
+            }
+            Mode::Command => {
+                let (passthru, actions) = self.key_handler.next(&self.command_keys, event);
+                self.command_keys_passthru(&passthru);
+                for (action, _) in actions {
+                    self.handle_command_event(action);
+                }
+            }
+            Mode::Search => {
+                let (passthru, actions) = self.key_handler.next(&self.search_keys, event);
+                self.search_keys_passthru(&passthru);
+                for (action, _) in actions {
+                    self.handle_search_event(action);
+                }
+            }
+            Mode::Locked => self.handle_locked_key(event),
+            Mode::Forward => self.handle_forward_key(event),
         }
+        self.messages.update_read_markers();
     }
 
-    fn handle_main_event(&mut self, event: Event) {
-        // TODO: configuration
-        // TODO: sequences
-        match event {
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('q'),
-                ..
-            }) => self.stopped = true,
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('j'),
-                ..
-            }) => self.messages.select_next(),
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('k'),
-                ..
-            }) => self.messages.select_prev(),
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('G'),
-                ..
-            }) => self.messages.select_last(),
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('g'),
-                ..
-            }) => self.messages.select_first(),
-            Event::Key(KeyEvent {
-                code: KeyCode::Char('d'),
-                ..
-            }) => self.messages.delete_selected(),
-            _ => tracing::debug!("{event:?}"),
+    fn handle_key_timeout(&mut self) {
+        match self.mode {
+            Mode::Main => {
+                let (passthru, action) = self.key_handler.timeout(&self.main_keys);
+                tracing::debug!("key sequence timed out: {passthru:?}");
+                if let Some((action, count)) = action {
+                    self.handle_main_event(action, count.unwrap_or(1));
+                }
+            }
+            Mode::Insert => {
+                let (passthru, action) = self.key_handler.timeout(&self.insert_keys);
+                self.insert_keys_passthru(&passthru);
+                if let Some((action, _)) = action {
+                    self.handle_insert_event(action);
+                }
+            }
+            Mode::Command => {
+                let (passthru, action) = self.key_handler.timeout(&self.command_keys);
+                self.command_keys_passthru(&passthru);
+                if let Some((action, _)) = action {
+                    self.handle_command_event(action);
+                }
+            }
+            Mode::Search => {
+                let (passthru, action) = self.key_handler.timeout(&self.search_keys);
+                self.search_keys_passthru(&passthru);
+                if let Some((action, _)) = action {
+                    self.handle_search_event(action);
+                }
+            }
+            // these modes don't use key sequences
+            Mode::Confirm | Mode::Locked | Mode::Forward => self.key_handler = Default::default(),
+        }
+    }
+
+    fn handle_main_event(&mut self, action: MainEvent, count: usize) {
+        self.dispatch_key_command(action.command(count));
+    }
+
+    /// Carries out a command from a key binding. Errors are shown, except that a missing selection
+    /// just rings the bell.
+    fn dispatch_key_command(&mut self, command: AppCommand) {
+        let result = self.dispatch(command);
+        self.show_key_command_result(result);
+    }
+
+    fn show_key_command_result(&mut self, result: Result<(), CommandError>) {
+        match result {
+            Ok(()) => {}
+            Err(CommandError::NoSelection) => self.ring_bell(),
+            Err(err) => self.show_toast(Toast::error(err.to_string())),
+        }
+    }
+
+    /// Carries out a command, whichever way it was input. A command which needs confirmation is
+    /// instead held until the user answers the prompt.
+    fn dispatch(&mut self, command: AppCommand) -> Result<(), CommandError> {
+        match command {
+            AppCommand::DeleteSelected(count) if count > self.config.bulk_delete_threshold => {
+                self.confirmation = Some(Confirmation {
+                    prompt: format!("Delete {count} messages? (y/n)"),
+                    command,
+                });
+                self.mode = Mode::Confirm;
+                Ok(())
+            }
+            command => self.execute(command),
+        }
+    }
+
+    /// Carries out a command, without asking for confirmation.
+    fn execute(&mut self, command: AppCommand) -> Result<(), CommandError> {
+        match command {
+            AppCommand::Quit => self.stopped = true,
+            AppCommand::SelectPrev(count) => {
+                let moved = (0..count).all(|_| self.messages.select_prev());
+                self.handle_boundary(moved);
+            }
+            AppCommand::SelectNext(count) => {
+                let moved = (0..count).all(|_| self.messages.select_next());
+                self.handle_boundary(moved);
+            }
+            AppCommand::SelectFirst => self.messages.select_first(),
+            AppCommand::SelectLast => self.messages.select_last(),
+            AppCommand::ScrollPage {
+                forward,
+                half,
+                count,
+            } => {
+                let moved = (0..count).all(|_| self.messages.scroll_page(forward, half));
+                self.handle_boundary(moved);
+            }
+            AppCommand::Goto(target) => {
+                if self.messages.goto(&target) {
+                    return Ok(());
+                }
+                match target.parse() {
+                    Ok(position) if self.messages.is_shown(&target).is_none() => {
+                        let moved = self.messages.select_nth(position);
+                        self.handle_boundary(moved);
+                    }
+                    _ => return Err(CommandError::NoSuchMessage(target)),
+                }
+            }
+            AppCommand::SelectSameSender { forward, count } => {
+                let mut moved = true;
+                for _ in 0..count {
+                    if !self.messages.select_same_sender(forward) {
+                        moved = false;
+                        break;
+                    }
+                }
+                self.handle_boundary(moved);
+            }
+            AppCommand::SelectReplyParent => {
+                let selected = self.messages.selected().ok_or(CommandError::NoSelection)?;
+                if selected.reply_to.is_none() {
+                    self.show_toast(Toast::info("not a reply"));
+                    return Ok(());
+                }
+                match self.messages.reply_parent(selected).cloned() {
+                    Some(parent) => {
+                        if !self.messages.goto_key(&parent) {
+                            self.show_toast(Toast::info("the message it replies to isn't visible"))
+                        }
+                    }
+                    // the backends can't be asked for older messages, so it can't be loaded
+                    None => self.show_toast(Toast::info("the message it replies to isn't loaded")),
+                }
+            }
+            AppCommand::SelectNextReply => {
+                if self.messages.selected().is_none() {
+                    return Err(CommandError::NoSelection);
+                }
+                if !self.messages.select_next_reply() {
+                    self.show_toast(Toast::info("no replies"));
+                }
+            }
+            AppCommand::DeleteSelected(count) => {
+                if self.messages.selected().is_none() {
+                    return Err(CommandError::NoSelection);
+                }
+                (0..count).for_each(|_| self.messages.delete_selected());
+            }
+            AppCommand::Undo(count) => {
+                if !self.messages.undo() {
+                    return Err(CommandError::NothingToUndo);
+                }
+                (1..count).for_each(|_| {
+                    self.messages.undo();
+                });
+            }
+            AppCommand::SetMode(mode) => self.set_mode(mode),
+            AppCommand::StartSearch => {
+                self.set_mode(Mode::Search);
+                self.pre_search = self.messages.selected().map(Message::key);
+            }
+            AppCommand::Search(query) => {
+                let searching = self.mode == Mode::Search;
+                let from = if searching {
+                    self.pre_search.clone()
+                } else {
+                    self.messages.selected().map(Message::key)
+                };
+                self.messages.set_search(Some(&query));
+                let found = !query.is_empty() && self.messages.select_match(from.as_ref(), &query);
+                self.search_failed = !query.is_empty() && !found;
+                // without a match, go back to where the search started
+                if !found {
+                    if searching {
+                        self.messages.select(from.as_ref());
+                    } else {
+                        self.ring_bell();
+                    }
+                }
+            }
+            AppCommand::EndSearch { confirm } => {
+                self.mode = Mode::Main;
+                self.search.clear();
+                self.search_failed = false;
+                let pre_search = self.pre_search.take();
+                if confirm {
+                    self.messages.spotlight_selected();
+                } else {
+                    self.messages.set_search(None);
+                    self.messages.select(pre_search.as_ref());
+                }
+            }
+            AppCommand::SelectMatch { forward, count } => {
+                let query = self
+                    .messages
+                    .search()
+                    .ok_or(CommandError::NoSearch)?
+                    .to_owned();
+                let mut wrapped = false;
+                for _ in 0..count {
+                    wrapped |= self
+                        .messages
+                        .select_next_match(forward)
+                        .ok_or(CommandError::NoMatches(query.clone()))?;
+                }
+                if wrapped {
+                    self.show_toast(Toast::info(if forward {
+                        "search hit the bottom, continuing at the top"
+                    } else {
+                        "search hit the top, continuing at the bottom"
+                    }));
+                }
+            }
+            AppCommand::ToggleCollapseSystem => self.messages.toggle_collapse_system(),
+            AppCommand::ToggleGrouping => self.messages.toggle_grouping(),
+            AppCommand::ToggleCollapseRoom => {
+                if self.messages.selected().is_none() {
+                    return Err(CommandError::NoSelection);
+                }
+                self.messages.toggle_collapse_room();
+            }
+            AppCommand::ToggleFold => self.messages.toggle_fold_selected(),
+            AppCommand::ToggleReadingMode => self.messages.toggle_reading_mode(),
+            AppCommand::ToggleDetails => self.show_details = !self.show_details,
+            AppCommand::CenterSelected => self.messages.center_selected(),
+            AppCommand::ToggleRoomFocus => {
+                if self.messages.room_filter().is_some() {
+                    self.messages.set_room_filter(None);
+                } else {
+                    let selected = self.messages.selected().ok_or(CommandError::NoSelection)?;
+                    let room = selected.room.identifier.clone();
+                    self.messages.set_room_filter(Some(room));
+                }
+            }
+            AppCommand::ClearFilters => self.messages.clear_filters(),
+            AppCommand::StartRecording(register) => {
+                self.recording = Some((register, Vec::new()));
+                self.show_toast(Toast::info(format!("recording @{register}")));
+            }
+            AppCommand::ReplayMacro { register, count } => {
+                let keys = self
+                    .macros
+                    .get(&register)
+                    .ok_or(CommandError::EmptyRegister(register))?
+                    .clone();
+                if self.replay_depth >= MAX_REPLAY_DEPTH {
+                    return Err(CommandError::MacroTooDeep);
+                }
+                self.replay_depth += 1;
+                for _ in 0..count {
+                    for &key in &keys {
+                        self.handle_key_event(key);
+                    }
+                }
+                self.replay_depth -= 1;
+            }
+            AppCommand::MarkRoomRead => {
+                if !self.messages.mark_selected_room_read() {
+                    return Err(CommandError::NoSelection);
+                }
+            }
+            AppCommand::YankSelected => {
+                let text = self
+                    .messages
+                    .selected_plain_text()
+                    .ok_or(CommandError::NoSelection)?;
+                if self.yank(&text) {
+                    self.show_toast(Toast::info("copied message"));
+                }
+            }
+            AppCommand::YankTimestamp => {
+                let selected = self.messages.selected().ok_or(CommandError::NoSelection)?;
+                let timestamp = selected.key.timestamp.to_rfc3339();
+                if self.yank(&timestamp) {
+                    self.show_toast(Toast::info(format!("copied {timestamp}")));
+                }
+            }
+            AppCommand::YankAll => {
+                let total = self.messages.messages().count();
+                let (text, count) = self.messages.plain_text(MAX_YANK_MESSAGES);
+                if self.yank(&text) {
+                    self.show_toast(if count < total {
+                        Toast::warning(format!("copied only the last {count} of {total} messages"))
+                    } else {
+                        Toast::info(format!("copied {count} messages"))
+                    });
+                }
+            }
+            AppCommand::SaveAttachment => self.save_selected_attachment()?,
+            AppCommand::ForwardSelected => {
+                let Some(message) = self.messages.selected().cloned() else {
+                    return Err(CommandError::NoSelection);
+                };
+                let rooms = self
+                    .messages
+                    .room_summaries()
+                    .into_iter()
+                    .map(|(room, _)| room)
+                    .filter(|room| room.identifier != message.room.identifier)
+                    .collect();
+                match RoomPicker::new(rooms) {
+                    Some(picker) => {
+                        self.forward = Some((message, picker));
+                        self.mode = Mode::Forward;
+                    }
+                    None => self.show_toast(Toast::info("no other rooms to forward to")),
+                }
+            }
+            AppCommand::SetRoomNotify(level) => {
+                let room = self
+                    .messages
+                    .selected()
+                    .ok_or(CommandError::NoSelection)?
+                    .room
+                    .identifier
+                    .clone();
+                self.room_notify.insert(room, level);
+            }
+            AppCommand::React(emoji) => {
+                let key = self
+                    .messages
+                    .selected()
+                    .ok_or(CommandError::NoSelection)?
+                    .key();
+                self.messages.react(&key, emoji, self.user.clone());
+            }
+            AppCommand::ShowStats => {
+                self.show_toast(Toast::info(self.messages.stats().to_string()))
+            }
+            AppCommand::ExportCsv(path) => {
+                let result = std::fs::File::create(&path)
+                    .map_err(csv::Error::from)
+                    .and_then(|file| export::write_csv(self.messages.messages(), file));
+                if let Err(err) = result {
+                    return Err(CommandError::Write {
+                        path,
+                        error: err.to_string(),
+                    });
+                }
+                self.show_toast(Toast::info(format!("exported to {}", path.display())));
+            }
+            AppCommand::SubmitDraft => self.submit_draft()?,
+        }
+        Ok(())
+    }
+
+    /// Saves the selected message's attachment in the background, showing a toast when done.
+    fn save_selected_attachment(&mut self) -> Result<(), CommandError> {
+        let Some(attachment) = self
+            .messages
+            .selected()
+            .and_then(|message| message.body.attachment())
+            .cloned()
+        else {
+            return Err(CommandError::NoSelection);
+        };
+        let dir = self.config.download_dir.clone().unwrap_or_default();
+        let toasts = self.background_toasts.clone();
+        tokio::spawn(async move {
+            // the backends don't fetch attachments themselves, so only `file://` URLs can be saved
+            let toast = match attachment::save_attachment(&FileUrlResolver, &attachment, &dir).await
+            {
+                Ok(path) => Toast::info(format!("saved {}", path.display())),
+                Err(err) => Toast::error(err.to_string()),
+            };
+            // if the TUI has stopped, there's nowhere to show the result
+            let _ = toasts.send(toast);
+        });
+        Ok(())
+    }
+
+    /// Switches modes, clearing the command line or search being entered and any half-typed key
+    /// sequence. The draft is discarded too, unless the config keeps it so that composing can be
+    /// resumed.
+    fn set_mode(&mut self, mode: Mode) {
+        match self.mode {
+            _ if mode == self.mode => {}
+            Mode::Insert if !self.config.keep_draft => self.draft.clear(),
+            Mode::Command => self.command_line.clear(),
+            Mode::Search => self.search.clear(),
+            _ => {}
+        }
+        self.key_handler = Default::default();
+        self.mode = mode;
+    }
+
+    fn handle_command_event(&mut self, action: CommandEvent) {
+        self.mode = Mode::Main;
+        let command_line = self.command_line.take();
+        match action {
+            CommandEvent::Cancel => {}
+            CommandEvent::Execute => {
+                if let Err(err) = self.execute_command_line(&command_line) {
+                    self.show_toast(Toast::error(err.to_string()));
+                }
+            }
+        }
+    }
+
+    /// Types the keys which aren't mapped in command mode into the command line.
+    fn command_keys_passthru(&mut self, keys: &[KeyEvent]) {
+        for key in keys {
+            if key.code == KeyCode::Backspace && self.command_line.is_empty() {
+                // backspacing past the start of the command line leaves command mode
+                self.mode = Mode::Main;
+                return;
+            }
+            if !self.command_line.type_key(key) {
+                tracing::debug!("unmapped key in command mode: {key:?}");
+            }
+        }
+    }
+
+    fn handle_search_event(&mut self, action: SearchEvent) {
+        let confirm = match action {
+            SearchEvent::Cancel => false,
+            SearchEvent::Confirm => true,
+        };
+        self.dispatch_key_command(AppCommand::EndSearch { confirm });
+    }
+
+    /// Types the keys which aren't mapped in search mode into the search, and selects the first
+    /// match.
+    fn search_keys_passthru(&mut self, keys: &[KeyEvent]) {
+        for key in keys {
+            if key.code == KeyCode::Backspace && self.search.is_empty() {
+                // backspacing past the start of the search cancels it
+                self.handle_search_event(SearchEvent::Cancel);
+                return;
+            }
+            if !self.search.type_key(key) {
+                tracing::debug!("unmapped key in search mode: {key:?}");
+            }
+        }
+        if !keys.is_empty() {
+            self.dispatch_key_command(AppCommand::Search(self.search.text().into()));
+        }
+    }
+
+    /// Executes each command in order, stopping at the first error. A command which needs
+    /// confirmation also stops it, since the rest of the line may depend on the answer.
+    fn execute_command_line(&mut self, command_line: &str) -> Result<(), CommandLineError> {
+        for (index, command) in command::parse_command_line(command_line)? {
+            self.dispatch(command.into())
+                .map_err(|error| CommandLineError { index, error })?;
+            if self.mode == Mode::Confirm {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_insert_event(&mut self, action: InsertEvent) {
+        match action {
+            InsertEvent::Leave => self.dispatch_key_command(AppCommand::SetMode(Mode::Main)),
+            InsertEvent::Submit => self.dispatch_key_command(AppCommand::SubmitDraft),
+            InsertEvent::Newline => self.draft.insert('\n'),
+            InsertEvent::EnterMode(mode) => self.dispatch_key_command(mode.enter_command()),
+        }
+    }
+
+    /// Types the keys which aren't mapped in insert mode into the draft.
+    fn insert_keys_passthru(&mut self, keys: &[KeyEvent]) {
+        for key in keys {
+            if key.code == KeyCode::Unknown {
+                // already logged when it was received
+                continue;
+            }
+            if !self.draft.type_key(key) {
+                tracing::debug!("unmapped key in insert mode: {key:?}");
+            }
+        }
+    }
+
+    /// Sends the draft in the background to the room of the selected message, which is the
+    /// focused room if there is one. The draft is cleared right away, and put back if sending
+    /// fails in a way which [keeps the draft](carrier_pigeon_common::SendError::keeps_draft).
+    /// Once it is sent, it is shown as a message from the user.
+    fn submit_draft(&mut self) -> Result<(), CommandError> {
+        if self.draft.is_empty() {
+            return Ok(());
+        }
+        let room = self.compose_room().ok_or(CommandError::NoSelection)?;
+        if let Err(err) = self
+            .outbox
+            .capabilities_for(&room)
+            .check_length(self.draft.text())
+        {
+            self.show_toast(Toast::from(&err));
+            return Ok(());
+        }
+        let text = self.draft.take();
+        let outbox = self.outbox.clone();
+        let toasts = self.background_toasts.clone();
+        let sent_drafts = self.sent_drafts.clone();
+        let sender = self.user.clone();
+        tokio::spawn(async move {
+            let body = MessageBody::Text(RichText::from_plain(text.as_str()));
+            // if the TUI has stopped, there's nowhere to show the result
+            match outbox.send(&room, body.clone()).await {
+                Ok(key) => {
+                    let _ = sent_drafts.send(SentDraft::Sent(Box::new(Message {
+                        key,
+                        sender,
+                        room,
+                        reply_to: None,
+                        body,
+                        received_at: Some(Utc::now()),
+                        edited_at: None,
+                        reactions: BTreeMap::new(),
+                    })));
+                }
+                Err(err) => {
+                    let _ = toasts.send(Toast::from(&err));
+                    if err.keeps_draft() {
+                        let _ = sent_drafts.send(SentDraft::Failed(text));
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// How much longer the draft can get before it is too long to send, which is red once it is
+    /// over the limit. `None` if there is no limit.
+    fn remaining_line(&self) -> Option<Line<'static>> {
+        let capabilities = match self.compose_room() {
+            Some(room) => self.outbox.capabilities_for(&room),
+            None => self.outbox.capabilities(),
+        };
+        let remaining = capabilities.remaining(self.draft.text())?;
+        let style = if remaining < 0 {
+            Style::new().red().bold()
+        } else {
+            Style::new()
+        };
+        Some(Line::styled(format!(" {remaining} "), style))
+    }
+
+    /// The room a draft is sent to.
+    fn compose_room(&self) -> Option<Room> {
+        Some(self.messages.selected()?.room.clone())
+    }
+
+    fn handle_sent_draft(&mut self, sent: SentDraft) {
+        match sent {
+            // the user's own messages aren't notified about
+            SentDraft::Sent(message) => self.messages.insert(*message),
+            SentDraft::Failed(text) => self.restore_draft(text),
+        }
+    }
+
+    /// Puts back a draft which failed to send, before whatever has been typed since.
+    fn restore_draft(&mut self, text: String) {
+        let typed = self.draft.take();
+        for c in text.chars() {
+            self.draft.insert(c);
+        }
+        if !typed.is_empty() {
+            self.draft.insert('\n');
+            for c in typed.chars() {
+                self.draft.insert(c);
+            }
+        }
+    }
+
+    fn handle_confirm_key(&mut self, event: KeyEvent) {
+        let confirmation = self.confirmation.take();
+        self.mode = Mode::Main;
+        match (confirmation, event.code) {
+            (Some(Confirmation { command, .. }), KeyCode::Char('y' | 'Y')) => {
+                let result = self.execute(command);
+                self.show_key_command_result(result);
+            }
+            (Some(Confirmation { prompt, .. }), _) => {
+                tracing::debug!("cancelled: {prompt}");
+                self.show_toast(Toast::info("Cancelled"));
+            }
+            (None, _) => tracing::warn!("confirm mode without a pending confirmation"),
+        }
+    }
+
+    fn lock(&mut self) {
+        self.unlocked_mode = self.mode;
+        self.mode = Mode::Locked;
+        // don't let a half-typed key sequence complete after unlocking
+        self.key_handler = Default::default();
+    }
+
+    fn unlock(&mut self) {
+        self.mode = self.unlocked_mode;
+        self.passphrase.clear();
+    }
+
+    fn handle_locked_key(&mut self, event: KeyEvent) {
+        let Some(passphrase) = &self.config.lock_passphrase else {
+            self.unlock();
+            return;
+        };
+        match event.code {
+            KeyCode::Enter if self.passphrase.text() == passphrase => self.unlock(),
+            KeyCode::Enter => {
+                self.passphrase.clear();
+                self.ring_bell();
+            }
+            KeyCode::Escape => self.passphrase.clear(),
+            _ => {
+                self.passphrase.type_key(&event);
+            }
+        }
+    }
+
+    fn handle_forward_key(&mut self, event: KeyEvent) {
+        let Some((_, picker)) = &mut self.forward else {
+            tracing::warn!("forward mode without a message to forward");
+            self.mode = Mode::Main;
+            return;
+        };
+        match event.code {
+            KeyCode::Char('j') | KeyCode::Down => picker.select_next(),
+            KeyCode::Char('k') | KeyCode::Up => picker.select_prev(),
+            KeyCode::Enter => {
+                if let Some((message, picker)) = self.forward.take() {
+                    self.forward_message(&message, picker.selected().clone());
+                }
+                self.mode = Mode::Main;
+            }
+            KeyCode::Escape => {
+                self.forward = None;
+                self.mode = Mode::Main;
+            }
+            _ => self.ring_bell(),
+        }
+    }
+
+    /// Sends the message to the room in the background, showing a toast when done.
+    fn forward_message(&mut self, message: &Message, room: Room) {
+        let body = forward_body(
+            message,
+            self.config.forward_prefix,
+            self.outbox.capabilities_for(&room).attachments,
+        );
+        let outbox = self.outbox.clone();
+        let toasts = self.background_toasts.clone();
+        tokio::spawn(async move {
+            let toast = match outbox.send(&room, body).await {
+                Ok(_) => Toast::info(format!("forwarded to {}", room.display_name)),
+                Err(err) => Toast::from(&err),
+            };
+            // if the TUI has stopped, there's nowhere to show the result
+            let _ = toasts.send(toast);
+        });
+    }
+
+    /// Copies text to the clipboard. Returns whether it succeeded, and shows an error otherwise.
+    fn yank(&mut self, text: &str) -> bool {
+        let result = clipboard::copy(text);
+        if let Err(err) = &result {
+            self.show_toast(Toast::error(format!("error copying: {err}")));
+        }
+        result.is_ok()
+    }
+
+    fn show_toast(&mut self, toast: Toast) {
+        self.toast = Some((toast, Instant::now()));
+    }
+
+    /// Rings the bell if a movement stopped at the first or last message, and the config asks for
+    /// that.
+    fn handle_boundary(&mut self, moved: bool) {
+        if !moved && self.config.boundary_behavior == BoundaryBehavior::Bell {
+            self.ring_bell();
+        }
+    }
+
+    fn ring_bell(&mut self) {
+        if self.config.bell.audible() {
+            let output = &mut self.bell_output.0;
+            if let Err(err) = output.write_all(b"\x07").and_then(|()| output.flush()) {
+                tracing::warn!("error ringing bell: {err}");
+            }
+        }
+        if self.config.bell.visual() {
+            self.flash = true;
+        }
+    }
+
+    fn handle_tick(&mut self) {
+        self.flash = false;
+        self.messages.refresh();
+        if self
+            .toast
+            .as_ref()
+            .is_some_and(|(_, shown)| shown.elapsed() >= toast::TOAST_DURATION)
+        {
+            self.toast = None;
+        }
+        if let Some(timeout) = self.config.idle_follow_timeout {
+            if !self.idle_follow && self.last_input.elapsed() >= timeout {
+                self.idle_follow = true;
+                self.messages.set_follow(true);
+            }
+        }
+        if let Some(timeout) = self.config.lock_timeout {
+            if self.mode != Mode::Locked && self.last_input.elapsed() >= timeout {
+                self.lock();
+            }
+        }
+    }
+
+    /// Applies a batch of events in order. New messages are inserted together, up to the next
+    /// edit or deletion.
+    fn handle_events(&mut self, events: Vec<StreamEvent>) {
+        let mut messages = Vec::new();
+        for event in events {
+            match event {
+                StreamEvent::New(message) => messages.push(message),
+                StreamEvent::Edit(message) => {
+                    self.handle_messages(std::mem::take(&mut messages));
+                    self.messages.edit(message);
+                }
+                StreamEvent::Delete(key) => {
+                    self.handle_messages(std::mem::take(&mut messages));
+                    self.messages.delete(&key);
+                }
+            }
+        }
+        self.handle_messages(messages);
+    }
+
+    fn handle_messages(&mut self, mut messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        for message in &mut messages {
+            message.received_at.get_or_insert(now);
+        }
+        let mut notify = false;
+        for message in messages
+            .iter()
+            .filter(|message| self.should_notify(message))
+        {
+            notify = true;
+            if let Some(announce_tx) = &self.announce_tx {
+                // announcements are best-effort, so a closed channel just stops them
+                let _ = announce_tx.send(self.messages.announcement(message));
+            }
+        }
+        if notify {
+            self.ring_bell();
         }
+        self.messages.extend(messages);
     }
 
-    fn handle_message(&mut self, message: Message) {
-        self.messages.insert(message);
+    /// Inserts the messages saved in the history file, without notifying about them. Returns
+    /// `false` if it couldn't be read.
+    fn load_history(&mut self, path: &Path) -> bool {
+        match self.messages.load_from(path) {
+            Ok(0) => true,
+            Ok(skipped) => {
+                self.show_toast(Toast::error(format!(
+                    "skipped {skipped} invalid lines in {}",
+                    path.display()
+                )));
+                true
+            }
+            Err(err) => {
+                self.show_toast(Toast::error(format!(
+                    "error reading {}: {err}",
+                    path.display()
+                )));
+                false
+            }
+        }
+    }
+
+    fn should_notify(&self, message: &Message) -> bool {
+        if message.body.is_system() || self.messages.is_edit(message) {
+            return false;
+        }
+        let level = self
+            .room_notify
+            .get(&message.room.identifier)
+            .unwrap_or(&self.config.notify);
+        match level {
+            NotifyLevel::All => true,
+            NotifyLevel::MentionsOnly => {
+                let text = message.body.plain_text().to_lowercase();
+                self.config
+                    .mention_keywords
+                    .iter()
+                    .any(|keyword| text.contains(&keyword.to_lowercase()))
+            }
+            NotifyLevel::None => false,
+        }
     }
 }
 
 impl Widget for &mut State {
     fn render(self, area: Rect, buffer: &mut Buffer) {
-        self.messages.render(area, buffer)
+        if self.mode == Mode::Locked {
+            self.render_lock_screen(area, buffer);
+            if self.flash {
+                buffer.set_style(area, Style::new().reversed());
+            }
+            return;
+        }
+        let bottom_line = match (&self.confirmation, &self.toast) {
+            _ if self.mode == Mode::Command => Some(Line::raw(":")),
+            _ if self.mode == Mode::Search => Some(Line::raw("/")),
+            (Some(confirmation), _) => Some(Line::raw(confirmation.prompt.as_str())),
+            (None, Some((toast, _))) => {
+                Some(Line::styled(toast.message.as_str(), toast.level.style()))
+            }
+            (None, None) => None,
+        };
+        let compose_height = if self.mode == Mode::Insert {
+            // leave room for the borders
+            self.draft.line_count() as u16 + 2
+        } else {
+            0
+        };
+        let details = self.show_details.then(|| {
+            details::MessageDetails::new(
+                self.messages.selected(),
+                area.width.into(),
+                self.capabilities.ellipsis(),
+            )
+        });
+        // when merged, the bottom line covers the status line
+        let status_shown = !(self.config.merge_status_line && bottom_line.is_some());
+        let [messages_area, details_area, compose_area, status_area, bottom_area] =
+            Layout::vertical([
+                Constraint::Min(0),
+                Constraint::Length(details.as_ref().map_or(0, details::MessageDetails::height)),
+                Constraint::Length(compose_height),
+                Constraint::Length(status_shown.into()),
+                Constraint::Length(bottom_line.is_some().into()),
+            ])
+            .areas(area);
+        let main_area = messages_area;
+        let messages_area = match self.config.room_list_width {
+            Some(width) => {
+                let [room_list_area, _, messages_area] = Layout::horizontal([
+                    Constraint::Length(width),
+                    Constraint::Length(1),
+                    Constraint::Min(0),
+                ])
+                .areas(messages_area);
+                let rooms = self.messages.room_summaries();
+                room_list::RoomList {
+                    rooms: &rooms,
+                    capabilities: self.capabilities,
+                }
+                .render(room_list_area, buffer);
+                messages_area
+            }
+            None => messages_area,
+        };
+        self.messages.render(messages_area, buffer);
+        if let Some(details) = details {
+            details.render(details_area, buffer);
+        }
+        if self.mode == Mode::Insert {
+            let title = match self.compose_room() {
+                Some(room) => format!(
+                    "Compose to {} as {}",
+                    room.display_name, self.user.display_name
+                ),
+                None => "Compose".to_owned(),
+            };
+            let mut block = Block::bordered().title(title);
+            if let Some(remaining) = self.remaining_line() {
+                block = block.title(remaining.right_aligned());
+            }
+            self.draft.render(block.inner(compose_area), buffer);
+            block.render(compose_area, buffer);
+        }
+        self.status_line().render(status_area, buffer);
+        if let Some((_, picker)) = &self.forward {
+            self.render_forward_picker(picker, messages_area, buffer);
+        }
+        if self.mode == Mode::Main {
+            self.render_continuations(main_area, buffer);
+        }
+        if let Some(line) = bottom_line {
+            let prompt_width = line.width() as u16;
+            line.render(bottom_area, buffer);
+            let input = match self.mode {
+                Mode::Command => Some(&self.command_line),
+                Mode::Search => Some(&self.search),
+                _ => None,
+            };
+            if let Some(input) = input {
+                let [_, input_area] =
+                    Layout::horizontal([Constraint::Length(prompt_width), Constraint::Min(0)])
+                        .areas(bottom_area);
+                input.render(input_area, buffer);
+            }
+        }
+        if self.flash {
+            buffer.set_style(area, Style::new().reversed());
+        }
     }
 }
 
-async fn run_inner(
-    mut term: ratatui::DefaultTerminal,
-    mut messages: mpsc::UnboundedReceiver<Message>,
-) -> std::io::Result<()> {
-    use futures::{future::Either, stream::StreamExt};
+impl State {
+    /// Draws the room picker for forwarding a message, over the middle of the area.
+    fn render_forward_picker(&self, picker: &RoomPicker, area: Rect, buffer: &mut Buffer) {
+        const PICKER_WIDTH: u16 = 40;
+        let block = Block::bordered().title("Forward to");
+        let [_, area, _] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(picker.height().saturating_add(2)),
+            Constraint::Fill(1),
+        ])
+        .areas(area);
+        let [_, area, _] = Layout::horizontal([
+            Constraint::Fill(1),
+            Constraint::Length(PICKER_WIDTH),
+            Constraint::Fill(1),
+        ])
+        .areas(area);
+        Clear.render(area, buffer);
+        picker.render(block.inner(area), buffer, self.capabilities);
+        block.render(area, buffer);
+    }
 
-    let mut state = State::default();
+    /// The mode, and the position of the selected message among the shown ones.
+    fn status_line(&self) -> Line<'static> {
+        let (position, count) = self.messages.position();
+        let position = match position {
+            Some(position) => format!("{position}/{count}"),
+            None => format!("-/{count}"),
+        };
+        let mut spans = vec![
+            Span::styled(format!(" {} ", self.mode.name()), self.mode.style()),
+            Span::raw(" "),
+            Span::raw(position),
+        ];
+        if self.mode == Mode::Search && self.search_failed {
+            spans.push(Span::raw(" "));
+            spans.push(Span::styled("no matches", Style::new().red().bold()));
+        }
+        Line::from(spans).on_dark_gray()
+    }
+
+    /// Lists the bindings which continue the pending key sequence (if any) in columns, over the
+    /// bottom of the area.
+    fn render_continuations(&self, area: Rect, buffer: &mut Buffer) {
+        const COLUMN_GAP: usize = 2;
+        let pending = self.key_handler.pending();
+        let continuations = self.main_keys.continuations(pending);
+        if pending.is_empty() || continuations.is_empty() {
+            return;
+        }
+        let entries = continuations
+            .into_iter()
+            .map(|(keys, action)| {
+                let keys = keys.iter().map(KeyEvent::to_string).collect::<String>();
+                format!("{keys}  {action:?}")
+            })
+            .collect::<Vec<_>>();
+        let column_width = entries
+            .iter()
+            .map(|entry| entry.chars().count())
+            .max()
+            .unwrap_or(0)
+            + COLUMN_GAP;
+        let inner_width = usize::from(area.width.saturating_sub(2));
+        let columns = (inner_width / column_width).max(1);
+        // leave most of the messages visible, even if not every binding fits
+        let rows = entries
+            .len()
+            .div_ceil(columns)
+            .min(usize::from(area.height / 2).saturating_sub(2))
+            .max(1);
+        let lines = (0..rows)
+            .map(|row| {
+                let mut line = String::new();
+                for entry in entries.iter().skip(row).step_by(rows).take(columns) {
+                    line.push_str(&format!("{entry:column_width$}"));
+                }
+                Line::raw(line.trim_end().to_owned())
+            })
+            .collect::<Vec<_>>();
+        let title = pending.iter().map(KeyEvent::to_string).collect::<String>();
+        let block = Block::bordered().title(title);
+        let [_, area] =
+            Layout::vertical([Constraint::Fill(1), Constraint::Length(rows as u16 + 2)])
+                .areas(area);
+        Clear.render(area, buffer);
+        Text::from(lines).render(block.inner(area), buffer);
+        block.render(area, buffer);
+    }
+
+    fn render_lock_screen(&self, area: Rect, buffer: &mut Buffer) {
+        let prompt = if self.config.lock_passphrase.is_some() {
+            let masked = "*".repeat(self.passphrase.text().chars().count());
+            Line::raw(format!("Passphrase: {masked}"))
+        } else {
+            Line::raw("Press any key to unlock")
+        };
+        let block = Block::bordered().title("Locked");
+        let [_, area, _] = Layout::vertical([
+            Constraint::Fill(1),
+            Constraint::Length(3),
+            Constraint::Fill(1),
+        ])
+        .areas(area);
+        let [_, area, _] = Layout::horizontal([
+            Constraint::Fill(1),
+            Constraint::Length(prompt.width() as u16 + 2),
+            Constraint::Fill(1),
+        ])
+        .areas(area);
+        prompt.render(block.inner(area), buffer);
+        block.render(area, buffer);
+    }
+}
 
-    let mut term_events = crossterm::event::EventStream::new();
+/// The body to forward `message` with, starting with where it came from if `prefix` is set.
+/// Attachments are kept if the sink can send them (and aren't prefixed), and replaced with a
+/// description otherwise.
+fn forward_body(message: &Message, prefix: bool, attachments: bool) -> MessageBody {
+    let text = match &message.body {
+        MessageBody::File(_) | MessageBody::Image(_) if attachments => return message.body.clone(),
+        MessageBody::File(attachment) | MessageBody::Image(attachment) => {
+            format!("{} ({})", attachment.filename, attachment.url)
+        }
+        body => body.plain_text().into_owned(),
+    };
+    let text = if prefix {
+        format!(
+            "Forwarded from {} in {}:\n{text}",
+            message.sender.display_name, message.room.display_name
+        )
+    } else {
+        text
+    };
+    MessageBody::Text(RichText::from_plain(text))
+}
+
+#[allow(
+    clippy::too_many_arguments,
+    reason = "these are the arguments of `run`, along with the terminal"
+)]
+async fn run_inner<B: ratatui::backend::Backend>(
+    term: &mut ratatui::Terminal<B>,
+    term_events: impl Stream<Item = std::io::Result<Event>>,
+    messages: impl Stream<Item = StreamEvent>,
+    outbox: Outbox,
+    user: User,
+    config: ConfigHandle,
+    key_bindings: KeyBindings,
+    announce: Option<mpsc::UnboundedSender<String>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    let mut config_warnings = config.warnings();
+    let mut config = config.subscribe();
+    let (toasts_tx, mut toasts) = mpsc::unbounded_channel();
+    let (sent_drafts_tx, mut sent_drafts) = mpsc::unbounded_channel();
+    let mut state = State::new(
+        config.borrow_and_update().clone(),
+        toasts_tx,
+        sent_drafts_tx,
+        outbox,
+        user,
+        key_bindings,
+    );
+    state.announce_tx = announce;
+    // if the history couldn't be read, it isn't overwritten either
+    let history_file = state.config.history_file.clone();
+    let history_file = history_file.filter(|path| state.load_history(path));
+
+    let mut messages = std::pin::pin!(messages.ready_chunks(MAX_MESSAGE_BATCH));
+    let mut term_events = std::pin::pin!(term_events);
+    let mut ticks = tokio::time::interval(TICK_INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut frames = FrameLimiter::default();
     while !state.stopped {
-        term.draw(|frame| frame.render_widget(&mut state, frame.area()))?;
-        match futures::future::select(term_events.next(), std::pin::pin!(messages.recv())).await {
-            Either::Left((Some(Ok(event)), _)) => state.handle_event(event),
-            Either::Left((Some(Err(err)), _)) => {
-                tracing::warn!("error reading terminal event: {err}")
-            }
-            Either::Right((Some(message), _)) => state.handle_message(message),
-            Either::Left((None, _)) => {
-                tracing::info!("term events stream stopped, shutting down");
-                break;
-            }
-            Either::Right((None, _)) => {
-                tracing::info!("message stream stopped, shutting down");
+        if frames.should_draw(Instant::now(), state.config.max_fps) {
+            term.draw(|frame| frame.render_widget(&mut state, frame.area()))?;
+        }
+        let frame_deadline = frames.pending();
+        let key_deadline = state.key_handler.deadline();
+        tokio::select! {
+            () = tokio::time::sleep_until(frame_deadline.unwrap_or_else(Instant::now)),
+                if frame_deadline.is_some() => {}
+            event = term_events.next() => match event {
+                Some(Ok(event)) => state.handle_event(event),
+                Some(Err(err)) => tracing::warn!("error reading terminal event: {err}"),
+                None => {
+                    tracing::info!("term events stream stopped, shutting down");
+                    break;
+                }
+            },
+            batch = messages.next() => match batch {
+                Some(batch) => state.handle_events(batch),
+                None => {
+                    tracing::info!("message stream stopped, shutting down");
+                    break;
+                }
+            },
+            () = tokio::time::sleep_until(key_deadline.unwrap_or_else(Instant::now)),
+                if key_deadline.is_some() => state.handle_key_timeout(),
+            _ = ticks.tick() => state.handle_tick(),
+            // `state` holds a sender, so this never returns `None`
+            Some(toast) = toasts.recv() => state.show_toast(toast),
+            Some(sent) = sent_drafts.recv() => state.handle_sent_draft(sent),
+            // if every handle has been dropped, this branch is disabled
+            Ok(()) = config.changed() => state.set_config(config.borrow_and_update().clone()),
+            Ok(warning) = config_warnings.recv() => state.show_toast(Toast::warning(warning)),
+            () = cancel.cancelled() => {
+                tracing::info!("cancelled, shutting down");
                 break;
             }
         }
     }
+    // handle the messages which have already arrived, so that they are saved too
+    while let Some(Some(batch)) = messages.next().now_or_never() {
+        state.handle_events(batch);
+    }
+    // and the toasts which have arrived, so that they are on the final frame
+    while let Ok(toast) = toasts.try_recv() {
+        state.show_toast(toast);
+    }
+    // draw once more, so the result of the last action is visible before the terminal is restored
+    term.draw(|frame| frame.render_widget(&mut state, frame.area()))?;
+    if let Some(path) = &history_file {
+        state.messages.save_to(path).map_err(|err| {
+            std::io::Error::other(format!("error writing {}: {err}", path.display()))
+        })?;
+    }
     Ok(())
 }
+
+/// Keeps frames to at most `max_fps` a second. A frame which would come too soon after the last
+/// one is put off until it's allowed, and drawn then along with anything else which happens in the
+/// meantime.
+#[derive(Debug, Default)]
+struct FrameLimiter {
+    last_draw: Option<Instant>,
+    /// When the frame which was put off can be drawn
+    pending: Option<Instant>,
+}
+
+impl FrameLimiter {
+    /// Whether a frame can be drawn at `now`, which it is assumed to be if so. If not, it is
+    /// pending until it can be.
+    fn should_draw(&mut self, now: Instant, max_fps: u16) -> bool {
+        let interval = Duration::from_secs(1).checked_div(max_fps.into());
+        let next = self
+            .last_draw
+            .zip(interval)
+            .map(|(last, interval)| last + interval);
+        match next.filter(|&next| next > now) {
+            Some(next) => {
+                self.pending = Some(next);
+                false
+            }
+            None => {
+                self.last_draw = Some(now);
+                self.pending = None;
+                true
+            }
+        }
+    }
+
+    /// When the frame which was put off can be drawn, if there is one.
+    fn pending(&self) -> Option<Instant> {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use carrier_pigeon_common::{Attachment, LengthUnit, SendError, ServiceType, SinkCapabilities};
+    use ratatui::style::Modifier;
+
+    use super::*;
+
+    /// A sink for messages of up to this many bytes, which fails to send them.
+    struct LimitedSink(usize);
+
+    impl MessageSink for LimitedSink {
+        fn capabilities(&self) -> SinkCapabilities {
+            SinkCapabilities {
+                max_message_length: Some(self.0),
+                length_unit: LengthUnit::Bytes,
+                attachments: false,
+            }
+        }
+
+        fn send(
+            &self,
+            _room: &Room,
+            _body: MessageBody,
+        ) -> impl std::future::Future<Output = Result<MessageKey, SendError>> + Send {
+            std::future::ready(Err(SendError::Network))
+        }
+    }
+
+    /// A sink which accepts every message, as if it was given the identifier `sent`.
+    struct AcceptingSink;
+
+    impl MessageSink for AcceptingSink {
+        fn send(
+            &self,
+            _room: &Room,
+            _body: MessageBody,
+        ) -> impl std::future::Future<Output = Result<MessageKey, SendError>> + Send {
+            std::future::ready(Ok(MessageKey {
+                timestamp: chrono::DateTime::from_timestamp(10, 0).unwrap(),
+                identifier: "sent".into(),
+                service: ServiceType::Fake,
+            }))
+        }
+    }
+
+    /// A sink which passes on where each message is sent, and what it is.
+    struct RecordingSink {
+        attachments: bool,
+        sent: mpsc::UnboundedSender<(Room, MessageBody)>,
+    }
+
+    impl MessageSink for RecordingSink {
+        fn capabilities(&self) -> SinkCapabilities {
+            SinkCapabilities {
+                attachments: self.attachments,
+                ..SinkCapabilities::default()
+            }
+        }
+
+        fn send(
+            &self,
+            room: &Room,
+            body: MessageBody,
+        ) -> impl std::future::Future<Output = Result<MessageKey, SendError>> + Send {
+            let _ = self.sent.send((room.clone(), body));
+            AcceptingSink.send(room, MessageBody::Text(RichText::default()))
+        }
+    }
+
+    /// A message from alice in `general`, sent `seconds` after the epoch.
+    fn message(identifier: &str, seconds: i64, text: &str) -> Message {
+        Message {
+            key: MessageKey {
+                timestamp: chrono::DateTime::from_timestamp(seconds, 0).unwrap(),
+                identifier: identifier.into(),
+                service: ServiceType::Fake,
+            },
+            sender: User {
+                display_name: "alice".into(),
+                identifier: "@alice:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            room: Room {
+                display_name: "general".into(),
+                identifier: "!general:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            reply_to: None,
+            body: MessageBody::Text(RichText::from_plain(text)),
+            received_at: None,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    fn test_state(config: Config) -> State {
+        test_state_with_keys(config, KeyBindings::default())
+    }
+
+    fn test_state_with_keys(config: Config, key_bindings: KeyBindings) -> State {
+        let (toasts, _) = mpsc::unbounded_channel();
+        let (sent_drafts, _) = mpsc::unbounded_channel();
+        let user = User {
+            display_name: "me".into(),
+            identifier: "@me:example.com".into(),
+            service: ServiceType::Fake,
+        };
+        let mut state = State::new(
+            Arc::new(config),
+            toasts,
+            sent_drafts,
+            Outbox::closed(),
+            user,
+            key_bindings,
+        );
+        // don't depend on the environment the tests are run in, or ring its bell
+        state.capabilities = Capabilities::default();
+        state.bell_output = BellOutput(Box::new(std::io::sink()));
+        state.messages.capabilities = Capabilities::default();
+        state
+    }
+
+    /// Inserts `count` messages, a second apart, and selects the first.
+    fn insert_messages(state: &mut State, count: usize) {
+        for i in 0..count {
+            state
+                .messages
+                .insert(message(&format!("m{i}"), i as i64, &format!("message {i}")));
+        }
+        press(state, "gg");
+    }
+
+    fn message_count(state: &State) -> usize {
+        state.messages.messages().count()
+    }
+
+    fn press(state: &mut State, keys: &str) {
+        for key in keymap::parse_key_sequence(keys).unwrap() {
+            state.handle_key_event(key);
+        }
+    }
+
+    fn render(state: &mut State, width: u16, height: u16) -> Buffer {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, width, height));
+        state.render(buffer.area, &mut buffer);
+        buffer
+    }
+
+    fn row(buffer: &Buffer, y: u16) -> String {
+        (0..buffer.area.width)
+            .map(|x| buffer[(x, y)].symbol())
+            .collect::<String>()
+            .trim_end()
+            .to_owned()
+    }
+
+    #[test]
+    fn compose_shows_remaining_length() {
+        let mut state = test_state(Config::default());
+        state.outbox = Outbox::new(LimitedSink(5)).0;
+        // `é` is two bytes
+        press(&mut state, "ié");
+        let buffer = render(&mut state, 30, 6);
+        // the compose box is the three rows above the status line
+        assert!(row(&buffer, 2).ends_with("─ 3 ┐"));
+        assert_eq!(buffer[(27, 2)].fg, Color::Reset);
+    }
+
+    #[test]
+    fn compose_shows_over_limit_in_red() {
+        let mut state = test_state(Config::default());
+        state.outbox = Outbox::new(LimitedSink(5)).0;
+        press(&mut state, "itoo long");
+        let buffer = render(&mut state, 30, 6);
+        assert!(row(&buffer, 2).ends_with("─ -3 ┐"));
+        assert_eq!(buffer[(26, 2)].fg, Color::Red);
+    }
+
+    #[test]
+    fn compose_without_limit_has_no_counter() {
+        let mut state = test_state(Config::default());
+        press(&mut state, "ihi");
+        let buffer = render(&mut state, 30, 6);
+        assert_eq!(row(&buffer, 2), format!("┌Compose{}┐", "─".repeat(21)));
+    }
+
+    #[test]
+    fn over_limit_draft_isnt_sent() {
+        let mut state = test_state(Config::default());
+        state.outbox = Outbox::new(LimitedSink(5)).0;
+        state.messages.insert(message("a", 0, "hello"));
+        state.messages.select_first();
+        press(&mut state, "itoo long<CR>");
+        assert_eq!(state.draft.text(), "too long");
+        let (toast, _) = state.toast.as_ref().unwrap();
+        assert_eq!(toast.level, toast::ToastLevel::Error);
+        assert!(toast.message.contains("message too long (max 5)"));
+    }
+
+    #[test]
+    fn goto_prefers_identifiers_to_positions() {
+        let mut state = test_state(Config::default());
+        state.messages.extend([
+            message("a", 0, "first"),
+            message("b", 1, "second"),
+            message("1", 2, "third"),
+        ]);
+        press(&mut state, ":goto 1<CR>");
+        assert_eq!(&*state.messages.selected().unwrap().key.identifier, "1");
+        press(&mut state, ":goto 2<CR>");
+        assert_eq!(&*state.messages.selected().unwrap().key.identifier, "b");
+        press(&mut state, ":goto a<CR>");
+        assert_eq!(&*state.messages.selected().unwrap().key.identifier, "a");
+        press(&mut state, ":goto c<CR>");
+        assert_eq!(&*state.messages.selected().unwrap().key.identifier, "a");
+        let (toast, _) = state.toast.as_ref().unwrap();
+        assert!(toast
+            .message
+            .contains("no shown message has the identifier `c`"));
+    }
+
+    #[test]
+    fn reply_parent_not_visible() {
+        let mut state = test_state(Config::default());
+        let parent = Message {
+            room: Room {
+                display_name: "random".into(),
+                identifier: "!random:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            ..message("parent", 0, "question")
+        };
+        let child = Message {
+            reply_to: Some("parent".into()),
+            ..message("child", 1, "answer")
+        };
+        state.messages.extend([parent, child]);
+        state.messages.select_identifier("child");
+        press(&mut state, "<Tab>gp");
+        assert_eq!(&*state.messages.selected().unwrap().key.identifier, "child");
+        let (toast, _) = state.toast.as_ref().unwrap();
+        assert_eq!(toast.message, "the message it replies to isn't visible");
+        press(&mut state, "<Tab>gp");
+        assert_eq!(
+            &*state.messages.selected().unwrap().key.identifier,
+            "parent"
+        );
+    }
+
+    #[tokio::test]
+    async fn sent_draft_is_from_user() {
+        let mut state = test_state(Config::default());
+        let (outbox, send_task) = Outbox::new(AcceptingSink);
+        tokio::spawn(send_task);
+        state.outbox = outbox;
+        let (sent_drafts_tx, mut sent_drafts) = mpsc::unbounded_channel();
+        state.sent_drafts = sent_drafts_tx;
+        state.messages.insert(message("a", 0, "hello"));
+        state.messages.select_first();
+        press(&mut state, "ihi there<CR>");
+        assert!(state.draft.is_empty());
+        state.handle_sent_draft(sent_drafts.recv().await.unwrap());
+        let sent = state
+            .messages
+            .messages()
+            .find(|message| &*message.key.identifier == "sent")
+            .unwrap();
+        assert_eq!(sent.sender.identifier, state.user.identifier);
+        assert_eq!(sent.room.identifier, "!general:example.com".into());
+        assert_eq!(sent.body.plain_text(), "hi there");
+    }
+
+    #[test]
+    fn mode_transitions() {
+        let mut state = test_state(Config::default());
+        press(&mut state, "i");
+        assert_eq!(state.mode, Mode::Insert);
+        press(&mut state, "<Esc>");
+        assert_eq!(state.mode, Mode::Main);
+        press(&mut state, ":");
+        assert_eq!(state.mode, Mode::Command);
+        press(&mut state, "q<Esc>");
+        assert_eq!(state.mode, Mode::Main);
+        assert!(state.command_line.is_empty());
+        press(&mut state, "/");
+        assert_eq!(state.mode, Mode::Search);
+        press(&mut state, "<Esc>");
+        assert_eq!(state.mode, Mode::Main);
+    }
+
+    #[test]
+    fn leaving_insert_mode_discards_draft() {
+        let mut state = test_state(Config::default());
+        press(&mut state, "ihello<Esc>");
+        assert_eq!(state.mode, Mode::Main);
+        assert!(state.draft.is_empty());
+    }
+
+    #[test]
+    fn leaving_insert_mode_keeps_draft() {
+        let mut state = test_state(Config {
+            keep_draft: true,
+            ..Config::default()
+        });
+        press(&mut state, "ihello<Esc>");
+        assert_eq!(state.draft.text(), "hello");
+        press(&mut state, "i!");
+        assert_eq!(state.draft.text(), "hello!");
+    }
+
+    #[test]
+    fn enter_mode_binding() {
+        let key_bindings = KeyBindings::from_toml(
+            r#"
+            [main]
+            "a" = { enter-mode = "insert" }
+            [insert]
+            "<C-s>" = { enter-mode = "search" }
+            "#,
+        )
+        .unwrap();
+        let mut state = test_state_with_keys(Config::default(), key_bindings);
+        press(&mut state, "ahi");
+        assert_eq!(state.mode, Mode::Insert);
+        assert_eq!(state.draft.text(), "hi");
+        press(&mut state, "<C-s>");
+        assert_eq!(state.mode, Mode::Search);
+        assert!(state.draft.is_empty());
+    }
+
+    #[test]
+    fn merged_status_line_shows_status() {
+        let mut state = test_state(Config {
+            merge_status_line: true,
+            ..Config::default()
+        });
+        let buffer = render(&mut state, 30, 5);
+        assert_eq!(row(&buffer, 4), " NORMAL  -/0");
+    }
+
+    #[test]
+    fn merged_status_line_shows_command_line() {
+        let mut state = test_state(Config {
+            merge_status_line: true,
+            ..Config::default()
+        });
+        press(&mut state, ":goto 3");
+        let buffer = render(&mut state, 30, 5);
+        assert_eq!(row(&buffer, 4), ":goto 3");
+        assert_eq!(row(&buffer, 3), "");
+    }
+
+    #[test]
+    fn separate_status_line() {
+        let mut state = test_state(Config::default());
+        press(&mut state, ":goto 3");
+        let buffer = render(&mut state, 30, 5);
+        assert_eq!(row(&buffer, 3), " COMMAND  -/0");
+        assert_eq!(row(&buffer, 4), ":goto 3");
+    }
+
+    #[test]
+    fn small_bulk_delete_is_immediate() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 6);
+        press(&mut state, "2dd");
+        assert_eq!(state.mode, Mode::Main);
+        assert_eq!(message_count(&state), 4);
+    }
+
+    #[test]
+    fn large_bulk_delete_confirms() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 6);
+        press(&mut state, "5dd");
+        assert_eq!(state.mode, Mode::Confirm);
+        assert_eq!(message_count(&state), 6);
+        press(&mut state, "y");
+        assert_eq!(state.mode, Mode::Main);
+        assert_eq!(message_count(&state), 1);
+    }
+
+    #[test]
+    fn bulk_delete_can_be_cancelled() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 6);
+        press(&mut state, "5ddn");
+        assert_eq!(state.mode, Mode::Main);
+        assert_eq!(message_count(&state), 6);
+    }
+
+    #[test]
+    fn delete_command_confirms() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 6);
+        press(&mut state, ":delete 5<CR>");
+        assert_eq!(state.mode, Mode::Confirm);
+        press(&mut state, "y");
+        assert_eq!(message_count(&state), 1);
+        // the rest of a command line waits for the answer
+        insert_messages(&mut state, 6);
+        press(&mut state, ":delete 4; delete 1<CR>");
+        assert_eq!(state.mode, Mode::Confirm);
+        assert_eq!(message_count(&state), 6);
+    }
+
+    /// Terminal events for typing the text, ending with enter.
+    fn typed_line(text: &str) -> Vec<std::io::Result<Event>> {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+        text.chars()
+            .map(KeyCode::Char)
+            .chain([KeyCode::Enter])
+            .map(|code| Ok(Event::Key(KeyEvent::new(code, KeyModifiers::NONE))))
+            .collect()
+    }
+
+    /// Runs the TUI on a test terminal until it stops by itself, with the terminal events and no
+    /// messages.
+    async fn run_test_terminal(
+        term: &mut ratatui::Terminal<ratatui::backend::TestBackend>,
+        events: Vec<std::io::Result<Event>>,
+        cancel: CancellationToken,
+    ) {
+        run_test_terminal_with_messages(term, events, futures::stream::pending(), cancel).await;
+    }
+
+    /// Like [`run_test_terminal`], but with the messages from the stream, stopping once it ends.
+    async fn run_test_terminal_with_messages(
+        term: &mut ratatui::Terminal<ratatui::backend::TestBackend>,
+        events: Vec<std::io::Result<Event>>,
+        messages: impl Stream<Item = StreamEvent>,
+        cancel: CancellationToken,
+    ) {
+        let user = User {
+            display_name: "me".into(),
+            identifier: "@me:example.com".into(),
+            service: ServiceType::Fake,
+        };
+        run_inner(
+            term,
+            futures::stream::iter(events).chain(futures::stream::pending()),
+            messages,
+            Outbox::closed(),
+            user,
+            // draw after every event, so that the frames don't depend on how quickly the events come
+            ConfigHandle::new(Config {
+                max_fps: 0,
+                ..Config::default()
+            }),
+            KeyBindings::default(),
+            None,
+            cancel,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn final_state_is_drawn() {
+        let mut term = ratatui::Terminal::new(ratatui::backend::TestBackend::new(30, 6)).unwrap();
+        run_test_terminal(&mut term, typed_line(":q"), CancellationToken::new()).await;
+        // without the last draw, the screen would still show the command line with `:q` on it
+        let buffer = term.backend().buffer();
+        let rows = (0..6).map(|y| row(buffer, y)).collect::<Vec<_>>();
+        assert!(rows.iter().all(|row| !row.contains(":q")), "{rows:#?}");
+        assert!(rows[5].contains("NORMAL"), "{rows:#?}");
+    }
+
+    fn selected_identifier(state: &State) -> Option<&str> {
+        state
+            .messages
+            .selected()
+            .map(|message| &*message.key.identifier)
+    }
+
+    #[test]
+    fn idle_follow() {
+        let mut state = test_state(Config {
+            idle_follow_timeout: Some(Duration::from_secs(60)),
+            ..Config::default()
+        });
+        insert_messages(&mut state, 3);
+        state.handle_tick();
+        assert_eq!(selected_identifier(&state), Some("m0"));
+        // as if the last keypress was just over a minute ago
+        state.last_input -= Duration::from_secs(61);
+        state.handle_tick();
+        assert!(state.idle_follow);
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        state.messages.insert(message("new", 10, "new"));
+        assert_eq!(selected_identifier(&state), Some("new"));
+        // a keypress stops following
+        press(&mut state, "k");
+        assert!(!state.idle_follow);
+        state.messages.insert(message("newer", 11, "newer"));
+        assert_eq!(selected_identifier(&state), Some("m2"));
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_the_run_loop() {
+        let mut term = ratatui::Terminal::new(ratatui::backend::TestBackend::new(30, 6)).unwrap();
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            canceller.cancel();
+        });
+        // nothing else would stop it
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            run_test_terminal(&mut term, Vec::new(), cancel),
+        )
+        .await
+        .expect("the run loop should stop once cancelled");
+    }
+
+    /// A writer which keeps what is written to it where the test can see it.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bell_modes() {
+        for (bell, audible, visual) in [
+            (BellMode::None, false, false),
+            (BellMode::Audible, true, false),
+            (BellMode::Visual, false, true),
+            (BellMode::Both, true, true),
+        ] {
+            assert_eq!(bell.audible(), audible, "{bell:?}");
+            let mut state = test_state(Config {
+                bell,
+                ..Config::default()
+            });
+            let output = SharedOutput::default();
+            state.bell_output = BellOutput(Box::new(output.clone()));
+            // an unmapped key rings the bell
+            press(&mut state, "<F12>");
+            let rung: &[u8] = if audible { b"\x07" } else { b"" };
+            assert_eq!(*output.0.lock().unwrap(), rung, "{bell:?}");
+            let buffer = render(&mut state, 30, 6);
+            let reversed = buffer[(0, 0)].modifier.contains(Modifier::REVERSED);
+            assert_eq!(reversed, visual, "{bell:?}");
+            // the flash only lasts until the next tick
+            state.handle_tick();
+            let buffer = render(&mut state, 30, 6);
+            assert!(!buffer[(0, 0)].modifier.contains(Modifier::REVERSED));
+        }
+    }
+
+    /// A state with a message selected to reply in the room of, and an outbox which accepts drafts
+    /// without sending them anywhere.
+    fn compose_state(submit_key: SubmitKey) -> State {
+        let mut state = test_state(Config {
+            submit_key,
+            ..Config::default()
+        });
+        state.outbox = Outbox::new(AcceptingSink).0;
+        state.messages.insert(message("a", 0, "hello"));
+        state.messages.select_first();
+        state
+    }
+
+    #[tokio::test]
+    async fn enter_submits() {
+        let mut state = compose_state(SubmitKey::Enter);
+        press(&mut state, "ione<S-CR>two");
+        assert_eq!(state.draft.text(), "one\ntwo");
+        press(&mut state, "<CR>");
+        assert!(state.draft.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shift_enter_submits() {
+        let mut state = compose_state(SubmitKey::ShiftEnter);
+        press(&mut state, "ione<CR>two");
+        assert_eq!(state.draft.text(), "one\ntwo");
+        press(&mut state, "<S-CR>");
+        assert!(state.draft.is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_from_a_stream() {
+        let mut term = ratatui::Terminal::new(ratatui::backend::TestBackend::new(60, 12)).unwrap();
+        let messages = futures::stream::iter([
+            message("a", 0, "first").into(),
+            message("b", 1, "second").into(),
+        ]);
+        run_test_terminal_with_messages(&mut term, Vec::new(), messages, CancellationToken::new())
+            .await;
+        let buffer = term.backend().buffer();
+        let rows = (0..12).map(|y| row(buffer, y)).collect::<Vec<_>>();
+        assert!(rows.iter().any(|row| row.contains("first")), "{rows:#?}");
+        assert!(rows.iter().any(|row| row.contains("second")), "{rows:#?}");
+        assert!(rows[11].ends_with("-/2"), "{rows:#?}");
+    }
+
+    #[test]
+    fn continuations_popup() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 1);
+        let rows = |state: &mut State| {
+            let buffer = render(state, 60, 12);
+            (0..12).map(|y| row(&buffer, y)).collect::<Vec<_>>()
+        };
+        let idle = rows(&mut state);
+        assert!(idle.iter().all(|row| !row.contains('┌')), "{idle:#?}");
+        press(&mut state, "g");
+        let pending = rows(&mut state);
+        assert!(
+            pending.iter().any(|row| row.starts_with("┌g")),
+            "{pending:#?}"
+        );
+        assert!(
+            pending.iter().any(|row| row.contains("g  ")),
+            "{pending:#?}"
+        );
+        press(&mut state, "g");
+        let resolved = rows(&mut state);
+        assert!(
+            resolved.iter().all(|row| !row.contains('┌')),
+            "{resolved:#?}"
+        );
+    }
+
+    fn lock_state(lock_passphrase: Option<&str>) -> State {
+        let mut state = test_state(Config {
+            lock_timeout: Some(Duration::from_secs(60)),
+            lock_passphrase: lock_passphrase.map(Into::into),
+            ..Config::default()
+        });
+        insert_messages(&mut state, 1);
+        state.handle_tick();
+        assert_eq!(state.mode, Mode::Main);
+        // as if the last keypress was just over a minute ago
+        state.last_input -= Duration::from_secs(61);
+        state.handle_tick();
+        assert_eq!(state.mode, Mode::Locked);
+        state
+    }
+
+    #[test]
+    fn idle_locks() {
+        let mut state = lock_state(None);
+        // messages are still stored, but not shown
+        state.messages.insert(message("secret", 10, "secret"));
+        assert_eq!(message_count(&state), 2);
+        let buffer = render(&mut state, 40, 10);
+        let rows = (0..10).map(|y| row(&buffer, y)).collect::<Vec<_>>();
+        assert!(rows.iter().all(|row| !row.contains("message")), "{rows:#?}");
+        assert!(
+            rows.iter().any(|row| row.contains("Press any key")),
+            "{rows:#?}"
+        );
+        press(&mut state, "x");
+        assert_eq!(state.mode, Mode::Main);
+        let buffer = render(&mut state, 40, 10);
+        let rows = (0..10).map(|y| row(&buffer, y)).collect::<Vec<_>>();
+        assert!(rows.iter().any(|row| row.contains("secret")), "{rows:#?}");
+    }
+
+    #[test]
+    fn passphrase_unlocks() {
+        let mut state = lock_state(Some("open"));
+        press(&mut state, "<Esc>");
+        assert_eq!(state.mode, Mode::Locked);
+        press(&mut state, "nope<CR>");
+        assert_eq!(state.mode, Mode::Locked);
+        assert!(state.passphrase.is_empty());
+        press(&mut state, "open<CR>");
+        assert_eq!(state.mode, Mode::Main);
+    }
+
+    #[test]
+    fn room_notify_levels() {
+        let mut state = test_state(Config {
+            notify: NotifyLevel::All,
+            mention_keywords: vec!["Carol".into()],
+            ..Config::default()
+        });
+        insert_messages(&mut state, 1);
+        let other_room = Message {
+            room: Room {
+                display_name: "random".into(),
+                identifier: "!random:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            ..message("other", 10, "anything")
+        };
+        let plain = message("plain", 10, "hello");
+        let mention = message("mention", 10, "hello carol");
+        assert!(state.should_notify(&plain));
+
+        press(&mut state, ":notify mentions-only<CR>");
+        assert!(!state.should_notify(&plain));
+        assert!(state.should_notify(&mention));
+        assert!(state.should_notify(&other_room));
+
+        press(&mut state, ":notify none<CR>");
+        assert!(!state.should_notify(&plain));
+        assert!(!state.should_notify(&mention));
+        assert!(state.should_notify(&other_room));
+    }
+
+    #[test]
+    fn incremental_search() {
+        let mut state = test_state(Config::default());
+        state.messages.extend([
+            message("a", 0, "apple"),
+            message("b", 1, "banana"),
+            message("c", 2, "blueberry"),
+            message("d", 3, "cherry"),
+        ]);
+        state.messages.select_identifier("a");
+        press(&mut state, "/b");
+        assert_eq!(selected_identifier(&state), Some("b"));
+        press(&mut state, "l");
+        assert_eq!(selected_identifier(&state), Some("c"));
+        press(&mut state, "<Esc>");
+        assert_eq!(state.mode, Mode::Main);
+        assert_eq!(selected_identifier(&state), Some("a"));
+        press(&mut state, "/cher<CR>");
+        assert_eq!(state.mode, Mode::Main);
+        assert_eq!(selected_identifier(&state), Some("d"));
+    }
+
+    #[test]
+    fn unknown_key_in_insert_mode() {
+        use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+        let mut state = test_state(Config::default());
+        press(&mut state, "ihi");
+        // crossterm's caps lock has no equivalent
+        state.handle_event(Event::Key(KeyEvent::new(
+            KeyCode::CapsLock,
+            KeyModifiers::NONE,
+        )));
+        assert_eq!(state.mode, Mode::Insert);
+        assert_eq!(state.draft.text(), "hi");
+        press(&mut state, "!");
+        assert_eq!(state.draft.text(), "hi!");
+    }
+
+    #[test]
+    fn toggle_room_focus() {
+        let mut state = test_state(Config::default());
+        let random = |identifier, seconds, text| Message {
+            room: Room {
+                display_name: "random".into(),
+                identifier: "!random:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            ..message(identifier, seconds, text)
+        };
+        state.messages.extend([
+            message("a", 0, "one"),
+            random("b", 1, "two"),
+            message("c", 2, "three"),
+        ]);
+        state.messages.select_identifier("c");
+        press(&mut state, "<Tab>");
+        assert_eq!(
+            state.messages.room_filter(),
+            Some(&"!general:example.com".into())
+        );
+        assert_eq!(selected_identifier(&state), Some("c"));
+        assert_eq!(message_count(&state), 2);
+        press(&mut state, "<Tab>");
+        assert_eq!(state.messages.room_filter(), None);
+        assert_eq!(selected_identifier(&state), Some("c"));
+        assert_eq!(message_count(&state), 3);
+    }
+
+    #[test]
+    fn yank_timestamp() {
+        let mut state = test_state(Config::default());
+        state.messages.insert(message("a", 1_700_000_000, "hello"));
+        state.messages.select_first();
+        press(&mut state, "yt");
+        let (toast, _) = state.toast.as_ref().unwrap();
+        assert_eq!(toast.message, "copied 2023-11-14T22:13:20+00:00");
+    }
+
+    #[test]
+    fn dispatch_delete_selected() {
+        let mut state = test_state(Config::default());
+        assert!(matches!(
+            state.dispatch(AppCommand::DeleteSelected(1)),
+            Err(CommandError::NoSelection)
+        ));
+        insert_messages(&mut state, 3);
+        state.dispatch(AppCommand::DeleteSelected(1)).unwrap();
+        assert_eq!(message_count(&state), 2);
+        assert_eq!(selected_identifier(&state), Some("m1"));
+        state.dispatch(AppCommand::Undo(1)).unwrap();
+        assert_eq!(message_count(&state), 3);
+    }
+
+    #[test]
+    fn dispatch_search() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 3);
+        state
+            .dispatch(AppCommand::Search("message 2".into()))
+            .unwrap();
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        assert!(!state.search_failed);
+        state
+            .dispatch(AppCommand::Search("nowhere".into()))
+            .unwrap();
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        assert!(state.search_failed);
+    }
+
+    /// Three messages in `general`, then two in `random`, with the policy for marking them read.
+    fn read_policy_state(mark_read: ReadPolicy) -> State {
+        let mut state = test_state(Config {
+            mark_read,
+            ..Config::default()
+        });
+        let random = |identifier, seconds| Message {
+            room: Room {
+                display_name: "random".into(),
+                identifier: "!random:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            ..message(identifier, seconds, "hi")
+        };
+        state.messages.extend([
+            message("g0", 0, "hi"),
+            message("g1", 1, "hi"),
+            message("g2", 2, "hi"),
+            random("r0", 3),
+            random("r1", 4),
+        ]);
+        state
+    }
+
+    /// The number of unread messages in `general` and `random`.
+    fn unread(state: &State) -> (usize, usize) {
+        let summaries = state.messages.room_summaries();
+        let count = |name: &str| {
+            summaries
+                .iter()
+                .find(|(room, _)| &*room.display_name == name)
+                .map_or(0, |(_, unread)| *unread)
+        };
+        (count("general"), count("random"))
+    }
+
+    #[test]
+    fn read_on_select() {
+        let mut state = read_policy_state(ReadPolicy::OnSelect);
+        assert_eq!(unread(&state), (3, 2));
+        press(&mut state, "gg");
+        assert_eq!(unread(&state), (2, 2));
+        press(&mut state, "j");
+        assert_eq!(unread(&state), (1, 2));
+        press(&mut state, "jj");
+        assert_eq!(unread(&state), (0, 1));
+        // the markers never move back
+        press(&mut state, "k");
+        assert_eq!(unread(&state), (0, 1));
+    }
+
+    #[test]
+    fn read_on_view() {
+        let mut state = read_policy_state(ReadPolicy::OnView);
+        press(&mut state, "gg");
+        assert_eq!(unread(&state), (0, 2));
+        press(&mut state, "jj");
+        assert_eq!(unread(&state), (0, 2));
+        press(&mut state, "j");
+        assert_eq!(unread(&state), (0, 0));
+    }
+
+    #[test]
+    fn read_manually() {
+        let mut state = read_policy_state(ReadPolicy::Manual);
+        press(&mut state, "ggjjj");
+        assert_eq!(unread(&state), (3, 2));
+        press(&mut state, "mr");
+        assert_eq!(unread(&state), (3, 0));
+    }
+
+    /// The rows of the details footer, if shown, which is just above the status line.
+    fn details_rows(state: &mut State) -> Vec<String> {
+        let buffer = render(state, 80, 12);
+        (0..buffer.area.height)
+            .map(|y| row(&buffer, y))
+            .filter(|row| row.starts_with("sent: ") || row.starts_with("sender: "))
+            .collect()
+    }
+
+    #[test]
+    fn details_footer() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 2);
+        assert!(details_rows(&mut state).is_empty());
+        state.dispatch(AppCommand::ToggleDetails).unwrap();
+        assert_eq!(
+            details_rows(&mut state),
+            [
+                "sent: 1970-01-01T00:00:00.000Z  room: !general:example.com",
+                "sender: @alice:example.com  id: m0  service: fake",
+            ]
+        );
+        press(&mut state, "j");
+        assert_eq!(
+            details_rows(&mut state)[0],
+            "sent: 1970-01-01T00:00:01.000Z  room: !general:example.com"
+        );
+        assert!(details_rows(&mut state)[1].contains("id: m1"));
+    }
+
+    #[test]
+    fn boundary_behaviors() {
+        for (boundary_behavior, after_top, after_bottom, rings) in [
+            (BoundaryBehavior::Stop, "m0", "m2", false),
+            (BoundaryBehavior::Wrap, "m2", "m0", false),
+            (BoundaryBehavior::Bell, "m0", "m2", true),
+        ] {
+            let mut state = test_state(Config {
+                boundary_behavior,
+                bell: BellMode::Visual,
+                ..Config::default()
+            });
+            insert_messages(&mut state, 3);
+            press(&mut state, "k");
+            assert_eq!(
+                selected_identifier(&state),
+                Some(after_top),
+                "{boundary_behavior:?}"
+            );
+            assert_eq!(state.flash, rings, "{boundary_behavior:?}");
+            state.handle_tick();
+            press(&mut state, "Gj");
+            assert_eq!(
+                selected_identifier(&state),
+                Some(after_bottom),
+                "{boundary_behavior:?}"
+            );
+            assert_eq!(state.flash, rings, "{boundary_behavior:?}");
+        }
+    }
+
+    /// Forwards a message with `body` from `general` to `random`, returning what was sent.
+    async fn forwarded(body: MessageBody, attachments: bool) -> (Room, MessageBody) {
+        let mut state = test_state(Config::default());
+        let (sent_tx, mut sent) = mpsc::unbounded_channel();
+        let (outbox, send_task) = Outbox::new(RecordingSink {
+            attachments,
+            sent: sent_tx,
+        });
+        tokio::spawn(send_task);
+        state.outbox = outbox;
+        let mut elsewhere = message("b", 1, "elsewhere");
+        elsewhere.room = Room {
+            display_name: "random".into(),
+            identifier: "!random:example.com".into(),
+            service: ServiceType::Fake,
+        };
+        state.messages.insert(elsewhere);
+        state.messages.insert(Message {
+            body,
+            ..message("a", 0, "")
+        });
+        press(&mut state, "gggf");
+        assert_eq!(state.mode, Mode::Forward);
+        press(&mut state, "<CR>");
+        assert_eq!(state.mode, Mode::Main);
+        sent.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn forward_text() {
+        let (room, body) = forwarded(MessageBody::Text(RichText::from_plain("hello")), false).await;
+        assert_eq!(room.identifier, "!random:example.com".into());
+        assert_eq!(body.plain_text(), "Forwarded from alice in general:\nhello");
+    }
+
+    #[tokio::test]
+    async fn forward_attachment() {
+        let attachment = Attachment {
+            url: "mxc://example.com/cat".into(),
+            filename: "cat.png".into(),
+            size: None,
+        };
+        let body = MessageBody::Image(attachment.clone());
+        let (room, sent) = forwarded(body.clone(), true).await;
+        assert_eq!(room.identifier, "!random:example.com".into());
+        assert!(matches!(&sent, MessageBody::Image(sent) if sent.url == attachment.url));
+        // without support for attachments, it's described instead
+        let (_, sent) = forwarded(body, false).await;
+        assert_eq!(
+            sent.plain_text(),
+            "Forwarded from alice in general:\ncat.png (mxc://example.com/cat)"
+        );
+    }
+
+    #[test]
+    fn record_and_replay_macro() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 6);
+        press(&mut state, "qajjq");
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        assert!(state.recording.is_none());
+        // the keys starting and stopping the recording aren't recorded
+        let j = KeyEvent::from(KeyCode::Char('j'));
+        assert_eq!(state.macros[&'a'], [j, j]);
+        press(&mut state, "@a");
+        assert_eq!(selected_identifier(&state), Some("m4"));
+        // replaying doesn't record anything
+        assert_eq!(state.macros[&'a'], [j, j]);
+        press(&mut state, "gg2@a");
+        assert_eq!(selected_identifier(&state), Some("m4"));
+    }
+
+    #[test]
+    fn replaying_empty_register() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 3);
+        assert!(matches!(
+            state.dispatch(AppCommand::ReplayMacro {
+                register: 'b',
+                count: 1
+            }),
+            Err(CommandError::EmptyRegister('b'))
+        ));
+        assert_eq!(selected_identifier(&state), Some("m0"));
+    }
+
+    #[test]
+    fn bench_workloads_run_once() {
+        let (keymap, presses) = bench_workloads::keymap_input();
+        assert_eq!(presses.len(), bench_workloads::KEY_PRESSES);
+        assert!(bench_workloads::resolve(&keymap, &presses) > 0);
+        // the same seed gives the same input
+        assert_eq!(bench_workloads::keymap_input().1, presses);
+
+        let messages = bench_workloads::messages();
+        let mut view = bench_workloads::insert(messages.clone());
+        assert_eq!(view.messages().count(), bench_workloads::MESSAGE_COUNT);
+        let mut buffer = Buffer::empty(bench_workloads::AREA);
+        bench_workloads::redraw(&mut view, &mut buffer);
+        assert_ne!(buffer, Buffer::empty(bench_workloads::AREA));
+    }
+
+    #[test]
+    fn clear_filters() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 3);
+        let mut elsewhere = message("r", 1, "elsewhere");
+        elsewhere.room = Room {
+            display_name: "random".into(),
+            identifier: "!random:example.com".into(),
+            service: ServiceType::Fake,
+        };
+        state.messages.insert(elsewhere);
+        press(&mut state, "<Tab>");
+        assert_eq!(state.messages.is_shown("r"), Some(false));
+        press(&mut state, "/message 2<CR>");
+        assert_eq!(state.messages.search(), Some("message 2"));
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        press(&mut state, "<Esc>");
+        assert_eq!(state.messages.room_filter(), None);
+        assert_eq!(state.messages.search(), None);
+        assert_eq!(state.messages.is_shown("r"), Some(true));
+        assert_eq!(selected_identifier(&state), Some("m2"));
+    }
+
+    #[test]
+    fn frames_are_capped() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut frames = FrameLimiter::default();
+        // at 10 frames a second, frames are at least 100ms apart
+        assert!(frames.should_draw(at(0), 10));
+        assert_eq!(frames.pending(), None);
+        assert!(!frames.should_draw(at(10), 10));
+        assert_eq!(frames.pending(), Some(at(100)));
+        // requests in the meantime are drawn in the same frame
+        assert!(!frames.should_draw(at(30), 10));
+        assert_eq!(frames.pending(), Some(at(100)));
+        assert!(frames.should_draw(at(100), 10));
+        assert_eq!(frames.pending(), None);
+        // the next is 100ms after the one which was put off
+        assert!(!frames.should_draw(at(150), 10));
+        assert_eq!(frames.pending(), Some(at(200)));
+        // requests far enough apart are drawn straight away
+        assert!(frames.should_draw(at(400), 10));
+        assert!(frames.should_draw(at(500), 10));
+        // and with no cap, every one is
+        assert!(frames.should_draw(at(501), 0));
+        assert!(frames.should_draw(at(501), 0));
+    }
+
+    #[test]
+    fn announcements() {
+        let mut state = test_state(Config {
+            notify: NotifyLevel::All,
+            ..Config::default()
+        });
+        let (announce_tx, mut announcements) = mpsc::unbounded_channel();
+        state.announce_tx = Some(announce_tx);
+        insert_messages(&mut state, 1);
+        press(&mut state, ":notify none<CR>");
+        let other_room = Message {
+            room: Room {
+                display_name: "random".into(),
+                identifier: "!random:example.com".into(),
+                service: ServiceType::Fake,
+            },
+            ..message("other", 10, "two\nlines")
+        };
+        state.handle_events(vec![
+            StreamEvent::New(message("muted", 10, "quiet")),
+            StreamEvent::New(other_room),
+        ]);
+        assert_eq!(
+            announcements.try_recv().unwrap(),
+            "alice (@alice:example.com) in random: two lines"
+        );
+        assert!(announcements.try_recv().is_err());
+    }
+
+    #[test]
+    fn resize_keeps_selection_shown() {
+        let mut state = test_state(Config::default());
+        insert_messages(&mut state, 20);
+        state.messages.select_identifier("m15");
+        render(&mut state, 60, 50);
+        let (first, _) = state.messages.visible_range().unwrap();
+        assert_eq!(&*first.identifier, "m0");
+        state.handle_event(Event::Resize(60, 10));
+        let buffer = render(&mut state, 60, 10);
+        assert_eq!(selected_identifier(&state), Some("m15"));
+        let (first, last) = state.messages.visible_range().unwrap();
+        assert_ne!(&*first.identifier, "m0");
+        assert_eq!(&*last.identifier, "m15");
+        assert!((0..10).any(|y| row(&buffer, y) == "   message 15"));
+    }
+
+    #[test]
+    fn same_sender_jump_without_match_rings() {
+        let mut state = test_state(Config {
+            boundary_behavior: BoundaryBehavior::Bell,
+            bell: BellMode::Visual,
+            ..Config::default()
+        });
+        insert_messages(&mut state, 3);
+        press(&mut state, "]a");
+        assert_eq!(selected_identifier(&state), Some("m1"));
+        assert!(!state.flash);
+        // there is only one more from alice
+        press(&mut state, "3]a");
+        assert_eq!(selected_identifier(&state), Some("m2"));
+        assert!(state.flash);
+    }
+
+    #[test]
+    fn message_batch_is_laid_out_once() {
+        let mut state = test_state(Config::default());
+        let messages = carrier_pigeon_fake_messages::seeded_messages(
+            7,
+            MAX_MESSAGE_BATCH,
+            chrono::DateTime::from_timestamp(0, 0).unwrap(),
+        );
+        // as the run loop gets them, when they arrive faster than they are drawn
+        state.handle_events(messages.into_iter().map(StreamEvent::New).collect());
+        render(&mut state, 40, 10);
+        assert_eq!(state.messages.redraws, 1);
+        assert_eq!(message_count(&state), MAX_MESSAGE_BATCH);
+    }
+}
@@ -1,91 +1,1015 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt,
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
 
-use carrier_pigeon_common::{Message, MessageBody, MessageKey, RichText};
+use carrier_pigeon_common::{
+    Attachment, Clock, Message, MessageBody, MessageKey, Room, ServiceType, SystemClock,
+    SystemEvent, User,
+};
+use chrono::{DateTime, Utc};
 use ratatui::{
     buffer::Buffer,
     layout::Rect,
-    text::{Line, Text},
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
     widgets::{List, ListItem, ListState, StatefulWidget, Widget},
 };
 
+use tokio::{
+    sync::broadcast,
+    time::{Duration, Instant},
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::{
+    capabilities::Capabilities,
+    config::{
+        AgeFade, BoundaryBehavior, MessageOrder, ReactionDisplay, ReadPolicy, SenderDisplay,
+        SeparatorGranularity, TimestampFormat,
+    },
+    highlight::Highlighter,
+    wrap::{elide_line, mark_matches, wrap_text},
+};
+
+/// Marks the selected message. Its width is reserved to the left of every message.
+const HIGHLIGHT_SYMBOL: &str = "-> ";
+
+/// Indents replies by one level, in reply tree mode.
+const REPLY_INDENT: &str = "  ";
+/// Replies nested more deeply than this are indented the same.
+const MAX_REPLY_DEPTH: usize = 2;
+
+/// How long a message stays highlighted after jumping to it.
+const SPOTLIGHT_DURATION: Duration = Duration::from_secs(2);
+/// How many deletions can be undone.
+const UNDO_LIMIT: usize = 50;
+/// How many changes are kept for a subscriber which hasn't read them yet.
+const EVENT_CAPACITY: usize = 256;
+
+/// The key messages are ordered by: the time according to the [`MessageOrder`], then the message
+/// key to break ties.
+type SortKey = (DateTime<Utc>, MessageKey);
+
+/// A message identifier along with its service, since identifiers are only unique within one.
+type ServiceIdentifier = (ServiceType, Arc<str>);
+
+/// The index entry for the message with the key.
+fn service_identifier(key: &MessageKey) -> ServiceIdentifier {
+    (key.service, key.identifier.clone())
+}
+
+/// A change to the messages in a [`MessageListView`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageEvent {
+    /// A message was added
+    Inserted(MessageKey),
+    /// A message was replaced by another version of it, edited, or reacted to
+    Replaced(MessageKey),
+    /// A message was deleted
+    Removed(MessageKey),
+}
+
 #[derive(Debug)]
 pub struct MessageListView {
-    messages: BTreeMap<MessageKey, Message>,
-    cursor: Option<MessageKey>,
+    messages: BTreeMap<SortKey, Message>,
+    /// The time each message is sorted by
+    sort_times: BTreeMap<MessageKey, DateTime<Utc>>,
+    /// The key of each message, by identifier
+    identifiers: BTreeMap<ServiceIdentifier, MessageKey>,
+    /// The replies to each message, by the identifier of the message they reply to
+    replies: BTreeMap<ServiceIdentifier, BTreeSet<SortKey>>,
+    /// The message whose replies were last cycled through
+    reply_anchor: Option<ServiceIdentifier>,
+    cursor: Option<SortKey>,
+    /// The messages deleted by the user which can be restored, most recent last
+    undo_stack: VecDeque<Message>,
     list_state: ListState,
     list_items: List<'static>,
     /// Marks whether the `list_state` and `list_items` are out-of-sync
     dirty: bool,
-    // TODO: filters
+    /// The width messages were wrapped to in `list_items`
+    wrap_width: usize,
+    /// The height of each item in `list_items`
+    item_heights: Vec<usize>,
+    /// Where the list was in the last render
+    list_area: Rect,
+    /// The (first) message in each item in `list_items`
+    item_keys: Vec<MessageKey>,
+    /// The first and last messages which were visible in the last render
+    visible: Option<(MessageKey, MessageKey)>,
+    /// Whether to scroll the selected message to the middle of the list in the next render
+    center: bool,
+    /// Whether the cursor follows newly inserted messages
+    follow: bool,
+    /// A message which was just jumped to, and when, so it can be briefly highlighted
+    spotlight: Option<(MessageKey, Instant)>,
+    /// The search whose matches are highlighted, in lowercase
+    search: Option<String>,
+    highlighter: Highlighter,
+    /// Where changes to the messages are sent, once anything has subscribed to them
+    events: Option<broadcast::Sender<MessageEvent>>,
+    /// How many times the list has been laid out, so tests can check it isn't done needlessly
+    #[cfg(test)]
+    pub(crate) redraws: usize,
+    /// Whether consecutive messages from the same room are grouped under a header
+    group_rooms: bool,
+    /// The rooms whose groups are collapsed to just their header, when grouping by room
+    collapsed_rooms: BTreeSet<Arc<str>>,
+    /// Whether to label messages with their room when it differs from the previous message's
+    pub room_chips: bool,
+    /// The identifier of the user running carrier-pigeon, whose messages have bold headers
+    pub own_user: Option<Arc<str>>,
+    /// Whether to show a badge with the sender's initial before their name
+    pub sender_avatars: bool,
+    /// How to fade messages with age
+    pub age_fade: Option<AgeFade>,
+    /// How to order messages. Changing this only affects messages inserted afterwards.
+    pub order: MessageOrder,
+    /// How to show the sender of each message
+    pub sender_display: SenderDisplay,
+    /// Where to show the reactions to each message
+    pub reaction_display: ReactionDisplay,
+    /// Show how long ago each message was sent, rather than when
+    pub timestamp_format: TimestampFormat,
+    /// Indent replies under the messages they reply to
+    pub reply_tree: bool,
+    /// The source of the current time, for relative times and age fading
+    pub clock: Arc<dyn Clock>,
+    /// What the terminal can display
+    pub capabilities: Capabilities,
+    /// The widest messages are wrapped to in reading mode
+    pub reading_width: u16,
+    /// Below this wrap width, messages are shown on a single line
+    pub compact_below: Option<usize>,
+    /// Whether messages are wrapped to `reading_width` and centered, rather than filling the area
+    reading_mode: bool,
+    /// Whether consecutive system messages are collapsed into a single summary
+    collapse_system: bool,
+    /// Messages which are folded to their first line
+    folded: BTreeSet<MessageKey>,
+    /// If set, only messages from the room with this identifier are shown
+    room_filter: Option<Arc<str>>,
+    /// The latest read message in each room, by room identifier
+    last_read: BTreeMap<Arc<str>, SortKey>,
+    /// The room of the selected message when the read markers were last updated
+    viewed_room: Option<Arc<str>>,
+    /// When messages are marked as read
+    pub read_policy: ReadPolicy,
+    /// What moving past the first or last message does
+    pub boundary: BoundaryBehavior,
+    /// If set, messages in different periods are separated by a line with the date
+    pub date_separators: Option<SeparatorGranularity>,
+}
+
+/// A summary of the messages in a [`MessageListView`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ViewStats {
+    pub total: usize,
+    /// The number of messages in each room, by display name
+    pub per_room: BTreeMap<Arc<str>, usize>,
+    /// The number of distinct senders
+    pub senders: usize,
+    /// The times of the first and last messages, according to the message order
+    pub span: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl fmt::Display for ViewStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} messages from {} senders in {} rooms",
+            self.total,
+            self.senders,
+            self.per_room.len()
+        )?;
+        if let Some((first, last)) = self.span {
+            write!(f, ", {first} to {last}")?;
+        }
+        for (i, (room, count)) in self.per_room.iter().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{separator}{room}: {count}")?;
+        }
+        if !self.per_room.is_empty() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for MessageListView {
     fn default() -> Self {
         Self {
             messages: Default::default(),
+            sort_times: Default::default(),
+            identifiers: BTreeMap::new(),
+            replies: BTreeMap::new(),
+            reply_anchor: None,
             cursor: None,
             list_state: Default::default(),
-            list_items: List::default().highlight_symbol("-> "),
+            list_items: List::default().highlight_symbol(HIGHLIGHT_SYMBOL),
             dirty: false,
+            wrap_width: 0,
+            item_heights: Vec::new(),
+            list_area: Rect::ZERO,
+            item_keys: Vec::new(),
+            center: false,
+            visible: None,
+            follow: false,
+            spotlight: None,
+            undo_stack: VecDeque::new(),
+            search: None,
+            group_rooms: false,
+            collapsed_rooms: BTreeSet::new(),
+            room_chips: false,
+            own_user: None,
+            sender_avatars: false,
+            age_fade: None,
+            order: MessageOrder::SenderTimestamp,
+            sender_display: SenderDisplay::NameWithId,
+            reaction_display: ReactionDisplay::SeparateLine,
+            timestamp_format: TimestampFormat::Utc,
+            reply_tree: false,
+            clock: Arc::new(SystemClock),
+            capabilities: Capabilities::default(),
+            reading_width: 80,
+            compact_below: None,
+            reading_mode: false,
+            collapse_system: false,
+            folded: BTreeSet::new(),
+            room_filter: None,
+            last_read: BTreeMap::new(),
+            viewed_room: None,
+            read_policy: ReadPolicy::OnSelect,
+            boundary: BoundaryBehavior::Stop,
+            date_separators: None,
+            highlighter: Default::default(),
+            events: None,
+            #[cfg(test)]
+            redraws: 0,
         }
     }
 }
 
 impl MessageListView {
-    pub fn select_next(&mut self) {
+    /// Selects the next message, wrapping around to the first one if the boundary behavior is
+    /// [`BoundaryBehavior::Wrap`]. Returns `false` if the selection didn't move.
+    pub fn select_next(&mut self) -> bool {
         use std::ops::Bound;
-        self.cursor = match &self.cursor {
+        let next = match &self.cursor {
             Some(cursor) => self
                 .messages
                 .range((Bound::Excluded(cursor), Bound::Unbounded))
-                .next(),
-            None => self.messages.iter().next(),
+                .find(|(k, m)| !self.is_hidden(k, m)),
+            None => self.messages.iter().find(|(k, m)| !self.is_hidden(k, m)),
         }
-        .map(|(k, _)| k.clone())
-        .or_else(|| self.cursor.clone());
-        self.list_state.select_next();
+        .map(|(k, _)| k.clone());
+        self.move_cursor(next, Self::select_first)
     }
 
-    pub fn select_prev(&mut self) {
-        self.cursor = match &self.cursor {
-            Some(cursor) => self.messages.range(..cursor).next_back(),
-            None => self.messages.iter().next_back(),
+    /// Selects the previous message, wrapping around to the last one if the boundary behavior is
+    /// [`BoundaryBehavior::Wrap`]. Returns `false` if the selection didn't move.
+    pub fn select_prev(&mut self) -> bool {
+        let prev = match &self.cursor {
+            Some(cursor) => self.messages.range(..cursor).rev(),
+            None => self.messages.range(..).rev(),
+        }
+        .find(|(k, m)| !self.is_hidden(k, m))
+        .map(|(k, _)| k.clone());
+        self.move_cursor(prev, Self::select_last)
+    }
+
+    /// Moves the cursor to `to`, or if there is nowhere to move to, applies the boundary behavior
+    /// with `wrap` selecting the other end.
+    fn move_cursor(&mut self, to: Option<SortKey>, wrap: fn(&mut Self)) -> bool {
+        self.dirty = true;
+        match to {
+            Some(to) => {
+                self.cursor = Some(to);
+                true
+            }
+            None if self.boundary == BoundaryBehavior::Wrap => {
+                let before = self.cursor.clone();
+                wrap(self);
+                self.cursor != before
+            }
+            None => false,
         }
-        .map(|(k, _)| k.clone())
-        .or_else(|| self.cursor.clone());
-        self.list_state.select_previous();
     }
 
     pub fn select_first(&mut self) {
-        self.cursor = self.messages.keys().next().cloned();
-        self.list_state.select_first();
+        self.cursor = self
+            .messages
+            .iter()
+            .find(|(k, m)| !self.is_hidden(k, m))
+            .map(|(k, _)| k.clone());
+        self.dirty = true;
     }
 
     pub fn select_last(&mut self) {
-        self.cursor = self.messages.keys().next_back().cloned();
-        self.list_state.select_last();
+        self.cursor = self.last_shown();
+        self.dirty = true;
+    }
+
+    /// Selects the shown message at the given 1-based position, or the last one if there are
+    /// fewer. Returns `false` if the position was past the last message.
+    pub fn select_nth(&mut self, position: usize) -> bool {
+        let nth = self
+            .messages
+            .iter()
+            .filter(|(k, m)| !self.is_hidden(k, m))
+            .nth(position.saturating_sub(1))
+            .map(|(k, _)| k.clone());
+        if nth.is_none() {
+            self.select_last();
+            return false;
+        }
+        self.cursor = nth;
+        self.dirty = true;
+        true
+    }
+
+    /// The 1-based position of the selected message among the shown ones, if any is selected, and
+    /// the number of shown messages.
+    pub fn position(&self) -> (Option<usize>, usize) {
+        let mut position = None;
+        let mut count = 0;
+        for (k, m) in &self.messages {
+            if self.is_hidden(k, m) {
+                continue;
+            }
+            count += 1;
+            if self.cursor.as_ref() == Some(k) {
+                position = Some(count);
+            }
+        }
+        (position, count)
+    }
+
+    fn last_shown(&self) -> Option<SortKey> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|(k, m)| !self.is_hidden(k, m))
+            .map(|(k, _)| k.clone())
+    }
+
+    /// The nearest shown message to `key` (which may not be present), other than itself. This
+    /// prefers the following message, then the preceding one.
+    fn nearest_shown(&self, key: &SortKey) -> Option<SortKey> {
+        use std::ops::Bound;
+        self.messages
+            .range((Bound::Excluded(key), Bound::Unbounded))
+            .find(|(k, m)| !self.is_hidden(k, m))
+            .or_else(|| {
+                self.messages
+                    .range(..key)
+                    .rev()
+                    .find(|(k, m)| !self.is_hidden(k, m))
+            })
+            .map(|(k, _)| k.clone())
+    }
+
+    /// The room which messages are filtered to, if any.
+    pub fn room_filter(&self) -> Option<&Arc<str>> {
+        self.room_filter.as_ref()
+    }
+
+    /// Shows only the messages from the room with the given identifier, or all messages if
+    /// `None`. The cursor stays on the selected message if it is still shown, or else moves to the
+    /// nearest one which is.
+    pub fn set_room_filter(&mut self, room: Option<Arc<str>>) {
+        if let (Some(room), ReadPolicy::OnView) = (&room, self.read_policy) {
+            self.mark_room_read(room);
+        }
+        self.room_filter = room;
+        self.reconcile_cursor_after_filter_change();
+        self.dirty = true;
+    }
+
+    /// Shows all the messages, and clears the highlights of a search. The cursor stays on the
+    /// selected message.
+    pub fn clear_filters(&mut self) {
+        self.spotlight = None;
+        self.set_search(None);
+        self.set_room_filter(None);
+    }
+
+    /// Keeps the cursor on the selected message if the filters still show it, or else moves it to
+    /// the shown message nearest to it in time, preferring the following one in a tie. This should
+    /// be called whenever the filters change.
+    fn reconcile_cursor_after_filter_change(&mut self) {
+        use std::ops::Bound;
+        let Some(cursor) = self.cursor.take() else {
+            return;
+        };
+        if !self.is_hidden(&cursor, &self.messages[&cursor]) {
+            self.cursor = Some(cursor);
+            return;
+        }
+        let next = self
+            .messages
+            .range((Bound::Excluded(&cursor), Bound::Unbounded))
+            .find(|(k, m)| !self.is_hidden(k, m))
+            .map(|(k, _)| k);
+        let prev = self
+            .messages
+            .range(..&cursor)
+            .rev()
+            .find(|(k, m)| !self.is_hidden(k, m))
+            .map(|(k, _)| k);
+        let nearest = match (prev, next) {
+            (Some(prev), Some(next)) if cursor.0 - prev.0 < next.0 - cursor.0 => Some(prev),
+            (prev, next) => next.or(prev),
+        };
+        self.cursor = nearest.cloned();
+    }
+
+    /// Advances the read markers after the user moves the cursor, according to the read policy.
+    pub fn update_read_markers(&mut self) {
+        let Some(cursor) = &self.cursor else {
+            return;
+        };
+        let room = self.messages[cursor].room.identifier.clone();
+        match self.read_policy {
+            ReadPolicy::OnSelect => self.mark_read(&room, cursor.clone()),
+            ReadPolicy::OnView if self.viewed_room.as_ref() != Some(&room) => {
+                self.mark_room_read(&room)
+            }
+            ReadPolicy::OnView | ReadPolicy::Manual => {}
+        }
+        self.viewed_room = Some(room);
+    }
+
+    /// Marks all the messages in the selected message's room as read. Returns `false` if no
+    /// message is selected.
+    pub fn mark_selected_room_read(&mut self) -> bool {
+        let Some(selected) = self.selected() else {
+            return false;
+        };
+        let room = selected.room.identifier.clone();
+        self.mark_room_read(&room);
+        true
+    }
+
+    fn mark_room_read(&mut self, room: &Arc<str>) {
+        let latest = self
+            .messages
+            .iter()
+            .rev()
+            .find(|(_, message)| message.room.identifier == *room)
+            .map(|(key, _)| key.clone());
+        if let Some(latest) = latest {
+            self.mark_read(room, latest);
+        }
+    }
+
+    /// Marks the messages in the room up to `key` as read. The marker never moves backwards.
+    fn mark_read(&mut self, room: &Arc<str>, key: SortKey) {
+        match self.last_read.get_mut(room) {
+            Some(last_read) => *last_read = key.max(last_read.clone()),
+            None => {
+                self.last_read.insert(room.clone(), key);
+            }
+        }
+    }
+
+    /// Whether the message matches the filters.
+    fn is_listed(&self, message: &Message) -> bool {
+        room_matches(&self.room_filter, message)
+    }
+
+    /// Selects the message, or clears the selection (also if the message isn't present).
+    pub fn select(&mut self, key: Option<&MessageKey>) {
+        if !key.is_some_and(|key| self.select_key(key)) {
+            self.cursor = None;
+            self.dirty = true;
+        }
+    }
+
+    /// Selects the message, if it is present. Returns whether it is.
+    pub fn select_key(&mut self, key: &MessageKey) -> bool {
+        let Some(cursor) = self.sort_key(key) else {
+            return false;
+        };
+        self.cursor = Some(cursor);
+        self.dirty = true;
+        true
+    }
+
+    /// The key of a message with the identifier, from any service, e.g. for one the user typed.
+    pub fn find_identifier(&self, identifier: &str) -> Option<&MessageKey> {
+        self.identifiers
+            .iter()
+            .find(|((_, id), _)| **id == *identifier)
+            .map(|(_, key)| key)
+    }
+
+    /// The key of the message `message` replies to, if it is present.
+    pub fn reply_parent(&self, message: &Message) -> Option<&MessageKey> {
+        let parent = message.reply_to.clone()?;
+        self.identifiers.get(&(message.key.service, parent))
+    }
+
+    /// Selects the message with the identifier, if it is present. Returns whether it is.
+    pub fn select_identifier(&mut self, identifier: &str) -> bool {
+        match self.find_identifier(identifier) {
+            Some(key) => self.select_key(&key.clone()),
+            None => false,
+        }
+    }
+
+    /// Whether the message with the identifier is shown (rather than filtered out or collapsed),
+    /// or `None` if it isn't present.
+    pub fn is_shown(&self, identifier: &str) -> Option<bool> {
+        self.is_key_shown(self.find_identifier(identifier)?)
+    }
+
+    /// Whether the message is shown (rather than filtered out or collapsed), or `None` if it isn't
+    /// present.
+    pub fn is_key_shown(&self, key: &MessageKey) -> Option<bool> {
+        let key = self.sort_key(key)?;
+        Some(!self.is_hidden(&key, &self.messages[&key]))
+    }
+
+    /// Selects the message with the identifier and spotlights it, if it is present and shown.
+    /// Returns whether it is. The list scrolls to it when next rendered.
+    pub fn goto(&mut self, identifier: &str) -> bool {
+        match self.find_identifier(identifier) {
+            Some(key) => self.goto_key(&key.clone()),
+            None => false,
+        }
+    }
+
+    /// Selects the message and spotlights it, if it is present and shown. Returns whether it is.
+    pub fn goto_key(&mut self, key: &MessageKey) -> bool {
+        if self.is_key_shown(key) != Some(true) || !self.select_key(key) {
+            return false;
+        }
+        self.spotlight_selected();
+        true
+    }
+
+    /// Selects the next reply to the message whose replies were last cycled through, if the
+    /// selected message is one of them, or else the first reply to the selected message after
+    /// it. Replies are cycled through in order, wrapping around. Returns `false` if there are no
+    /// (shown) replies.
+    pub fn select_next_reply(&mut self) -> bool {
+        use std::ops::Bound;
+        let Some(cursor) = &self.cursor else {
+            return false;
+        };
+        let selected = &self.messages[cursor];
+        let service = selected.key.service;
+        let anchor = match &self.reply_anchor {
+            Some(anchor @ (anchor_service, parent))
+                if *anchor_service == service && selected.reply_to.as_ref() == Some(parent) =>
+            {
+                anchor.clone()
+            }
+            _ => (service, selected.key.identifier.clone()),
+        };
+        let Some(replies) = self.replies.get(&anchor) else {
+            return false;
+        };
+        let next = replies
+            .range((Bound::Excluded(cursor), Bound::Unbounded))
+            .chain(replies.range(..=cursor))
+            .find(|key| !self.is_hidden(key, &self.messages[*key]))
+            .cloned();
+        let Some(next) = next else {
+            return false;
+        };
+        self.reply_anchor = Some(anchor);
+        self.cursor = Some(next);
+        self.dirty = true;
+        true
+    }
+
+    /// Where the message is in the list, if it is present.
+    fn sort_key(&self, key: &MessageKey) -> Option<SortKey> {
+        Some((*self.sort_times.get(key)?, key.clone()))
+    }
+
+    /// Selects the first message at or after `from` (or the first message, if `from` is `None`)
+    /// whose body contains `query`, ignoring case, and wrapping around to the start. Returns
+    /// whether any message matched.
+    pub fn select_match(&mut self, from: Option<&MessageKey>, query: &str) -> bool {
+        let query = query.to_lowercase();
+        let start = from.and_then(|key| self.sort_key(key));
+        let (after, before) = match &start {
+            Some(start) => (self.messages.range(start..), self.messages.range(..start)),
+            None => (self.messages.range(..), self.messages.range(..)),
+        };
+        let found = after
+            .chain(before)
+            .find(|(key, message)| !self.is_hidden(key, message) && matches_query(message, &query))
+            .map(|(key, _)| key.clone());
+        let Some(found) = found else {
+            return false;
+        };
+        self.cursor = Some(found);
+        self.dirty = true;
+        true
+    }
+
+    /// The search whose matches are highlighted.
+    pub fn search(&self) -> Option<&str> {
+        self.search.as_deref()
+    }
+
+    /// Highlights the matches of a search in the messages, or clears the highlights. An empty
+    /// search highlights nothing.
+    pub fn set_search(&mut self, query: Option<&str>) {
+        self.search = query
+            .filter(|query| !query.is_empty())
+            .map(str::to_lowercase);
+        self.dirty = true;
+    }
+
+    /// Selects the next (or previous) message after the selected one which matches the search,
+    /// wrapping around at the ends. Returns whether it wrapped, or `None` if nothing matches.
+    pub fn select_next_match(&mut self, forward: bool) -> Option<bool> {
+        use std::ops::Bound;
+        let query = self.search.as_deref()?;
+        let is_match = |(key, message): &(&SortKey, &Message)| {
+            !self.is_hidden(key, message) && matches_query(message, query)
+        };
+        let (after, before) = match &self.cursor {
+            Some(cursor) => (
+                self.messages
+                    .range((Bound::Excluded(cursor), Bound::Unbounded))
+                    .find(is_match),
+                self.messages.range(..cursor).rev().find(is_match),
+            ),
+            None => (
+                self.messages.iter().find(is_match),
+                self.messages.iter().rev().find(is_match),
+            ),
+        };
+        let (found, wrapped) = match (forward, after, before) {
+            (true, Some(found), _) | (false, _, Some(found)) => (found, false),
+            (true, None, _) => (self.messages.iter().find(is_match)?, true),
+            (false, _, None) => (self.messages.iter().rev().find(is_match)?, true),
+        };
+        self.cursor = Some(found.0.clone());
+        self.dirty = true;
+        Some(wrapped)
+    }
+
+    /// Toggles whether consecutive system messages are collapsed into a single summary.
+    pub fn toggle_collapse_system(&mut self) {
+        self.collapse_system = !self.collapse_system;
+        self.keep_cursor_shown();
+    }
+
+    /// Toggles whether consecutive messages from the same room are grouped under a header with
+    /// the room's name.
+    pub fn toggle_grouping(&mut self) {
+        self.group_rooms = !self.group_rooms;
+        self.keep_cursor_shown();
+    }
+
+    /// Collapses the group of the selected message's room to just its header, or expands it if
+    /// it was collapsed. This applies to all of the room's groups, and only while grouping by
+    /// room.
+    pub fn toggle_collapse_room(&mut self) {
+        let Some(cursor) = &self.cursor else {
+            return;
+        };
+        let room = &self.messages[cursor].room.identifier;
+        if !self.collapsed_rooms.remove(room) {
+            self.collapsed_rooms.insert(room.clone());
+        }
+        self.keep_cursor_shown();
+    }
+
+    /// Moves the cursor from a message which has just been collapsed into a summary to the first
+    /// message of the summary.
+    fn keep_cursor_shown(&mut self) {
+        if let Some(cursor) = &self.cursor {
+            if let Some((key, _)) = self
+                .messages
+                .range(..=cursor)
+                .rev()
+                .find(|(k, m)| !self.is_hidden(k, m))
+            {
+                self.cursor = Some(key.clone());
+            }
+        }
+        self.dirty = true;
+    }
+
+    /// Folds the selected message to its first line, or unfolds it if it was already folded.
+    pub fn toggle_fold_selected(&mut self) {
+        if let Some((_, selected)) = &self.cursor {
+            if !self.folded.remove(selected) {
+                self.folded.insert(selected.clone());
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// Whether the message isn't shown, either because it doesn't match the filters, or because
+    /// it is collapsed into the summary of a preceding system message or the header of its room's
+    /// group.
+    fn is_hidden(&self, key: &SortKey, message: &Message) -> bool {
+        if !self.is_listed(message) {
+            return true;
+        }
+        let collapsed_room = self.is_room_collapsed(message);
+        let collapsed_system = self.collapse_system && message.body.is_system();
+        if !collapsed_room && !collapsed_system {
+            return false;
+        }
+        self.messages
+            .range(..key)
+            .rev()
+            .find(|(_, prev)| self.is_listed(prev))
+            .is_some_and(|(_, prev)| {
+                let same_room = prev.room.identifier == message.room.identifier;
+                (collapsed_room && same_room)
+                    || (collapsed_system
+                        && prev.body.is_system()
+                        && (same_room || !self.group_rooms))
+            })
+    }
+
+    /// Whether the message's room is collapsed to its header.
+    fn is_room_collapsed(&self, message: &Message) -> bool {
+        self.group_rooms && self.collapsed_rooms.contains(&message.room.identifier)
+    }
+
+    /// Moves the cursor to the next (or previous, if `forward` is false) message from the same
+    /// sender as the selected message. Returns whether such a message was found.
+    pub fn select_same_sender(&mut self, forward: bool) -> bool {
+        use std::ops::Bound;
+        let Some(cursor) = &self.cursor else {
+            return false;
+        };
+        let sender = &self.messages[cursor].sender.identifier;
+        let same_sender = |(key, message): &(&SortKey, &Message)| {
+            &message.sender.identifier == sender && !self.is_hidden(key, message)
+        };
+        let found = if forward {
+            self.messages
+                .range((Bound::Excluded(cursor), Bound::Unbounded))
+                .find(same_sender)
+        } else {
+            self.messages.range(..cursor).rev().find(same_sender)
+        };
+        match found {
+            Some((key, _)) => {
+                self.cursor = Some(key.clone());
+                self.spotlight_selected();
+                true
+            }
+            None => false,
+        }
     }
 
     pub fn insert(&mut self, message: Message) {
-        self.messages.insert(message.key(), message);
+        self.insert_unfollowed(message);
+        if self.follow {
+            self.cursor = self.last_shown();
+        }
         self.dirty = true;
     }
 
+    /// Inserts many messages at once, only moving the cursor (in follow mode) once at the end.
+    pub fn extend(&mut self, messages: impl IntoIterator<Item = Message>) {
+        for message in messages {
+            self.insert_unfollowed(message);
+        }
+        if self.follow {
+            self.cursor = self.last_shown();
+        }
+        self.dirty = true;
+    }
+
+    /// Whether the message is an edit of one which is already present, i.e. it has the same
+    /// identifier but a different key.
+    pub fn is_edit(&self, message: &Message) -> bool {
+        self.identifiers
+            .get(&service_identifier(&message.key))
+            .is_some_and(|key| *key != message.key)
+    }
+
+    fn insert_unfollowed(&mut self, message: Message) {
+        let message = parse_quotes(message);
+        if self.is_edit(&message) {
+            self.edit(message);
+            return;
+        }
+        // a message which is already present keeps its place, even if it was received again, so
+        // that the cursor and read markers still point at it
+        let old_time = self.sort_times.get(&message.key).copied();
+        let time = old_time.unwrap_or(match self.order {
+            MessageOrder::SenderTimestamp => message.key.timestamp,
+            MessageOrder::ReceivedOrder => message.received_at.unwrap_or(message.key.timestamp),
+        });
+        self.sort_times.insert(message.key(), time);
+        if let Some(old_time) = old_time {
+            // the message was already present, so make sure to replace it
+            let old_key = (old_time, message.key());
+            if let Some(old) = self.messages.remove(&old_key) {
+                self.unindex(&old_key, old.reply_to.as_ref());
+            }
+        }
+        self.notify(match old_time {
+            Some(_) => MessageEvent::Replaced(message.key()),
+            None => MessageEvent::Inserted(message.key()),
+        });
+        let key = (time, message.key());
+        self.identifiers
+            .insert(service_identifier(&message.key), message.key());
+        if let Some(parent) = &message.reply_to {
+            self.replies
+                .entry((message.key.service, parent.clone()))
+                .or_default()
+                .insert(key.clone());
+        }
+        self.messages.insert(key, message);
+    }
+
+    /// Replaces the body of the message with the same identifier, keeping its place in the list.
+    /// Edits of unknown messages, or which are older than the version already shown, are
+    /// ignored.
+    pub fn edit(&mut self, edit: Message) {
+        let Some(key) = self.identifiers.get(&service_identifier(&edit.key)) else {
+            return;
+        };
+        let Some(time) = self.sort_times.get(key) else {
+            return;
+        };
+        let Some(message) = self.messages.get_mut(&(*time, key.clone())) else {
+            return;
+        };
+        let edited_at = edit.edited_at.unwrap_or(edit.key.timestamp);
+        if edited_at < message.edited_at.unwrap_or(message.key.timestamp) {
+            return;
+        }
+        message.body = parse_quotes(edit).body;
+        message.edited_at = Some(edited_at);
+        let key = message.key();
+        self.notify(MessageEvent::Replaced(key));
+        self.dirty = true;
+    }
+
+    /// Adds the user's reaction to a message, or removes it if they have already reacted with
+    /// that emoji.
+    pub fn react(&mut self, key: &MessageKey, emoji: Arc<str>, user: User) {
+        let Some(time) = self.sort_times.get(key) else {
+            return;
+        };
+        let Some(message) = self.messages.get_mut(&(*time, key.clone())) else {
+            return;
+        };
+        let reactors = message.reactions.entry(emoji.clone()).or_default();
+        match reactors
+            .iter()
+            .position(|reactor| reactor.identifier == user.identifier)
+        {
+            Some(i) => {
+                reactors.remove(i);
+                if reactors.is_empty() {
+                    message.reactions.remove(&emoji);
+                }
+            }
+            None => reactors.push(user),
+        }
+        self.notify(MessageEvent::Replaced(key.clone()));
+        self.dirty = true;
+    }
+
+    /// Returns a receiver for every change to the messages from now on. Nothing is sent until
+    /// something has subscribed.
+    #[allow(dead_code, reason = "nothing in the TUI follows the changes yet")]
+    pub fn subscribe(&mut self) -> broadcast::Receiver<MessageEvent> {
+        self.events
+            .get_or_insert_with(|| broadcast::Sender::new(EVENT_CAPACITY))
+            .subscribe()
+    }
+
+    fn notify(&self, event: MessageEvent) {
+        if let Some(events) = &self.events {
+            // the subscribers may have gone away since
+            let _ = events.send(event);
+        }
+    }
+
+    /// Removes a message which is no longer present from the indices.
+    fn unindex(&mut self, key: &SortKey, reply_to: Option<&Arc<str>>) {
+        let (_, message_key) = key;
+        let identifier = service_identifier(message_key);
+        if self.identifiers.get(&identifier) == Some(message_key) {
+            self.identifiers.remove(&identifier);
+        }
+        if let Some(parent) = reply_to {
+            let parent = (message_key.service, parent.clone());
+            if let Some(replies) = self.replies.get_mut(&parent) {
+                replies.remove(key);
+                if replies.is_empty() {
+                    self.replies.remove(&parent);
+                }
+            }
+        }
+    }
+
+    /// Briefly highlights the selected message, so it is easy to find after a jump.
+    pub fn spotlight_selected(&mut self) {
+        self.spotlight = self
+            .cursor
+            .as_ref()
+            .map(|(_, key)| (key.clone(), Instant::now()));
+        self.dirty = true;
+    }
+
+    /// Switches between wrapping messages to the full width and to the reading width.
+    pub fn toggle_reading_mode(&mut self) {
+        self.reading_mode = !self.reading_mode;
+        self.dirty = true;
+    }
+
+    /// Marks the list to be re-rendered, e.g. after changing its settings.
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Re-renders the messages, e.g. to update their age fading, and clears an expired spotlight.
+    pub fn refresh(&mut self) {
+        if self
+            .spotlight
+            .as_ref()
+            .is_some_and(|(_, start)| start.elapsed() >= SPOTLIGHT_DURATION)
+        {
+            self.spotlight = None;
+            self.dirty = true;
+        }
+        if self.age_fade.is_some() || self.timestamp_format == TimestampFormat::Relative {
+            self.dirty = true;
+        }
+    }
+
+    /// Sets whether the cursor follows newly inserted messages. Enabling follow mode also moves
+    /// the cursor to the latest message.
+    pub fn set_follow(&mut self, follow: bool) {
+        self.follow = follow;
+        if follow {
+            self.cursor = self.last_shown();
+            self.dirty = true;
+        }
+    }
+
     pub fn delete(&mut self, message: &MessageKey) {
+        // a message deleted elsewhere can no longer be restored
+        self.undo_stack.retain(|deleted| deleted.key != *message);
+        let Some(time) = self.sort_times.remove(message) else {
+            return;
+        };
+        self.folded.remove(message);
+        let message = &(time, message.clone());
         // update the cursor if the message to be deleted is selected
         if self.cursor.as_ref() == Some(message) {
-            use std::ops::Bound;
-            self.cursor = self
-                .messages
-                // first try to move the cursor forwards
-                .range((Bound::Excluded(message), Bound::Unbounded))
-                .next()
-                // but if the cursor is already at the end, try moving backwards
-                .or_else(|| self.messages.range(..message).next_back())
-                .map(|(k, _)| k.clone())
-            // if that fails, the deleted message was the only one, so the cursor is now `None`
+            // if there are no others, the deleted message was the only one, so the cursor is now
+            // `None`
+            self.cursor = self.nearest_shown(message);
+        }
+        if let Some(removed) = self.messages.remove(message) {
+            self.unindex(message, removed.reply_to.as_ref());
+            self.notify(MessageEvent::Removed(message.1.clone()));
+        }
+        self.dirty = true;
+    }
+
+    /// Removes the messages for which `f` returns `false`. If the selected message is removed,
+    /// the cursor moves to the next remaining message, or the previous one if there are none
+    /// after it.
+    pub fn retain(&mut self, mut f: impl FnMut(&Message) -> bool) {
+        let mut removed = Vec::new();
+        self.messages.retain(|key, message| {
+            let keep = f(message);
+            if !keep {
+                removed.push((key.clone(), message.reply_to.clone()));
+            }
+            keep
+        });
+        for (key, reply_to) in &removed {
+            self.sort_times.remove(&key.1);
+            self.folded.remove(&key.1);
+            self.unindex(key, reply_to.as_ref());
+            self.notify(MessageEvent::Removed(key.1.clone()));
+        }
+        if let Some(cursor) = self
+            .cursor
+            .take_if(|cursor| !self.messages.contains_key(cursor))
+        {
+            self.cursor = self.nearest_shown(&cursor);
         }
-        self.messages.remove(message);
         self.dirty = true;
     }
 
@@ -93,53 +1017,1986 @@ impl MessageListView {
         self.cursor.as_ref().and_then(|key| self.messages.get(key))
     }
 
+    /// Deletes the selected message, remembering it so that the deletion can be undone.
     pub fn delete_selected(&mut self) {
-        if let Some(selected) = &self.cursor {
-            self.delete(&selected.clone());
+        let Some(cursor) = self.cursor.clone() else {
+            return;
+        };
+        let Some(message) = self.messages.get(&cursor).cloned() else {
+            return;
+        };
+        self.delete(&cursor.1);
+        if self.undo_stack.len() == UNDO_LIMIT {
+            self.undo_stack.pop_front();
+        }
+        self.undo_stack.push_back(message);
+    }
+
+    /// Restores the most recently deleted message and selects it. Messages which arrived in the
+    /// meantime are kept. Returns `false` if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        let Some(message) = self.undo_stack.pop_back() else {
+            return false;
+        };
+        let key = message.key();
+        self.insert_unfollowed(message);
+        self.cursor = self.sort_times.get(&key).map(|&time| (time, key));
+        self.keep_cursor_shown();
+        self.spotlight_selected();
+        true
+    }
+
+    /// The messages which match the filters, in display order.
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages
+            .values()
+            .filter(|message| self.is_listed(message))
+    }
+
+    /// Writes all the messages to a file, one JSON message per line in order. The file is only
+    /// replaced once they have all been written.
+    pub fn save_to(&self, path: &Path) -> std::io::Result<()> {
+        let partial = path.with_extension("partial");
+        let result = self
+            .write_messages(&partial)
+            .and_then(|()| std::fs::rename(&partial, path));
+        if result.is_err() {
+            // don't leave half a history behind; it may not even have been created
+            let _ = std::fs::remove_file(&partial);
+        }
+        result
+    }
+
+    fn write_messages(&self, path: &Path) -> std::io::Result<()> {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for message in self.messages.values() {
+            serde_json::to_writer(&mut writer, message)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+
+    /// Inserts the messages from a file written by [`save_to`](Self::save_to), returning how
+    /// many lines were skipped because they couldn't be read. A missing file has no messages.
+    pub fn load_from(&mut self, path: &Path) -> std::io::Result<usize> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut messages = Vec::new();
+        let mut skipped = 0;
+        for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+            match serde_json::from_slice::<Message>(line) {
+                Ok(message) => messages.push(message),
+                Err(_) if line.trim_ascii().is_empty() => {}
+                Err(err) => {
+                    tracing::warn!("{}:{}: invalid message: {err}", path.display(), i + 1);
+                    skipped += 1;
+                }
+            }
+        }
+        self.extend(messages);
+        Ok(skipped)
+    }
+
+    /// Formats the selected message as plain text.
+    pub fn selected_plain_text(&self) -> Option<String> {
+        self.selected()
+            .map(|message| message_to_plain_text(message, self.sender_display))
+    }
+
+    /// Describes a message in a single line to be read aloud, e.g. "alice in general: hi".
+    pub fn announcement(&self, message: &Message) -> String {
+        let body = plain_body(message).lines().collect::<Vec<_>>().join(" ");
+        format!(
+            "{} in {}: {body}",
+            sender_text(&message.sender, self.sender_display),
+            message.room.display_name
+        )
+    }
+
+    /// Formats the last `limit` messages as plain text, returning the text and the number of
+    /// messages included.
+    pub fn plain_text(&self, limit: usize) -> (String, usize) {
+        let messages = self.messages().collect::<Vec<_>>();
+        let messages = &messages[messages.len().saturating_sub(limit)..];
+        let text = messages
+            .iter()
+            .map(|message| message_to_plain_text(message, self.sender_display))
+            .collect::<Vec<_>>()
+            .join("\n");
+        (text, messages.len())
+    }
+
+    /// Summarizes all the messages.
+    pub fn stats(&self) -> ViewStats {
+        let mut per_room = BTreeMap::new();
+        let mut senders = BTreeSet::new();
+        for message in self.messages.values() {
+            *per_room
+                .entry(message.room.display_name.clone())
+                .or_default() += 1;
+            senders.insert(&message.sender.identifier);
+        }
+        let first = self.messages.keys().next().map(|(time, _)| *time);
+        let last = self.messages.keys().next_back().map(|(time, _)| *time);
+        ViewStats {
+            total: self.messages.len(),
+            per_room,
+            senders: senders.len(),
+            span: first.zip(last),
         }
     }
 
+    /// The distinct rooms of all messages, most recently active first, along with the number of
+    /// unread messages in each. Messages after the selected one count as unread.
+    pub fn room_summaries(&self) -> Vec<(Room, usize)> {
+        let mut rooms = Vec::<(Room, usize)>::new();
+        let mut indices = BTreeMap::new();
+        for (key, message) in self.messages.iter().rev() {
+            let index = *indices
+                .entry(message.room.identifier.clone())
+                .or_insert_with(|| {
+                    rooms.push((message.room.clone(), 0));
+                    rooms.len() - 1
+                });
+            if self
+                .last_read
+                .get(&message.room.identifier)
+                .is_none_or(|last_read| key > last_read)
+            {
+                rooms[index].1 += 1;
+            }
+        }
+        rooms
+    }
+
+    /// The distinct senders of all messages, most recently active first. Each sender has the
+    /// display name from their most recent message.
+    pub fn sender_directory(&self) -> Vec<User> {
+        let mut seen = BTreeSet::new();
+        self.messages
+            .values()
+            .rev()
+            .filter(|message| seen.insert(message.sender.identifier.clone()))
+            .map(|message| message.sender.clone())
+            .collect()
+    }
+
+    /// The first and last messages which were visible in the last render.
+    pub fn visible_range(&self) -> Option<(MessageKey, MessageKey)> {
+        self.visible.clone()
+    }
+
     fn redraw_list(&mut self) {
+        #[cfg(test)]
+        {
+            self.redraws += 1;
+        }
         let mut selected_idx = None;
-        let items = self
+        let mut prev_room = None;
+        let mut prev_period = None;
+        let now = self.clock.now();
+        let cursor = self.cursor.as_ref().map(|(_, key)| key);
+        // the message at the top of the screen stays there, so that messages inserted above it
+        // (e.g. arriving out of order) don't shift the view
+        let top = self.visible.as_ref().map(|(first, _)| first);
+        let mut top_idx = None;
+        let mut items = Vec::new();
+        self.item_keys.clear();
+        let reply_depths = if self.reply_tree {
+            reply_depths(&self.messages)
+        } else {
+            BTreeMap::new()
+        };
+        let compact = self
+            .compact_below
+            .is_some_and(|width| self.wrap_width < width);
+        let room_filter = &self.room_filter;
+        let collapsed_rooms = &self.collapsed_rooms;
+        let mut messages = self
             .messages
             .values()
-            .enumerate()
-            .map(|(idx, msg)| {
-                if Some(&msg.key) == self.cursor.as_ref() {
-                    selected_idx = Some(idx);
+            .filter(|message| room_matches(room_filter, message))
+            .peekable();
+        while let Some(msg) = messages.next() {
+            let mut run = vec![msg];
+            let same_room = |next: &&Message| next.room.identifier == msg.room.identifier;
+            let collapsed_room = self.group_rooms && collapsed_rooms.contains(&msg.room.identifier);
+            if collapsed_room {
+                run.extend(std::iter::from_fn(|| messages.next_if(same_room)));
+            } else if self.collapse_system && msg.body.is_system() {
+                run.extend(std::iter::from_fn(|| {
+                    messages.next_if(|next| {
+                        next.body.is_system() && (!self.group_rooms || same_room(next))
+                    })
+                }));
+            }
+            if run.iter().any(|msg| Some(&msg.key) == cursor) {
+                selected_idx = Some(items.len());
+            }
+            if run.iter().any(|msg| Some(&msg.key) == top) {
+                top_idx = Some(items.len());
+            }
+            let new_room = prev_room.replace(&msg.room.identifier) != Some(&msg.room.identifier);
+            // the group header already names the room
+            let room_chip = self.room_chips && !self.group_rooms && new_room;
+            let header = self.group_rooms && new_room;
+            let separator = self.date_separators.and_then(|granularity| {
+                let period =
+                    granularity.period_start(self.timestamp_format.date(msg.key.timestamp));
+                (prev_period.replace(period) != Some(period)).then(|| granularity.label(period))
+            });
+            let mut style = self.age_fade.map_or_else(Style::new, |fade| {
+                fade.style((now - msg.key.timestamp).to_std().unwrap_or_default())
+            });
+            if self
+                .spotlight
+                .as_ref()
+                .is_some_and(|(key, _)| run.iter().any(|msg| &msg.key == key))
+            {
+                style = style.black().on_yellow();
+            }
+            let depth = reply_depths
+                .get(&(msg.key.service, &*msg.key.identifier))
+                .copied()
+                .unwrap_or(0);
+            let indent = REPLY_INDENT.repeat(depth);
+            let width = self.wrap_width.saturating_sub(indent.len());
+            let text = match run.as_slice() {
+                run if collapsed_room => {
+                    Text::from(room_header(&msg.room, Some(run.len()), self.capabilities))
                 }
-                ListItem::new(message_to_text(msg))
-            })
-            .collect::<Vec<_>>();
+                [msg] => {
+                    let mut text = message_to_text(
+                        msg,
+                        room_chip,
+                        TextOptions {
+                            sender_avatar: self.sender_avatars,
+                            sender_display: self.sender_display,
+                            reaction_display: self.reaction_display,
+                        },
+                        &self
+                            .timestamp_format
+                            .format(msg.key.timestamp, now, compact),
+                        compact,
+                        &mut self.highlighter,
+                    );
+                    if self.own_user.as_ref() == Some(&msg.sender.identifier) {
+                        // in compact mode, this includes the body
+                        text.lines[0] = std::mem::take(&mut text.lines[0]).bold();
+                    }
+                    if self.folded.contains(&msg.key) && !compact {
+                        fold(&mut text, self.capabilities);
+                    }
+                    if msg.reply_to.is_some() && !compact {
+                        let preview = self.reply_preview(msg);
+                        let preview = elide_line(preview, width, self.capabilities.ellipsis());
+                        text.lines.insert(1, preview);
+                    }
+                    if let Some(query) = &self.search {
+                        for line in &mut text.lines {
+                            mark_matches(line, query, Style::new().reversed());
+                        }
+                    }
+                    text
+                }
+                run => Text::from(system_summary(run)),
+            };
+            let mut text = if compact {
+                Text::from_iter(
+                    text.lines
+                        .into_iter()
+                        .map(|line| elide_line(line, width, self.capabilities.ellipsis())),
+                )
+            } else {
+                wrap_text(text, width)
+            };
+            if depth > 0 {
+                for line in &mut text.lines {
+                    line.spans.insert(0, indent.clone().into());
+                }
+            }
+            if header && !collapsed_room {
+                text.lines
+                    .insert(0, room_header(&msg.room, None, self.capabilities));
+            }
+            if let Some(label) = separator {
+                let line = date_separator(&label, self.wrap_width, self.capabilities);
+                text.lines.insert(0, line);
+            }
+            self.capabilities.downgrade_text(&mut text);
+            items.push(ListItem::new(text).style(self.capabilities.style(style)));
+            self.item_keys.push(msg.key());
+        }
+        self.item_heights = items.iter().map(ListItem::height).collect();
         self.list_state.select(selected_idx);
+        if let Some(top_idx) = top_idx {
+            *self.list_state.offset_mut() = top_idx;
+        }
         self.list_items = std::mem::take(&mut self.list_items).items(items);
         self.dirty = false;
     }
+
+    /// A line quoting the start of the message `reply` replies to.
+    fn reply_preview(&self, reply: &Message) -> Line<'static> {
+        let marker = if self.capabilities.unicode {
+            "↪"
+        } else {
+            ">"
+        };
+        let parent = self
+            .reply_parent(reply)
+            .and_then(|key| self.sort_key(key))
+            .and_then(|key| self.messages.get(&key));
+        let preview = match parent {
+            Some(parent) => format!(
+                "{marker} {}: {}",
+                parent.sender.display_name,
+                plain_body(parent).lines().next().unwrap_or_default()
+            ),
+            None => format!("{marker} (original message unavailable)"),
+        };
+        Line::styled(preview, Style::new().dim().italic())
+    }
+
+    /// The index of the item showing the message, as of the last render. A message collapsed into
+    /// a system message summary is in the summary's item.
+    pub fn key_to_index(&self, key: &MessageKey) -> Option<usize> {
+        let sort_key = self.sort_key(key)?;
+        if !self.is_listed(&self.messages[&sort_key]) {
+            return None;
+        }
+        let (_, shown) = self
+            .messages
+            .range(..=&sort_key)
+            .rev()
+            .find(|(k, m)| !self.is_hidden(k, m))?;
+        self.item_keys.iter().position(|key| *key == shown.key)
+    }
+
+    /// The (first) message in the item at the index, as of the last render.
+    pub fn index_to_key(&self, index: usize) -> Option<MessageKey> {
+        self.item_keys.get(index).cloned()
+    }
+
+    /// Scrolls the list so that the selected message is in the middle, the next time it is
+    /// rendered.
+    pub fn center_selected(&mut self) {
+        self.center = true;
+    }
+
+    /// Moves the cursor and the view down (or up) by a page, or half a page, of the list as last
+    /// rendered. Returns `false` if the selection didn't move.
+    pub fn scroll_page(&mut self, forward: bool, half: bool) -> bool {
+        let height = usize::from(self.list_area.height);
+        let rows = if half { height / 2 } else { height }.max(1);
+        let Some(index) = self
+            .cursor
+            .as_ref()
+            .and_then(|(_, key)| self.key_to_index(key))
+        else {
+            return if forward {
+                self.select_next()
+            } else {
+                self.select_prev()
+            };
+        };
+        let target = self.step_items(index, rows, forward);
+        let Some(cursor) = self
+            .index_to_key(target)
+            .and_then(|key| self.sort_key(&key))
+        else {
+            return false;
+        };
+        if target == index {
+            return false;
+        }
+        // the last page is kept full, rather than scrolling past the last message
+        let offset = self
+            .step_items(self.list_state.offset(), rows, forward)
+            .min(self.step_items(self.item_heights.len(), height, false));
+        *self.list_state.offset_mut() = offset;
+        // the top of the view is kept when the list is redrawn
+        self.visible = self.index_to_key(offset).map(|top| (top.clone(), top));
+        self.cursor = Some(cursor);
+        self.dirty = true;
+        true
+    }
+
+    /// The (first) message shown at a position on the screen, as of the last render.
+    pub fn key_at(&self, column: u16, row: u16) -> Option<MessageKey> {
+        if !self.list_area.contains((column, row).into()) {
+            return None;
+        }
+        let mut top = self.list_area.y;
+        for (index, &height) in self
+            .item_heights
+            .iter()
+            .enumerate()
+            .skip(self.list_state.offset())
+        {
+            top = top.saturating_add(height.try_into().unwrap_or(u16::MAX));
+            if row < top {
+                return self.index_to_key(index);
+            }
+        }
+        None
+    }
+
+    /// The index of the item `rows` rows after (or before) the one at `from`, moving at least one
+    /// item if there is one.
+    fn step_items(&self, from: usize, rows: usize, forward: bool) -> usize {
+        let mut index = from;
+        let mut moved = 0;
+        loop {
+            let height = match forward {
+                true if index + 1 < self.item_heights.len() => self.item_heights[index],
+                false if index > 0 => self.item_heights[index - 1],
+                _ => break,
+            };
+            if moved > 0 && moved + height > rows {
+                break;
+            }
+            moved += height;
+            if forward {
+                index += 1;
+            } else {
+                index -= 1;
+            }
+        }
+        index
+    }
+
+    fn scroll_to_center(&mut self, height: usize) {
+        let Some(index) = self
+            .cursor
+            .as_ref()
+            .and_then(|(_, key)| self.key_to_index(key))
+        else {
+            return;
+        };
+        let mut above = height.saturating_sub(self.item_heights[index]) / 2;
+        let mut first = index;
+        while first > 0 && self.item_heights[first - 1] <= above {
+            first -= 1;
+            above -= self.item_heights[first];
+        }
+        *self.list_state.offset_mut() = first;
+    }
+
+    fn update_visible_range(&mut self, height: usize) {
+        let first = self.list_state.offset();
+        let mut total_height = 0;
+        let count = self
+            .item_heights
+            .iter()
+            .skip(first)
+            .take_while(|item_height| {
+                total_height += *item_height;
+                total_height <= height
+            })
+            .count()
+            // the first item is always rendered, even if it doesn't fit
+            .max(1);
+        let last = (first + count).min(self.item_keys.len()).saturating_sub(1);
+        self.visible = self.index_to_key(first).map(|first_key| {
+            let last_key = self.index_to_key(last).unwrap_or_else(|| first_key.clone());
+            (first_key, last_key)
+        });
+    }
 }
 
 impl Widget for &mut MessageListView {
-    fn render(self, area: Rect, buffer: &mut Buffer) {
+    fn render(self, mut area: Rect, buffer: &mut Buffer) {
+        let symbol_width = HIGHLIGHT_SYMBOL.len() as u16;
+        if self.reading_mode {
+            let width = area
+                .width
+                .min(self.reading_width.saturating_add(symbol_width));
+            area.x += (area.width - width) / 2;
+            area.width = width;
+        }
+        let wrap_width = area.width.saturating_sub(symbol_width).into();
+        if wrap_width != self.wrap_width {
+            self.wrap_width = wrap_width;
+            self.dirty = true;
+        }
         if self.dirty {
             self.redraw_list();
         }
+        if std::mem::take(&mut self.center) {
+            self.scroll_to_center(area.height.into());
+        }
         StatefulWidget::render(&self.list_items, area, buffer, &mut self.list_state);
+        self.list_area = area;
+        self.update_visible_range(area.height.into());
     }
 }
 
-fn message_to_text(message: &Message) -> Text<'static> {
-    // TODO: configuration
-    let header = Line::raw(format!(
-        "{time} / {room} / {sender} ({sender_id})",
-        time = message.key.timestamp,
-        // TODO: spaces, threads, replies
-        room = message.room.display_name,
-        sender = message.sender.display_name,
-        sender_id = message.sender.identifier,
-    ));
-    let body = match &message.body {
-        // TODO: wrapping
-        MessageBody::Text(RichText(text)) => Line::raw(text.to_string()),
+/// A rule across the given width, with the label in the middle.
+fn date_separator(label: &str, width: usize, capabilities: Capabilities) -> Line<'static> {
+    let rule = if capabilities.unicode { "─" } else { "-" };
+    let label = format!(" {label} ");
+    let fill = width.saturating_sub(label.width());
+    let left = fill / 2;
+    Line::raw(format!(
+        "{}{label}{}",
+        rule.repeat(left),
+        rule.repeat(fill - left)
+    ))
+    .dim()
+}
+
+/// The message header, with the time it was sent formatted as `time`.
+fn header_text(message: &Message, sender_display: SenderDisplay, time: &str) -> String {
+    format!(
+        "{}{}",
+        header_prefix(message, time),
+        sender_text(&message.sender, sender_display)
+    )
+}
+
+/// The part of the header before the sender.
+fn header_prefix(message: &Message, time: &str) -> String {
+    // replies are shown by indenting them under their parent instead, and spaces and threads
+    // aren't part of the model yet
+    format!("{time} / {room} / ", room = message.room.display_name)
+}
+
+/// A color for the sender, which is always the same for the same identifier.
+///
+/// The colors are ones which are readable both as text on a dark background and as a background
+/// behind black text, so dark blue is left out.
+pub fn color_for_sender(identifier: &str) -> Color {
+    const PALETTE: [Color; 11] = [
+        Color::Red,
+        Color::Green,
+        Color::Yellow,
+        Color::Magenta,
+        Color::Cyan,
+        Color::LightRed,
+        Color::LightGreen,
+        Color::LightYellow,
+        Color::LightBlue,
+        Color::LightMagenta,
+        Color::LightCyan,
+    ];
+    // FNV-1a, since the standard library's hashers may change between releases
+    let hash = identifier
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    PALETTE[(hash % PALETTE.len() as u64) as usize]
+}
+
+/// A badge with the sender's initial (the first grapheme of their name), in their color.
+fn sender_badge(sender: &User) -> Span<'static> {
+    let name = if sender.display_name.is_empty() {
+        &sender.identifier
+    } else {
+        &sender.display_name
     };
-    Text::from(vec![header, body])
+    let initial = name.graphemes(true).next().unwrap_or("?").to_uppercase();
+    Span::styled(
+        format!(" {initial} "),
+        Style::new()
+            .fg(Color::Black)
+            .bg(color_for_sender(&sender.identifier)),
+    )
+}
+
+/// The sender as shown in the header, in their color.
+fn sender_span(sender: &User, sender_display: SenderDisplay) -> Span<'static> {
+    Span::styled(
+        sender_text(sender, sender_display),
+        Style::new().fg(color_for_sender(&sender.identifier)),
+    )
+}
+
+fn sender_text(sender: &User, sender_display: SenderDisplay) -> String {
+    match sender_display {
+        SenderDisplay::NameOnly => sender.display_name.to_string(),
+        SenderDisplay::IdOnly => sender.identifier.to_string(),
+        SenderDisplay::NameWithId => format!("{} ({})", sender.display_name, sender.identifier),
+    }
+}
+
+/// Formats a message as plain text, e.g. for copying, with the same header as in the list.
+fn message_to_plain_text(message: &Message, sender_display: SenderDisplay) -> String {
+    format!(
+        "{}\n{}\n",
+        header_text(message, sender_display, &message.key.timestamp.to_string()),
+        plain_body(message)
+    )
+}
+
+/// The body of a message as plain text, with system events and attachments described.
+fn plain_body(message: &Message) -> String {
+    match &message.body {
+        MessageBody::Text(text) => text.plain_text().into_owned(),
+        MessageBody::System(event) => describe_system_event(event, &[&message.sender.display_name]),
+        MessageBody::File(attachment) => describe_attachment("file", attachment),
+        MessageBody::Image(attachment) => describe_attachment("image", attachment),
+    }
+}
+
+/// How messages are shown, which is the same for every message in the list.
+#[derive(Clone, Copy, Debug)]
+struct TextOptions {
+    sender_avatar: bool,
+    sender_display: SenderDisplay,
+    reaction_display: ReactionDisplay,
+}
+
+/// Lays out a message as a header line followed by its body, or all on one line if `compact`.
+/// The time it was sent is formatted as `time`, which should be just the time of day if
+/// `compact`.
+fn message_to_text(
+    message: &Message,
+    room_chip: bool,
+    TextOptions {
+        sender_avatar,
+        sender_display,
+        reaction_display,
+    }: TextOptions,
+    time: &str,
+    compact: bool,
+    highlighter: &mut Highlighter,
+) -> Text<'static> {
+    let mut header = Line::default();
+    if room_chip {
+        header.push_span(Span::styled(
+            format!(" {} ", message.room.display_name),
+            Style::new().reversed(),
+        ));
+        header.push_span(" ");
+    }
+    if compact {
+        // the room is left out, since it takes up too much room
+        header.push_span(format!("{time} "));
+        if sender_avatar {
+            header.push_span(sender_badge(&message.sender));
+            header.push_span(" ");
+        }
+        header.push_span(sender_span(&message.sender, sender_display));
+    } else {
+        header.push_span(header_prefix(message, time));
+        if sender_avatar {
+            header.push_span(sender_badge(&message.sender));
+            header.push_span(" ");
+        }
+        header.push_span(sender_span(&message.sender, sender_display));
+    }
+    if message.edited_at.is_some() {
+        header.push_span(Span::styled(" (edited)", Style::new().dim()));
+    }
+    let mut body = match &message.body {
+        MessageBody::Text(text) => highlighter.body_lines(text),
+        MessageBody::System(event) => vec![Line::styled(
+            describe_system_event(event, &[&message.sender.display_name]),
+            Style::new().dim().italic(),
+        )],
+        MessageBody::File(attachment) => vec![Line::styled(
+            describe_attachment("file", attachment),
+            Style::new().underlined(),
+        )],
+        MessageBody::Image(attachment) => vec![Line::styled(
+            describe_attachment("image", attachment),
+            Style::new().underlined(),
+        )],
+    };
+    match (reaction_display, reactions_line(&message.reactions)) {
+        (ReactionDisplay::Inline, Some(reactions)) => match body.last_mut() {
+            Some(line) => {
+                line.push_span(" ");
+                line.spans
+                    .extend(reactions.spans.into_iter().map(|span| span.dim()));
+            }
+            None => body.push(reactions),
+        },
+        (ReactionDisplay::SeparateLine, Some(reactions)) => body.push(reactions),
+        (_, _) => {}
+    }
+    if compact {
+        // the body follows the header on the same line, keeping the styles of its lines
+        header.push_span(": ");
+        for (i, line) in body.into_iter().enumerate() {
+            if i > 0 {
+                header.push_span(" ");
+            }
+            let line_style = line.style;
+            header.spans.extend(
+                line.spans
+                    .into_iter()
+                    .map(|span| Span::styled(span.content, line_style.patch(span.style))),
+            );
+        }
+        return Text::from(header);
+    }
+    Text::from_iter(std::iter::once(header).chain(body))
+}
+
+/// Finds the blockquotes in the text of a message, so that it isn't done each time it's drawn.
+fn parse_quotes(mut message: Message) -> Message {
+    if let MessageBody::Text(text) = &mut message.body {
+        *text = std::mem::take(text).parse_quotes();
+    }
+    message
+}
+
+/// Summarizes the reactions to a message, e.g. `👍 3  ❤️ 1`.
+fn reactions_line(reactions: &BTreeMap<Arc<str>, Vec<User>>) -> Option<Line<'static>> {
+    let summary = reactions
+        .iter()
+        .filter(|(_, reactors)| !reactors.is_empty())
+        .map(|(emoji, reactors)| format!("{emoji} {}", reactors.len()))
+        .collect::<Vec<_>>();
+    if summary.is_empty() {
+        None
+    } else {
+        Some(Line::styled(summary.join("  "), Style::new().dim()))
+    }
+}
+
+/// Describes an attachment, e.g. `[file: notes.txt, 1234 bytes]`.
+fn describe_attachment(kind: &str, attachment: &Attachment) -> String {
+    match attachment.size {
+        Some(size) => format!("[{kind}: {}, {size} bytes]", attachment.filename),
+        None => format!("[{kind}: {}]", attachment.filename),
+    }
+}
+
+/// How deeply each reply is nested, by service and message identifier, up to
+/// [`MAX_REPLY_DEPTH`]. Replies to messages which aren't loaded are nested one level deep.
+fn reply_depths(messages: &BTreeMap<SortKey, Message>) -> BTreeMap<(ServiceType, &str), usize> {
+    let parents = messages
+        .values()
+        .map(|message| {
+            let key = (message.key.service, &*message.key.identifier);
+            (key, message.reply_to.as_deref())
+        })
+        .collect::<BTreeMap<_, _>>();
+    parents
+        .iter()
+        .filter_map(|(&identifier, &parent)| {
+            let (service, _) = identifier;
+            let mut parent = parent?;
+            let mut depth = 1;
+            while depth < MAX_REPLY_DEPTH {
+                match parents.get(&(service, parent)) {
+                    Some(Some(grandparent)) => {
+                        parent = grandparent;
+                        depth += 1;
+                    }
+                    _ => break,
+                }
+            }
+            Some((identifier, depth))
+        })
+        .collect()
+}
+
+/// Whether a message's text contains `query`, which should be lowercase.
+fn matches_query(message: &Message, query: &str) -> bool {
+    message.body.plain_text().to_lowercase().contains(query)
+}
+
+fn room_matches(room_filter: &Option<Arc<str>>, message: &Message) -> bool {
+    room_filter
+        .as_ref()
+        .is_none_or(|room| *room == message.room.identifier)
+}
+
+/// Folds a rendered message to its header and first line of the body.
+fn fold(text: &mut Text<'static>, capabilities: Capabilities) {
+    text.lines.truncate(2);
+    if let Some(line) = text.lines.last_mut() {
+        line.push_span(Span::styled(
+            format!(" {} [folded]", capabilities.ellipsis()),
+            Style::new().dim(),
+        ));
+    }
+}
+
+/// The header of a group of messages from a room. A collapsed group's header counts its messages.
+fn room_header(room: &Room, collapsed: Option<usize>, capabilities: Capabilities) -> Line<'static> {
+    let (expanded_marker, collapsed_marker) = if capabilities.unicode {
+        ("▾", "▸")
+    } else {
+        ("v", ">")
+    };
+    let header = match collapsed {
+        Some(1) => format!("{collapsed_marker} {} (1 message)", room.display_name),
+        Some(count) => format!(
+            "{collapsed_marker} {} ({count} messages)",
+            room.display_name
+        ),
+        None => format!("{expanded_marker} {}", room.display_name),
+    };
+    Line::styled(header, Style::new().bold())
+}
+
+/// Summarizes a run of system messages in one line, e.g. "alice and bob joined, charlie left".
+fn system_summary(messages: &[&Message]) -> Line<'static> {
+    let mut events = BTreeMap::<_, Vec<&str>>::new();
+    for message in messages {
+        if let MessageBody::System(event) = &message.body {
+            events
+                .entry(event)
+                .or_default()
+                .push(&message.sender.display_name);
+        }
+    }
+    let summary = events
+        .into_iter()
+        .map(|(event, users)| describe_system_event(event, &users))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Line::styled(summary, Style::new().dim().italic())
+}
+
+fn describe_system_event(event: &SystemEvent, users: &[&str]) -> String {
+    /// Above this many users, they are counted rather than named.
+    const MAX_NAMES: usize = 3;
+    let users = match users {
+        [user] => user.to_string(),
+        [init @ .., last] if users.len() <= MAX_NAMES => format!("{} and {last}", init.join(", ")),
+        _ => format!("{} users", users.len()),
+    };
+    match event {
+        SystemEvent::Joined => format!("{users} joined"),
+        SystemEvent::Left => format!("{users} left"),
+        SystemEvent::Other(text) => format!("{users}: {text}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use carrier_pigeon_common::{RichText, ServiceType};
+
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            display_name: name.into(),
+            identifier: format!("@{name}:example.com").into(),
+            service: ServiceType::Fake,
+        }
+    }
+
+    fn room(name: &str) -> Room {
+        Room {
+            display_name: name.into(),
+            identifier: format!("!{name}:example.com").into(),
+            service: ServiceType::Fake,
+        }
+    }
+
+    /// A text message sent `seconds` after the epoch.
+    fn message(
+        identifier: &str,
+        room_name: &str,
+        sender: &str,
+        seconds: i64,
+        text: &str,
+    ) -> Message {
+        Message {
+            key: MessageKey {
+                timestamp: DateTime::from_timestamp(seconds, 0).unwrap(),
+                identifier: identifier.into(),
+                service: ServiceType::Fake,
+            },
+            sender: user(sender),
+            room: room(room_name),
+            reply_to: None,
+            body: MessageBody::Text(RichText::from_plain(text)),
+            received_at: None,
+            edited_at: None,
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    fn line_text(line: &Line) -> String {
+        line.spans.iter().map(|span| &*span.content).collect()
+    }
+
+    fn text_lines(text: &Text) -> Vec<String> {
+        text.lines.iter().map(line_text).collect()
+    }
+
+    fn render_text(message: &Message, reaction_display: ReactionDisplay) -> Vec<String> {
+        let options = TextOptions {
+            sender_avatar: false,
+            sender_display: SenderDisplay::NameOnly,
+            reaction_display,
+        };
+        let text = message_to_text(
+            message,
+            false,
+            options,
+            "12:00",
+            false,
+            &mut Highlighter::default(),
+        );
+        text_lines(&text)
+    }
+
+    fn with_two_reactions() -> Message {
+        let mut message = message("a", "general", "alice", 0, "nice");
+        message
+            .reactions
+            .insert("👍".into(), vec![user("bob"), user("charlie")]);
+        message.reactions.insert("🎉".into(), vec![user("dana")]);
+        message
+    }
+
+    fn reply(identifier: &str, seconds: i64, parent: &str) -> Message {
+        Message {
+            reply_to: Some(parent.into()),
+            ..message(identifier, "general", "bob", seconds, "reply")
+        }
+    }
+
+    fn selected_identifier(view: &MessageListView) -> Option<&str> {
+        view.selected().map(|message| &*message.key.identifier)
+    }
+
+    #[test]
+    fn goto_parent() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("parent", "general", "alice", 0, "question"),
+            message("other", "random", "alice", 1, "unrelated"),
+            reply("child", 2, "parent"),
+        ]);
+        view.select_identifier("child");
+        assert!(view.goto("parent"));
+        assert_eq!(selected_identifier(&view), Some("parent"));
+    }
+
+    #[test]
+    fn goto_hidden_parent() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("parent", "random", "alice", 0, "question"),
+            Message {
+                room: room("random"),
+                ..reply("child", 1, "parent")
+            },
+            message("other", "general", "alice", 2, "unrelated"),
+        ]);
+        view.select_identifier("other");
+        view.set_room_filter(Some(room("general").identifier));
+        assert_eq!(view.is_shown("parent"), Some(false));
+        assert_eq!(view.is_shown("missing"), None);
+        assert!(!view.goto("parent"));
+        assert_eq!(selected_identifier(&view), Some("other"));
+    }
+
+    #[test]
+    fn goto_collapsed_system_message() {
+        let mut view = MessageListView::default();
+        let system = |identifier, seconds| Message {
+            body: MessageBody::System(SystemEvent::Joined),
+            ..message(identifier, "general", "alice", seconds, "")
+        };
+        view.extend([system("first", 0), system("second", 1)]);
+        view.toggle_collapse_system();
+        assert_eq!(view.is_shown("first"), Some(true));
+        assert_eq!(view.is_shown("second"), Some(false));
+        assert!(!view.goto("second"));
+    }
+
+    #[test]
+    fn cycle_replies() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("parent", "general", "alice", 0, "question"),
+            reply("first", 1, "parent"),
+            message("other", "general", "alice", 2, "unrelated"),
+            reply("second", 3, "parent"),
+        ]);
+        view.select_identifier("parent");
+        assert!(view.select_next_reply());
+        assert_eq!(selected_identifier(&view), Some("first"));
+        assert!(view.select_next_reply());
+        assert_eq!(selected_identifier(&view), Some("second"));
+        assert!(view.select_next_reply());
+        assert_eq!(selected_identifier(&view), Some("first"));
+        view.select_identifier("other");
+        assert!(!view.select_next_reply());
+    }
+
+    #[test]
+    fn reactions_inline() {
+        assert_eq!(
+            render_text(&with_two_reactions(), ReactionDisplay::Inline),
+            ["12:00 / general / alice", "nice 🎉 1  👍 2"],
+        );
+    }
+
+    #[test]
+    fn reactions_separate_line() {
+        assert_eq!(
+            render_text(&with_two_reactions(), ReactionDisplay::SeparateLine),
+            ["12:00 / general / alice", "nice", "🎉 1  👍 2"],
+        );
+    }
+
+    #[test]
+    fn reactions_hidden() {
+        assert_eq!(
+            render_text(&with_two_reactions(), ReactionDisplay::Hidden),
+            ["12:00 / general / alice", "nice"],
+        );
+    }
+
+    #[test]
+    fn change_events() {
+        let mut list = MessageListView::default();
+        // changes before subscribing aren't sent
+        list.insert(message("a", "general", "alice", 0, "hello"));
+        let mut events = list.subscribe();
+        let b = message("b", "general", "bob", 1, "hi");
+        let key = b.key();
+        list.insert(b.clone());
+        list.insert(b);
+        list.insert(message("b", "general", "bob", 5, "hi (edited)"));
+        list.react(&key, "👍".into(), user("alice"));
+        list.delete(&key);
+        let received = std::iter::from_fn(|| events.try_recv().ok()).collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            [
+                MessageEvent::Inserted(key.clone()),
+                MessageEvent::Replaced(key.clone()),
+                MessageEvent::Replaced(key.clone()),
+                MessageEvent::Replaced(key.clone()),
+                MessageEvent::Removed(key),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_sender_jumps() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a1", "general", "alice", 0, "one"),
+            message("b1", "general", "bob", 1, "two"),
+            message("b2", "general", "bob", 2, "three"),
+            message("a2", "general", "alice", 3, "four"),
+            message("b3", "general", "bob", 4, "five"),
+        ]);
+        view.select_identifier("a1");
+        assert!(view.select_same_sender(true));
+        assert_eq!(selected_identifier(&view), Some("a2"));
+        // there are no more from alice, so the selection stays
+        assert!(!view.select_same_sender(true));
+        assert_eq!(selected_identifier(&view), Some("a2"));
+        assert!(view.select_same_sender(false));
+        assert_eq!(selected_identifier(&view), Some("a1"));
+        assert!(!view.select_same_sender(false));
+        view.select_identifier("b3");
+        assert!(view.select_same_sender(false));
+        assert_eq!(selected_identifier(&view), Some("b2"));
+    }
+
+    #[test]
+    fn same_sender_jumps_skip_filtered() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a1", "general", "alice", 0, "one"),
+            message("a2", "random", "alice", 1, "two"),
+            message("a3", "general", "alice", 2, "three"),
+        ]);
+        view.select_identifier("a1");
+        view.set_room_filter(Some(room("general").identifier));
+        assert!(view.select_same_sender(true));
+        assert_eq!(selected_identifier(&view), Some("a3"));
+    }
+
+    #[test]
+    fn same_sender_jump_without_selection() {
+        let mut view = MessageListView::default();
+        assert!(!view.select_same_sender(true));
+    }
+
+    /// Renders the view, returning each row without trailing whitespace.
+    fn render_rows(view: &mut MessageListView, width: u16, height: u16) -> Vec<String> {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, width, height));
+        view.render(buffer.area, &mut buffer);
+        (0..height)
+            .map(|y| {
+                (0..width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+                    .trim_end()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn room_chips_on_room_changes() {
+        let mut view = MessageListView {
+            room_chips: true,
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.extend([
+            message("a", "general", "alice", 0, "one"),
+            message("b", "general", "bob", 1, "two"),
+            message("c", "random", "alice", 2, "three"),
+            message("d", "general", "bob", 3, "four"),
+        ]);
+        let headers = render_rows(&mut view, 60, 8)
+            .into_iter()
+            .filter(|row| row.contains(" UTC / "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            [
+                " general  1970-01-01 00:00:00 UTC / general / alice",
+                "1970-01-01 00:00:01 UTC / general / bob",
+                " random  1970-01-01 00:00:02 UTC / random / alice",
+                " general  1970-01-01 00:00:03 UTC / general / bob",
+            ]
+        );
+    }
+
+    #[test]
+    fn visible_range_after_render() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        assert_eq!(view.visible_range(), None);
+        view.extend((0..10).map(|i| message(&format!("m{i}"), "general", "alice", i, "hi")));
+        view.select_identifier("m9");
+        // each message is a header and a line of text
+        render_rows(&mut view, 60, 6);
+        let (first, last) = view.visible_range().unwrap();
+        assert_eq!((&*first.identifier, &*last.identifier), ("m7", "m9"));
+        view.select_identifier("m0");
+        render_rows(&mut view, 60, 6);
+        let (first, last) = view.visible_range().unwrap();
+        assert_eq!((&*first.identifier, &*last.identifier), ("m0", "m2"));
+    }
+
+    #[test]
+    fn sender_directory_has_latest_names() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a", "general", "alice", 0, "hi"),
+            message("b", "general", "bob", 1, "hello"),
+            Message {
+                sender: User {
+                    display_name: "Alice".into(),
+                    ..user("alice")
+                },
+                ..message("c", "general", "alice", 2, "I changed my name")
+            },
+        ]);
+        let names = view
+            .sender_directory()
+            .into_iter()
+            .map(|user| user.display_name.to_string())
+            .collect::<Vec<_>>();
+        // the most recently active first
+        assert_eq!(names, ["Alice", "bob"]);
+    }
+
+    /// Two messages whose senders' clocks disagree: `b` arrived second, but claims to be older.
+    fn skewed_messages() -> [Message; 2] {
+        let received = |message: Message, seconds| Message {
+            received_at: DateTime::from_timestamp(seconds, 0),
+            ..message
+        };
+        [
+            received(message("a", "general", "alice", 10, "first"), 20),
+            received(message("b", "general", "bob", 5, "second"), 21),
+        ]
+    }
+
+    fn identifiers(view: &MessageListView) -> Vec<String> {
+        view.messages()
+            .map(|message| message.key.identifier.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sender_timestamp_order() {
+        let mut view = MessageListView::default();
+        view.extend(skewed_messages());
+        assert_eq!(identifiers(&view), ["b", "a"]);
+    }
+
+    #[test]
+    fn received_order() {
+        let mut view = MessageListView {
+            order: MessageOrder::ReceivedOrder,
+            ..MessageListView::default()
+        };
+        view.extend(skewed_messages());
+        assert_eq!(identifiers(&view), ["a", "b"]);
+    }
+
+    #[test]
+    fn spotlight_after_jump() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a", "general", "alice", 0, "first"),
+            message("b", "general", "bob", 1, "second"),
+        ]);
+        assert!(view.goto("a"));
+        let spotlight = |view: &MessageListView| {
+            view.spotlight
+                .as_ref()
+                .map(|(key, _)| key.identifier.to_string())
+        };
+        assert_eq!(spotlight(&view).as_deref(), Some("a"));
+        view.refresh();
+        assert_eq!(spotlight(&view).as_deref(), Some("a"));
+        // as if the jump was a while ago
+        if let Some((_, start)) = &mut view.spotlight {
+            *start -= SPOTLIGHT_DURATION;
+        }
+        view.refresh();
+        assert_eq!(spotlight(&view), None);
+    }
+
+    #[test]
+    fn collapsed_system_messages() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        let joined = |identifier, sender, seconds| Message {
+            body: MessageBody::System(SystemEvent::Joined),
+            ..message(identifier, "general", sender, seconds, "")
+        };
+        view.extend([
+            joined("a", "alice", 0),
+            joined("b", "bob", 1),
+            joined("c", "charlie", 2),
+        ]);
+        assert!(render_rows(&mut view, 40, 4).contains(&"alice joined".to_owned()));
+        view.toggle_collapse_system();
+        assert_eq!(
+            render_rows(&mut view, 40, 4),
+            ["alice, bob and charlie joined", "", "", ""]
+        );
+        // with more users, they are counted
+        view.insert(joined("d", "dana", 3));
+        assert_eq!(render_rows(&mut view, 40, 1), ["4 users joined"]);
+        view.toggle_collapse_system();
+        assert!(render_rows(&mut view, 40, 4).contains(&"alice joined".to_owned()));
+    }
+
+    #[test]
+    fn older_message_keeps_selected_row() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.extend((0..10).map(|i| {
+            let identifier = format!("m{i}");
+            message(&identifier, "general", "alice", 10 + i, &identifier)
+        }));
+        // scroll to the bottom, then select a message in the middle of the screen
+        view.select_identifier("m9");
+        render_rows(&mut view, 40, 12);
+        view.select_identifier("m8");
+        let selected_row = |rows: &[String]| rows.iter().position(|row| row.starts_with("->"));
+        let before = render_rows(&mut view, 40, 12);
+        let row = selected_row(&before);
+        assert!(row.is_some(), "{before:#?}");
+        view.insert(message("old", "general", "bob", 0, "late"));
+        let after = render_rows(&mut view, 40, 12);
+        assert_eq!(selected_row(&after), row, "{before:#?}\n{after:#?}");
+        assert_eq!(selected_identifier(&view), Some("m8"));
+    }
+
+    #[test]
+    fn fold_selected() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.extend([message(
+            "a",
+            "general",
+            "alice",
+            0,
+            "line one\nline two\nline three",
+        )]);
+        view.select_first();
+        let unfolded = render_rows(&mut view, 40, 5);
+        assert_eq!(
+            unfolded[2..],
+            ["   line one", "   line two", "   line three"]
+        );
+        view.toggle_fold_selected();
+        assert_eq!(selected_identifier(&view), Some("a"));
+        let folded = render_rows(&mut view, 40, 5);
+        assert_eq!(folded[2..], ["   line one … [folded]", "", ""]);
+        view.toggle_fold_selected();
+        assert_eq!(render_rows(&mut view, 40, 5), unfolded);
+    }
+
+    #[test]
+    fn stats() {
+        let mut view = MessageListView::default();
+        assert_eq!(view.stats(), ViewStats::default());
+        view.extend([
+            message("a", "general", "alice", 0, "hi"),
+            message("b", "random", "bob", 60, "hello"),
+            message("c", "general", "bob", 30, "hey"),
+        ]);
+        let stats = view.stats();
+        assert_eq!(
+            stats,
+            ViewStats {
+                total: 3,
+                per_room: BTreeMap::from([("general".into(), 2), ("random".into(), 1)]),
+                senders: 2,
+                span: Some((
+                    DateTime::from_timestamp(0, 0).unwrap(),
+                    DateTime::from_timestamp(60, 0).unwrap()
+                )),
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "3 messages from 2 senders in 2 rooms, 1970-01-01 00:00:00 UTC to \
+             1970-01-01 00:01:00 UTC (general: 2, random: 1)"
+        );
+    }
+
+    #[test]
+    fn yank_all_text() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.extend([
+            message("a", "general", "alice", 0, "hi"),
+            message("b", "random", "bob", 60, "two\nlines"),
+        ]);
+        assert_eq!(
+            view.plain_text(10),
+            (
+                "1970-01-01 00:00:00 UTC / general / alice\nhi\n\n\
+                 1970-01-01 00:01:00 UTC / random / bob\ntwo\nlines\n"
+                    .to_owned(),
+                2
+            )
+        );
+        // only the last messages fit under the limit
+        assert_eq!(
+            view.plain_text(1),
+            (
+                "1970-01-01 00:01:00 UTC / random / bob\ntwo\nlines\n".to_owned(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn sender_display_variants() {
+        let message = message("a", "general", "alice", 0, "hi");
+        let header = |sender_display| {
+            let options = TextOptions {
+                sender_avatar: false,
+                sender_display,
+                reaction_display: ReactionDisplay::Inline,
+            };
+            let text = message_to_text(
+                &message,
+                false,
+                options,
+                "12:00",
+                false,
+                &mut Highlighter::default(),
+            );
+            text_lines(&text).swap_remove(0)
+        };
+        for (sender_display, expected) in [
+            (SenderDisplay::NameOnly, "alice"),
+            (SenderDisplay::IdOnly, "@alice:example.com"),
+            (SenderDisplay::NameWithId, "alice (@alice:example.com)"),
+        ] {
+            assert_eq!(
+                header(sender_display),
+                format!("12:00 / general / {expected}")
+            );
+            assert!(message_to_plain_text(&message, sender_display)
+                .starts_with(&format!("1970-01-01 00:00:00 UTC / general / {expected}\n")));
+        }
+    }
+
+    #[test]
+    fn retain_one_room() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a", "general", "alice", 0, "one"),
+            message("b", "random", "alice", 1, "two"),
+            message("c", "general", "alice", 2, "three"),
+            message("d", "random", "alice", 3, "four"),
+        ]);
+        view.select_identifier("b");
+        view.retain(|message| &*message.room.display_name == "general");
+        assert_eq!(identifiers(&view), ["a", "c"]);
+        // the cursor moves to the next remaining message
+        assert_eq!(selected_identifier(&view), Some("c"));
+        assert!(!view.goto("b"));
+        // or the previous one if there are none after it
+        view.retain(|message| &*message.key.identifier != "c");
+        assert_eq!(selected_identifier(&view), Some("a"));
+        view.retain(|_| false);
+        assert_eq!(selected_identifier(&view), None);
+    }
+
+    /// A clock which is always at the same time.
+    #[derive(Debug)]
+    struct FixedClock(DateTime<Utc>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn relative_time_from_clock() {
+        let mut view = MessageListView {
+            timestamp_format: TimestampFormat::Relative,
+            sender_display: SenderDisplay::NameOnly,
+            clock: Arc::new(FixedClock(DateTime::from_timestamp(1000 + 150, 0).unwrap())),
+            ..Default::default()
+        };
+        view.insert(message("a", "general", "alice", 1000, "hi"));
+        assert_eq!(render_rows(&mut view, 40, 2)[0], "2m ago / general / alice");
+    }
+
+    #[test]
+    fn reading_mode_wraps_and_centers() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.insert(message("a", "general", "alice", 0, &"word ".repeat(40)));
+        let full = render_rows(&mut view, 120, 6);
+        assert!(full.iter().any(|row| row.len() > 110), "{full:#?}");
+        view.toggle_reading_mode();
+        let reading = render_rows(&mut view, 120, 6);
+        // 80 columns of text after the highlight symbol, with the rest of the 120 split evenly
+        let margin = (120 - 83) / 2;
+        let rows = reading
+            .iter()
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 4, "{reading:#?}");
+        for row in &rows {
+            assert!(row[..margin].trim().is_empty(), "{reading:#?}");
+            assert!(row.len() <= margin + 83, "{reading:#?}");
+        }
+        assert!(
+            rows.iter().any(|row| row.len() > margin + 75),
+            "{reading:#?}"
+        );
+        view.toggle_reading_mode();
+        assert_eq!(render_rows(&mut view, 120, 6), full);
+    }
+
+    #[test]
+    fn degraded_glyphs() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            date_separators: Some(SeparatorGranularity::Day),
+            capabilities: Capabilities {
+                truecolor: false,
+                unicode: false,
+            },
+            ..Default::default()
+        };
+        view.extend([
+            message("parent", "general", "alice", 0, "question"),
+            reply("child", 1, "parent"),
+        ]);
+        let rows = render_rows(&mut view, 40, 8);
+        assert_eq!(rows[0], "----- Thursday, 1 January 1970 ------");
+        assert!(rows.contains(&"> alice: question".to_owned()), "{rows:#?}");
+        view.capabilities = Capabilities::default();
+        view.invalidate();
+        let rows = render_rows(&mut view, 40, 8);
+        assert_eq!(rows[0], "───── Thursday, 1 January 1970 ──────");
+        assert!(rows.contains(&"↪ alice: question".to_owned()), "{rows:#?}");
+    }
+
+    #[test]
+    fn reply_tree_indents() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            reply_tree: true,
+            ..Default::default()
+        };
+        view.extend([
+            message("parent", "general", "alice", 0, "question"),
+            reply("child", 1, "parent"),
+            Message {
+                body: MessageBody::Text(RichText::from_plain("deeper")),
+                ..reply("grandchild", 2, "child")
+            },
+            Message {
+                body: MessageBody::Text(RichText::from_plain("orphan")),
+                ..reply("orphan", 3, "missing")
+            },
+        ]);
+        let rows = render_rows(&mut view, 40, 16);
+        let indent = |text: &str| {
+            let row = rows.iter().find(|row| row.trim_start() == text).unwrap();
+            row.len() - row.trim_start().len()
+        };
+        assert_eq!(indent("question"), 0, "{rows:#?}");
+        assert_eq!(indent("reply"), 2, "{rows:#?}");
+        assert_eq!(indent("deeper"), 4, "{rows:#?}");
+        // a reply whose parent isn't loaded is indented once
+        assert_eq!(indent("orphan"), 2, "{rows:#?}");
+    }
+
+    #[test]
+    fn extend_many() {
+        let messages = carrier_pigeon_fake_messages::seeded_messages(
+            7,
+            1000,
+            DateTime::from_timestamp(0, 0).unwrap(),
+        );
+        let middle = messages[500].key.identifier.clone();
+        let last = messages[999].key.identifier.clone();
+        let (first, second) = messages.split_at(900);
+        let mut view = MessageListView::default();
+        view.set_follow(true);
+        // extending only marks the list for laying out when it's next rendered
+        view.extend(first.to_vec());
+        assert!(view.dirty);
+        assert_eq!(view.redraws, 0);
+        render_rows(&mut view, 40, 10);
+        assert_eq!(view.redraws, 1);
+        render_rows(&mut view, 40, 10);
+        assert_eq!(view.redraws, 1);
+        // so a batch is laid out once, however many messages are in it
+        view.extend(second.to_vec());
+        render_rows(&mut view, 40, 10);
+        assert_eq!(view.redraws, 2);
+        assert_eq!(view.messages().count(), 1000);
+        assert_eq!(selected_identifier(&view), Some(&*last));
+        assert!(view.select_identifier(&middle));
+        assert_eq!(selected_identifier(&view), Some(&*middle));
+    }
+
+    #[test]
+    fn two_message_snapshot() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        view.extend([
+            message("a", "general", "alice", 0, "hello"),
+            message("b", "general", "bob", 60, "hi alice"),
+        ]);
+        view.select_first();
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(50, 4)).unwrap();
+        terminal
+            .draw(|frame| view.render(frame.area(), frame.buffer_mut()))
+            .unwrap();
+        let snapshot = crate::buffer_to_snapshot(terminal.backend().buffer());
+        assert_eq!(
+            snapshot,
+            "\
+|-> 1970-01-01 00:00:00 UTC / general / alice      |
+  39..44 fg:lightgreen
+|   hello                                          |
+|   1970-01-01 00:01:00 UTC / general / bob        |
+  39..42 fg:magenta
+|   hi alice                                       |
+"
+        );
+    }
+
+    #[test]
+    fn select_existing_key() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            ..Default::default()
+        };
+        let messages = [
+            message("a", "general", "alice", 0, "one"),
+            message("b", "general", "bob", 60, "two"),
+        ];
+        view.extend(messages.clone());
+        view.select_first();
+        assert!(view.select_key(&messages[1].key));
+        assert_eq!(selected_identifier(&view), Some("b"));
+        // the list follows the cursor when it's next rendered
+        let rows = render_rows(&mut view, 50, 4);
+        assert_eq!(rows[2], "-> 1970-01-01 00:01:00 UTC / general / bob");
+    }
+
+    #[test]
+    fn select_missing_key() {
+        let mut view = MessageListView::default();
+        let messages = [
+            message("a", "general", "alice", 0, "one"),
+            message("b", "general", "bob", 60, "two"),
+        ];
+        view.extend(messages.clone());
+        view.select_first();
+        assert!(!view.select_key(&message("c", "general", "alice", 0, "three").key));
+        // nor with the identifier of one present but the wrong timestamp
+        let mut moved = messages[1].key.clone();
+        moved.timestamp = DateTime::from_timestamp(120, 0).unwrap();
+        assert!(!view.select_key(&moved));
+        assert_eq!(selected_identifier(&view), Some("a"));
+    }
+
+    #[test]
+    fn week_separators() {
+        const DAY: i64 = 24 * 60 * 60;
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            date_separators: Some(SeparatorGranularity::Week),
+            ..Default::default()
+        };
+        // 1 January 1970 was a Thursday
+        view.extend([
+            message("thursday", "general", "alice", 0, "one"),
+            message("sunday", "general", "bob", 3 * DAY, "two"),
+            message("monday", "general", "alice", 4 * DAY, "three"),
+        ]);
+        let separators = render_rows(&mut view, 50, 12)
+            .into_iter()
+            .filter(|row| row.contains("Week of"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            separators,
+            [
+                "────────── Week of 29 December 1969 ───────────",
+                "─────────── Week of 5 January 1970 ────────────",
+            ]
+        );
+    }
+
+    #[test]
+    fn key_index_mapping_with_filter() {
+        const DAY: i64 = 24 * 60 * 60;
+        let mut view = MessageListView {
+            date_separators: Some(SeparatorGranularity::Day),
+            ..Default::default()
+        };
+        let messages = [
+            message("a", "general", "alice", 0, "one\ntwo lines"),
+            message("b", "random", "bob", 1, "elsewhere"),
+            message("c", "general", "bob", DAY, "the next day"),
+            message("d", "general", "alice", DAY + 1, "three"),
+        ];
+        view.extend(messages.clone());
+        view.set_room_filter(Some("!general:example.com".into()));
+        render_rows(&mut view, 60, 20);
+        let indices = messages
+            .iter()
+            .map(|message| view.key_to_index(&message.key))
+            .collect::<Vec<_>>();
+        // separators and the lines of a message don't count as items
+        assert_eq!(indices, [Some(0), None, Some(1), Some(2)]);
+        for (message, index) in messages.iter().zip(indices) {
+            if let Some(index) = index {
+                assert_eq!(view.index_to_key(index).as_ref(), Some(&message.key));
+            }
+        }
+        assert_eq!(view.index_to_key(3), None);
+    }
+
+    #[test]
+    fn sender_avatar_badge() {
+        let badge = |sender: &str| {
+            let message = message("a", "general", sender, 0, "hi");
+            let options = TextOptions {
+                sender_avatar: true,
+                sender_display: SenderDisplay::NameOnly,
+                reaction_display: ReactionDisplay::Inline,
+            };
+            let text = message_to_text(
+                &message,
+                false,
+                options,
+                "12:00",
+                false,
+                &mut Highlighter::default(),
+            );
+            let header = &text.lines[0];
+            assert_eq!(
+                line_text(header),
+                format!("12:00 / general / {} {sender}", header.spans[1].content)
+            );
+            (
+                header.spans[1].content.clone().into_owned(),
+                header.spans[1].style.bg,
+                message.sender.identifier,
+            )
+        };
+        let (initial, color, identifier) = badge("alice");
+        assert_eq!(initial, " A ");
+        assert_eq!(color, Some(color_for_sender(&identifier)));
+        // the initial is the whole first grapheme, not just its first character
+        let (initial, color, identifier) = badge("e\u{301}mile");
+        assert_eq!(initial, " E\u{301} ");
+        assert_eq!(color, Some(color_for_sender(&identifier)));
+    }
+
+    /// Messages in `general` at 0s, 20s and 40s, with ones in `random` at 10s and 35s.
+    fn two_room_view() -> MessageListView {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("g0", "general", "alice", 0, "one"),
+            message("r10", "random", "bob", 10, "two"),
+            message("g20", "general", "alice", 20, "three"),
+            message("r35", "random", "bob", 35, "four"),
+            message("g40", "general", "alice", 40, "five"),
+        ]);
+        view
+    }
+
+    #[test]
+    fn filter_keeps_shown_selection() {
+        let mut view = two_room_view();
+        view.select_identifier("g20");
+        view.set_room_filter(Some("!general:example.com".into()));
+        assert_eq!(selected_identifier(&view), Some("g20"));
+        view.set_room_filter(None);
+        assert_eq!(selected_identifier(&view), Some("g20"));
+    }
+
+    #[test]
+    fn filter_moves_hidden_selection_to_nearest() {
+        let mut view = two_room_view();
+        view.select_identifier("r35");
+        view.set_room_filter(Some("!general:example.com".into()));
+        assert_eq!(selected_identifier(&view), Some("g40"));
+        // equally near to both, so the following one is chosen
+        view.set_room_filter(None);
+        view.select_identifier("r10");
+        view.set_room_filter(Some("!general:example.com".into()));
+        assert_eq!(selected_identifier(&view), Some("g20"));
+        // with no following message shown, the preceding one is chosen
+        view.set_room_filter(None);
+        view.select_identifier("g40");
+        view.set_room_filter(Some("!random:example.com".into()));
+        assert_eq!(selected_identifier(&view), Some("r35"));
+    }
+
+    #[test]
+    fn compact_below_threshold() {
+        let mut view = MessageListView {
+            sender_display: SenderDisplay::NameOnly,
+            compact_below: Some(40),
+            ..Default::default()
+        };
+        view.extend([message(
+            "a",
+            "general",
+            "alice",
+            0,
+            "a message too long to fit on a narrow terminal",
+        )]);
+        view.select_first();
+        let full = [
+            "-> 1970-01-01 00:00:00 UTC / general / alice",
+            "   a message too long to fit on a narrow terminal",
+        ];
+        assert_eq!(render_rows(&mut view, 50, 2), full);
+        assert_eq!(
+            render_rows(&mut view, 30, 2),
+            ["-> 00:00 alice: a message too…", ""]
+        );
+        // the threshold is the width of the messages, without the selection marker
+        assert_eq!(
+            render_rows(&mut view, 43, 4)[0],
+            "-> 1970-01-01 00:00:00 UTC / general /"
+        );
+        assert_eq!(
+            render_rows(&mut view, 42, 2)[0],
+            "-> 00:00 alice: a message too long to fit…"
+        );
+        // and it switches back when wide again
+        assert_eq!(render_rows(&mut view, 50, 2), full);
+    }
+
+    #[test]
+    fn same_sender_jumps_skip_collapsed() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("g1", "general", "bob", 0, "one"),
+            message("g2", "general", "alice", 1, "two"),
+            message("r", "random", "alice", 2, "three"),
+        ]);
+        view.toggle_grouping();
+        view.select_identifier("g1");
+        view.toggle_collapse_room();
+        assert_eq!(view.is_shown("g2"), Some(false));
+        view.select_identifier("r");
+        assert!(!view.select_same_sender(false));
+        assert_eq!(selected_identifier(&view), Some("r"));
+    }
+
+    #[test]
+    fn received_again_keeps_its_place() {
+        let mut view = MessageListView {
+            order: MessageOrder::ReceivedOrder,
+            read_policy: ReadPolicy::OnSelect,
+            ..MessageListView::default()
+        };
+        let received = |message: Message, seconds| Message {
+            received_at: DateTime::from_timestamp(seconds, 0),
+            ..message
+        };
+        let a = received(message("a", "general", "alice", 0, "one"), 10);
+        view.extend([a.clone(), received(reply("b", 1, "a"), 20)]);
+        view.select_first();
+        view.update_read_markers();
+        // e.g. replayed from a log without the time it was received, over the saved history
+        view.insert(received(a, 100));
+        view.update_read_markers();
+        view.select_same_sender(true);
+        view.set_room_filter(Some(room("general").identifier));
+        assert_eq!(identifiers(&view), ["a", "b"]);
+        assert_eq!(selected_identifier(&view), Some("a"));
+        assert!(view.select_next_reply());
+        assert_eq!(selected_identifier(&view), Some("b"));
+    }
+
+    #[test]
+    fn search_skips_collapsed_rooms() {
+        let mut view = MessageListView::default();
+        view.extend([
+            message("g1", "general", "bob", 0, "one"),
+            message("g2", "general", "alice", 1, "needle"),
+            message("r", "random", "alice", 2, "needle"),
+        ]);
+        view.toggle_grouping();
+        view.select_identifier("g1");
+        view.toggle_collapse_room();
+        assert_eq!(view.is_shown("g2"), Some(false));
+        assert!(view.select_match(None, "needle"));
+        assert_eq!(selected_identifier(&view), Some("r"));
+        view.set_search(Some("needle"));
+        assert_eq!(view.select_next_match(true), Some(true));
+        assert_eq!(selected_identifier(&view), Some("r"));
+        assert_eq!(view.select_next_match(false), Some(true));
+        assert_eq!(selected_identifier(&view), Some("r"));
+        // nothing is found if the only match is collapsed
+        view.select_identifier("g1");
+        view.set_room_filter(Some(room("general").identifier));
+        assert!(!view.select_match(None, "needle"));
+        assert_eq!(view.select_next_match(true), None);
+        assert_eq!(selected_identifier(&view), Some("g1"));
+    }
+
+    #[test]
+    fn identifiers_are_per_service() {
+        let in_service = |message: Message, service| {
+            let mut message = message;
+            message.key.service = service;
+            message
+        };
+        let matrix = in_service(
+            message("x", "general", "alice", 0, "one"),
+            ServiceType::Matrix,
+        );
+        let irc = in_service(message("x", "general", "bob", 1, "two"), ServiceType::Irc);
+        let matrix_reply = in_service(reply("r", 2, "x"), ServiceType::Matrix);
+        let mut view = MessageListView::default();
+        view.extend([matrix.clone(), irc.clone()]);
+        assert!(!view.is_edit(&irc));
+        view.insert(matrix_reply.clone());
+        assert_eq!(identifiers(&view), ["x", "x", "r"]);
+        assert_eq!(view.reply_parent(&matrix_reply), Some(&matrix.key));
+        view.select_first();
+        assert_eq!(view.selected().unwrap().key, matrix.key);
+        assert!(view.select_next_reply());
+        assert_eq!(selected_identifier(&view), Some("r"));
+        assert!(view.select_key(&irc.key));
+        assert!(!view.select_next_reply());
+        // removing one doesn't unindex the other
+        view.delete(&matrix.key);
+        assert_eq!(view.reply_parent(&matrix_reply), None);
+        assert!(view.goto("x"));
+        assert_eq!(view.selected().unwrap().key, irc.key);
+    }
+
+    #[test]
+    fn history_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a", "general", "alice", 0, "one"),
+            reply("b", 1, "a"),
+            message("c", "random", "charlie", 2, "two\nlines"),
+        ]);
+        view.save_to(&path).unwrap();
+        assert!(!path.with_extension("partial").exists());
+        let mut loaded = MessageListView::default();
+        assert_eq!(loaded.load_from(&path).unwrap(), 0);
+        assert_eq!(identifiers(&loaded), ["a", "b", "c"]);
+        assert_eq!(loaded.plain_text(usize::MAX), view.plain_text(usize::MAX));
+    }
+
+    #[test]
+    fn corrupt_history_lines_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.jsonl");
+        let mut view = MessageListView::default();
+        view.extend([
+            message("a", "general", "alice", 0, "one"),
+            message("b", "general", "bob", 1, "two"),
+        ]);
+        view.save_to(&path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let (first, second) = contents.split_once('\n').unwrap();
+        let corrupt = format!("{first}\nnot json\n\n{{\"key\": 1}}\n{second}");
+        std::fs::write(&path, corrupt).unwrap();
+        let mut loaded = MessageListView::default();
+        assert_eq!(loaded.load_from(&path).unwrap(), 2);
+        assert_eq!(identifiers(&loaded), ["a", "b"]);
+    }
+
+    #[test]
+    fn missing_history_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut view = MessageListView::default();
+        assert_eq!(
+            view.load_from(&dir.path().join("history.jsonl")).unwrap(),
+            0
+        );
+        assert_eq!(view.messages().count(), 0);
+    }
+
+    #[test]
+    fn failed_save_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        // a non-empty directory can't be replaced by the history
+        let path = dir.path().join("history.jsonl");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("other"), "").unwrap();
+        let mut view = MessageListView::default();
+        view.insert(message("a", "general", "alice", 0, "one"));
+        assert!(view.save_to(&path).is_err());
+        assert!(!path.with_extension("partial").exists());
+        assert!(path.join("other").exists());
+    }
 }
@@ -0,0 +1,182 @@
+use carrier_pigeon_common::Room;
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::Widget,
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::capabilities::Capabilities;
+
+/// A sidebar listing rooms, with their unread counts.
+#[derive(Debug)]
+pub struct RoomList<'a> {
+    pub rooms: &'a [(Room, usize)],
+    pub capabilities: Capabilities,
+}
+
+impl Widget for RoomList<'_> {
+    fn render(self, area: Rect, buffer: &mut Buffer) {
+        let rows = self
+            .rooms
+            .iter()
+            .map(|(room, unread)| {
+                let row = render_room_row(
+                    &room.display_name,
+                    Some(*unread),
+                    area.width.into(),
+                    self.capabilities.ellipsis(),
+                );
+                if *unread > 0 {
+                    row.bold()
+                } else {
+                    row
+                }
+            })
+            .collect::<Vec<_>>();
+        Text::from(rows).render(area, buffer);
+    }
+}
+
+/// A list of rooms to choose one from.
+#[derive(Debug)]
+pub struct RoomPicker {
+    rooms: Vec<Room>,
+    selected: usize,
+}
+
+impl RoomPicker {
+    /// Returns `None` if there are no rooms to choose from.
+    pub fn new(rooms: Vec<Room>) -> Option<Self> {
+        (!rooms.is_empty()).then_some(Self { rooms, selected: 0 })
+    }
+
+    pub fn select_next(&mut self) {
+        self.selected = (self.selected + 1).min(self.rooms.len() - 1);
+    }
+
+    pub fn select_prev(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    pub fn selected(&self) -> &Room {
+        &self.rooms[self.selected]
+    }
+
+    /// How many rows the picker needs to show every room.
+    pub fn height(&self) -> u16 {
+        self.rooms.len().try_into().unwrap_or(u16::MAX)
+    }
+
+    pub fn render(&self, area: Rect, buffer: &mut Buffer, capabilities: Capabilities) {
+        // keep the selected room in view
+        let skip = (self.selected + 1).saturating_sub(area.height.into());
+        let rows = self
+            .rooms
+            .iter()
+            .enumerate()
+            .skip(skip)
+            .map(|(index, room)| {
+                let row = render_room_row(
+                    &room.display_name,
+                    None,
+                    area.width.into(),
+                    capabilities.ellipsis(),
+                );
+                if index == self.selected {
+                    row.reversed()
+                } else {
+                    row
+                }
+            })
+            .collect::<Vec<_>>();
+        Text::from(rows).render(area, buffer);
+    }
+}
+
+/// Lays out one row of the room list, `width` columns wide: the name, elided with `ellipsis` if
+/// it doesn't fit, and the unread count (if any, and non-zero) flush right.
+pub fn render_room_row(
+    name: &str,
+    unread: Option<usize>,
+    width: usize,
+    ellipsis: &str,
+) -> Line<'static> {
+    let count = unread
+        .filter(|&unread| unread > 0)
+        .map(|unread| format!(" {unread}"))
+        .unwrap_or_default();
+    let name_width = width.saturating_sub(count.width());
+    let name = elide(name, name_width, ellipsis);
+    let padding = " ".repeat(name_width.saturating_sub(name.width()));
+    Line::from(vec![
+        name.into(),
+        padding.into(),
+        Span::styled(count, Style::new().reversed()),
+    ])
+}
+
+/// Shortens the text to at most `width` columns, replacing the end with `ellipsis` if it doesn't
+/// fit.
+pub fn elide(text: &str, width: usize, ellipsis: &str) -> String {
+    if text.width() <= width {
+        return text.to_owned();
+    }
+    let mut elided = String::new();
+    let mut elided_width = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        // leave room for the ellipsis
+        if elided_width + char_width + ellipsis.width() > width {
+            break;
+        }
+        elided.push(c);
+        elided_width += char_width;
+    }
+    if width >= ellipsis.width() {
+        elided.push_str(ellipsis);
+    }
+    elided
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_text(line: &Line) -> String {
+        line.spans.iter().map(|span| &*span.content).collect()
+    }
+
+    #[test]
+    fn long_room_name_with_unread() {
+        let line = render_room_row("a-very-long-room-name", Some(12), 12, "…");
+        assert_eq!(row_text(&line), "a-very-l… 12");
+        assert_eq!(line.width(), 12);
+        assert_eq!(line.spans[2].style, Style::new().reversed());
+    }
+
+    #[test]
+    fn short_room_name() {
+        assert_eq!(
+            row_text(&render_room_row("general", Some(3), 12, "…")),
+            "general    3"
+        );
+        assert_eq!(
+            row_text(&render_room_row("general", Some(0), 12, "...")),
+            "general     "
+        );
+        assert_eq!(
+            row_text(&render_room_row("a-very-long-room-name", None, 12, "...")),
+            "a-very-lo..."
+        );
+    }
+
+    #[test]
+    fn elide_wide_characters() {
+        // each of these takes two columns, so only two fit before the ellipsis
+        assert_eq!(elide("日本語のテキスト", 6, "…"), "日本…");
+        assert_eq!(elide("日本語", 6, "…"), "日本語");
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::Write;
+
+use ratatui::{
+    buffer::{Buffer, Cell},
+    style::{Color, Modifier},
+};
+use unicode_width::UnicodeWidthStr;
+
+/// Serializes a rendered buffer as text, so that renders can be compared in tests.
+///
+/// Each row is written as its symbols between `|`s, followed by a line for each run of cells with
+/// a non-default style, e.g. `  0..5 fg:red bg:blue +bold`. The columns are those of the buffer,
+/// so the cells covered by a wide symbol count towards them, though they aren't written.
+pub fn buffer_to_snapshot(buffer: &Buffer) -> String {
+    let mut snapshot = String::new();
+    let width = usize::from(buffer.area.width);
+    for row in buffer.content.chunks(width.max(1)) {
+        let mut text = String::new();
+        let mut runs = Vec::<(usize, usize, String)>::new();
+        let mut covered = 0;
+        for (column, cell) in row.iter().enumerate() {
+            if covered > 0 {
+                // part of the previous cell's run
+                covered -= 1;
+                if let Some((_, end, _)) = runs.last_mut() {
+                    *end = column + 1;
+                }
+                continue;
+            }
+            text.push_str(cell.symbol());
+            covered = cell.symbol().width().saturating_sub(1);
+            let style = encode_style(cell);
+            match runs.last_mut() {
+                Some((_, end, last)) if *end == column && *last == style => *end = column + 1,
+                _ => runs.push((column, column + 1, style)),
+            }
+        }
+        let _ = writeln!(snapshot, "|{text}|");
+        for (start, end, style) in runs.into_iter().filter(|(_, _, style)| !style.is_empty()) {
+            let _ = writeln!(snapshot, "  {start}..{end} {style}");
+        }
+    }
+    snapshot
+}
+
+/// A compact description of the cell's style, which is empty for the default style.
+fn encode_style(cell: &Cell) -> String {
+    let mut parts = Vec::new();
+    if cell.fg != Color::Reset {
+        parts.push(format!("fg:{}", encode_color(cell.fg)));
+    }
+    if cell.bg != Color::Reset {
+        parts.push(format!("bg:{}", encode_color(cell.bg)));
+    }
+    parts.extend(
+        cell.modifier
+            .iter_names()
+            .map(|(name, _): (&str, Modifier)| format!("+{}", name.to_lowercase())),
+    );
+    parts.join(" ")
+}
+
+fn encode_color(color: Color) -> String {
+    match color {
+        Color::Rgb(r, g, b) => format!("#{r:02x}{g:02x}{b:02x}"),
+        Color::Indexed(index) => index.to_string(),
+        color => format!("{color:?}").to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::{
+        layout::Rect,
+        style::{Style, Stylize},
+    };
+
+    use super::*;
+
+    #[test]
+    fn styles_are_run_length_encoded() {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 8, 2));
+        buffer.set_string(0, 0, "red", Style::new().fg(Color::Red));
+        buffer.set_string(3, 0, "bold", Style::new().fg(Color::Red).bold());
+        buffer.set_string(0, 1, "x", Style::new().bg(Color::Rgb(1, 2, 255)));
+        buffer.set_string(1, 1, "y", Style::new().fg(Color::Indexed(42)).italic());
+        assert_eq!(
+            buffer_to_snapshot(&buffer),
+            "\
+|redbold |
+  0..3 fg:red
+  3..7 fg:red +bold
+|xy      |
+  0..1 bg:#0102ff
+  1..2 fg:42 +italic
+"
+        );
+    }
+
+    #[test]
+    fn wide_symbols_cover_their_columns() {
+        let mut buffer = Buffer::empty(Rect::new(0, 0, 5, 1));
+        buffer.set_string(0, 0, "日本", Style::new().underlined());
+        assert_eq!(buffer_to_snapshot(&buffer), "|日本 |\n  0..4 +underlined\n");
+    }
+}
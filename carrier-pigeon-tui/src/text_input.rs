@@ -0,0 +1,136 @@
+use crossterm::event::KeyModifiers;
+use ratatui::{
+    buffer::Buffer,
+    layout::Rect,
+    style::{Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::Widget,
+};
+
+use crate::keymap::{KeyCode, KeyEvent};
+
+/// A multi-line text input box.
+#[derive(Debug, Default)]
+pub struct TextInput {
+    text: String,
+    /// Byte offset of the cursor in `text`
+    cursor: usize,
+}
+
+impl TextInput {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Removes and returns all the text, leaving the input empty.
+    pub fn take(&mut self) -> String {
+        self.cursor = 0;
+        std::mem::take(&mut self.text)
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    /// Inserts a character (which may be a newline) at the cursor.
+    pub fn insert(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    /// Deletes the character before the cursor.
+    pub fn backspace(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Deletes the character after the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Moves the cursor back one character.
+    pub fn move_left(&mut self) {
+        if let Some(c) = self.text[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    /// Moves the cursor forward one character.
+    pub fn move_right(&mut self) {
+        if let Some(c) = self.text[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    /// Byte offset of the start of the line the cursor is on.
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map_or(0, |i| i + 1)
+    }
+
+    /// Byte offset of the end of the line the cursor is on, before its newline.
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .find('\n')
+            .map_or(self.text.len(), |i| self.cursor + i)
+    }
+
+    /// Edits the text according to a key which isn't mapped to any action. Returns whether
+    /// the key had any effect.
+    pub fn type_key(&mut self, key: &KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char(c)
+                if !key
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                self.insert(c)
+            }
+            KeyCode::Backspace => self.backspace(),
+            KeyCode::Delete => self.delete(),
+            KeyCode::Left => self.move_left(),
+            KeyCode::Right => self.move_right(),
+            KeyCode::Home => self.cursor = self.line_start(),
+            KeyCode::End => self.cursor = self.line_end(),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The number of lines the text takes up.
+    pub fn line_count(&self) -> usize {
+        self.text.split('\n').count()
+    }
+}
+
+impl Widget for &TextInput {
+    fn render(self, area: Rect, buffer: &mut Buffer) {
+        let cursor_style = Style::new().reversed();
+        let (before, after) = self.text.split_at(self.cursor);
+        let mut lines = before
+            .split('\n')
+            .map(|line| Line::raw(line.to_owned()))
+            .collect::<Vec<_>>();
+        let mut after = after.split('\n');
+        let cursor_line = lines
+            .last_mut()
+            .expect("split always yields at least one item");
+        let rest_of_line = after.next().unwrap_or_default();
+        // the cursor is drawn over the character after it, or a blank space at the end of a line
+        let mut rest_chars = rest_of_line.chars();
+        let under_cursor = rest_chars.next().map_or(" ".into(), String::from);
+        cursor_line.push_span(Span::styled(under_cursor, cursor_style));
+        cursor_line.push_span(rest_chars.as_str().to_owned());
+        lines.extend(after.map(|line| Line::raw(line.to_owned())));
+        Text::from(lines).render(area, buffer);
+    }
+}
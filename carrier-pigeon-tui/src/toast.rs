@@ -0,0 +1,121 @@
+use carrier_pigeon_common::SendError;
+use ratatui::style::{Color, Style};
+use tokio::time::Duration;
+
+/// How long a toast stays on screen.
+pub const TOAST_DURATION: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ToastLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+impl ToastLevel {
+    pub fn style(self) -> Style {
+        match self {
+            Self::Info => Style::new(),
+            Self::Warning => Style::new().fg(Color::Yellow),
+            Self::Error => Style::new().fg(Color::Red),
+        }
+    }
+}
+
+/// A short-lived notification shown at the bottom of the screen.
+#[derive(Clone, Debug)]
+pub struct Toast {
+    pub level: ToastLevel,
+    pub message: String,
+}
+
+impl Toast {
+    pub fn info(message: impl Into<String>) -> Self {
+        Self {
+            level: ToastLevel::Info,
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            level: ToastLevel::Warning,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            level: ToastLevel::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<&SendError> for Toast {
+    fn from(err: &SendError) -> Self {
+        let level = match err {
+            SendError::Network | SendError::RateLimited { .. } => ToastLevel::Warning,
+            SendError::NotPermitted | SendError::TooLong { .. } | SendError::Unknown(_) => {
+                ToastLevel::Error
+            }
+        };
+        let draft = if err.keeps_draft() {
+            "draft kept"
+        } else {
+            "draft discarded"
+        };
+        Self {
+            level,
+            message: format!("failed to send message: {err} ({draft})"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_error_toasts() {
+        let cases = [
+            (
+                SendError::Network,
+                ToastLevel::Warning,
+                "failed to send message: network error (draft kept)",
+            ),
+            (
+                SendError::RateLimited {
+                    retry_after: Some(Duration::from_secs(30)),
+                },
+                ToastLevel::Warning,
+                "failed to send message: rate limited, retry after 30s (draft kept)",
+            ),
+            (
+                SendError::RateLimited { retry_after: None },
+                ToastLevel::Warning,
+                "failed to send message: rate limited (draft kept)",
+            ),
+            (
+                SendError::NotPermitted,
+                ToastLevel::Error,
+                "failed to send message: not permitted to send messages here (draft discarded)",
+            ),
+            (
+                SendError::TooLong { max: 512 },
+                ToastLevel::Error,
+                "failed to send message: message too long (max 512) (draft kept)",
+            ),
+            (
+                SendError::Unknown("server on fire".into()),
+                ToastLevel::Error,
+                "failed to send message: server on fire (draft kept)",
+            ),
+        ];
+        for (err, level, message) in cases {
+            let toast = Toast::from(&err);
+            assert_eq!(toast.level, level, "{err:?}");
+            assert_eq!(toast.message, message);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use ratatui::{
+    style::Style,
+    text::{Line, Span, Text},
+};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Wraps each line of `text` to at most `width` columns.
+pub fn wrap_text(text: Text<'static>, width: usize) -> Text<'static> {
+    let Text {
+        lines,
+        style,
+        alignment,
+    } = text;
+    let mut wrapped = Text::from(
+        lines
+            .into_iter()
+            .flat_map(|line| wrap_line(line, width))
+            .collect::<Vec<_>>(),
+    )
+    .style(style);
+    wrapped.alignment = alignment;
+    wrapped
+}
+
+/// Wraps a line to at most `width` columns, breaking at whitespace where possible. The styles of
+/// the spans are kept, and the line's own style and alignment apply to each resulting line.
+///
+/// The whitespace the line starts with (along with its styles) is repeated at the start of each
+/// resulting line, unless it would take up more than half the width.
+pub fn wrap_line(line: Line<'static>, width: usize) -> Vec<Line<'static>> {
+    let width = width.max(1);
+    let mut indent = line
+        .spans
+        .iter()
+        .flat_map(|span| span.content.chars().map(|c| (c, span.style)))
+        .take_while(|(c, _)| c.is_whitespace())
+        .collect::<Vec<_>>();
+    if indent
+        .iter()
+        .map(|(c, _)| c.width().unwrap_or(0))
+        .sum::<usize>()
+        * 2
+        > width
+    {
+        indent.clear();
+    }
+    let mut lines = Vec::new();
+    let mut current = Vec::<(char, Style)>::new();
+    let mut current_width = 0;
+    // the index in `current` just after the last whitespace
+    let mut last_break = None;
+    for span in &line.spans {
+        for c in span.content.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if current_width + c_width > width && current.len() > indent.len() {
+                let rest = match last_break {
+                    // whitespace at the end of a line is dropped, rather than starting the next
+                    _ if c.is_whitespace() => Vec::new(),
+                    Some(i) if i > indent.len() => current.split_off(i),
+                    _ => Vec::new(),
+                };
+                let next = indent.iter().copied().chain(rest).collect();
+                lines.push(to_line(std::mem::replace(&mut current, next), &line));
+                current_width = current.iter().map(|(c, _)| c.width().unwrap_or(0)).sum();
+                last_break = None;
+                if c.is_whitespace() {
+                    continue;
+                }
+            }
+            current.push((c, span.style));
+            current_width += c_width;
+            if c.is_whitespace() {
+                last_break = Some(current.len());
+            }
+        }
+    }
+    lines.push(to_line(current, &line));
+    lines
+}
+
+/// Shortens a line to at most `width` columns, replacing the end with `ellipsis` if it doesn't fit.
+/// The styles of the spans are kept.
+pub fn elide_line(line: Line<'static>, width: usize, ellipsis: &str) -> Line<'static> {
+    if line.width() <= width {
+        return line;
+    }
+    let room = width.saturating_sub(ellipsis.width());
+    let mut chars = Vec::<(char, Style)>::new();
+    let mut chars_width = 0;
+    'spans: for span in &line.spans {
+        for c in span.content.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if chars_width + c_width > room {
+                break 'spans;
+            }
+            chars.push((c, span.style));
+            chars_width += c_width;
+        }
+    }
+    if width >= ellipsis.width() {
+        let style = chars.last().map_or_else(Style::new, |&(_, style)| style);
+        chars.extend(ellipsis.chars().map(|c| (c, style)));
+    }
+    to_line(chars, &line)
+}
+
+/// Patches `style` onto each occurrence of `query` in the line, ignoring case.
+pub fn mark_matches(line: &mut Line<'static>, query: &str, style: Style) {
+    let query = query.chars().map(fold_case).collect::<Vec<_>>();
+    if query.is_empty() {
+        return;
+    }
+    let mut chars = line
+        .spans
+        .iter()
+        .flat_map(|span| span.content.chars().map(|c| (c, span.style)))
+        .collect::<Vec<_>>();
+    let lower = chars.iter().map(|&(c, _)| fold_case(c)).collect::<Vec<_>>();
+    let mut start = 0;
+    let mut marked = false;
+    while start + query.len() <= lower.len() {
+        if lower[start..start + query.len()] == query[..] {
+            for (_, char_style) in &mut chars[start..start + query.len()] {
+                *char_style = char_style.patch(style);
+            }
+            start += query.len();
+            marked = true;
+        } else {
+            start += 1;
+        }
+    }
+    if marked {
+        *line = to_line(chars, line);
+    }
+}
+
+/// The lowercase form of a character, for characters which lowercase to a single one.
+fn fold_case(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
+    }
+}
+
+/// Builds a line from styled characters, with the style and alignment of `template`.
+fn to_line(chars: Vec<(char, Style)>, template: &Line) -> Line<'static> {
+    let mut spans = Vec::<Span<'static>>::new();
+    for (c, style) in chars {
+        match spans.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push(c),
+            _ => spans.push(Span::styled(c.to_string(), style)),
+        }
+    }
+    let mut line = Line::from(spans).style(template.style);
+    line.alignment = template.alignment;
+    line
+}
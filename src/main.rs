@@ -1,31 +1,330 @@
-use carrier_pigeon_common::Message;
+use std::path::{Path, PathBuf};
+
+use carrier_pigeon_common::{Outbox, ServiceType, StreamEvent, User};
+use carrier_pigeon_tui::{Config, ConfigHandle, KeyBindings};
 use clap::Parser;
+use color_eyre::eyre::{bail, WrapErr};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use tracing_subscriber::prelude::*;
 
 #[derive(Debug, Parser)]
 struct Args {
-    // username: OwnedUserId,
+    /// Number of fake messages to generate at startup
+    #[arg(long, default_value_t = 0)]
+    seed_count: usize,
+    /// Probability that each fake message edits or deletes an earlier one instead
+    #[arg(long, default_value_t = 0.05, value_parser = parse_probability)]
+    change_probability: f64,
+    /// Path to the config file [default: $XDG_CONFIG_HOME/carrier-pigeon/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Use `config.<PROFILE>.toml` in the config directory instead of `config.toml`, falling back
+    /// to `config.toml` if it doesn't exist
+    #[arg(long, conflicts_with = "config")]
+    profile: Option<String>,
+    /// Write the default config file (to the given path, or the default config path) and exit
+    #[arg(long, value_name = "PATH")]
+    generate_config: Option<Option<PathBuf>>,
+    /// Overwrite an existing file with `--generate-config`
+    #[arg(long, requires = "generate_config")]
+    force: bool,
+    /// Show the messages from the `.jsonl` files in this directory, instead of generating fake
+    /// messages
+    #[arg(long, value_name = "DIR")]
+    jsonl_dir: Option<PathBuf>,
+    /// Show messages as they are appended to the files in `--jsonl-dir`
+    #[arg(long, requires = "jsonl_dir")]
+    watch: bool,
+    /// Who you are: a name, or a Matrix-style `@name:server`. Your messages are shown in bold, and
+    /// your reactions are made as this user
+    #[arg(long, value_name = "ID", default_value = "you", value_parser = parse_user)]
+    username: User,
+    /// Append a line describing each new message which is notified about to this file, e.g. a
+    /// FIFO read by a screen reader
+    #[arg(long, value_name = "PATH")]
+    announce: Option<PathBuf>,
+    /// Connect to IRC with the given config file, instead of generating fake messages
+    #[cfg(feature = "irc")]
+    #[arg(long, value_name = "PATH")]
+    irc_config: Option<PathBuf>,
+    /// Log in to Matrix as this user, instead of generating fake messages. The password is read
+    /// from $CARRIER_PIGEON_MATRIX_PASSWORD
+    #[cfg(feature = "matrix")]
+    #[arg(long, value_name = "USER_ID")]
+    matrix_user: Option<carrier_pigeon_matrix::OwnedUserId>,
 }
 
 #[tokio::main]
 async fn main() -> color_eyre::Result<()> {
     color_eyre::install()?;
-    // let args = Args::parse();
+    let args = Args::parse();
+    let config_dir = default_config_dir();
+    let profile = args.profile.as_deref();
+    if let Some(path) = args.generate_config {
+        let path = path.or_else(|| {
+            args.config
+                .or_else(|| Some(profile_path(config_dir.as_deref()?, "config", profile)))
+        });
+        let Some(path) = path else {
+            bail!("no config directory found; pass a path to --generate-config");
+        };
+        return generate_config(&path, args.force);
+    }
+    let config_path = args.config.clone().or_else(|| {
+        Some(resolve_profile_path(
+            config_dir.as_deref()?,
+            "config",
+            profile,
+        ))
+    });
+    let config = ConfigHandle::new(match config_path {
+        Some(path) => Config::from_path(&path)?,
+        None => Config::default(),
+    });
+    let key_bindings = match &config_dir {
+        Some(dir) => KeyBindings::from_path(&resolve_profile_path(dir, "keys", profile))?,
+        None => KeyBindings::default(),
+    };
+
     let log_file = std::sync::Mutex::new(std::fs::File::create("carrier-pigeon.log")?);
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer().with_writer(log_file))
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
+    let cancel = CancellationToken::new();
+    tokio::spawn(cancel_on_signal(cancel.clone()));
+
     let (tx, rx) = mpsc::unbounded_channel();
-    tokio::spawn(carrier_pigeon_fake_messages::message_sender(tx.clone()));
-    carrier_pigeon_tui::run(rx).await?;
+    let mut fake_messages = true;
+    // each backend sends the messages for rooms of its service
+    #[allow(unused_mut, reason = "only backends with features add routes")]
+    let mut outbox = Outbox::closed();
+    if let Some(dir) = args.jsonl_dir {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = carrier_pigeon_jsonl::message_sender(dir, args.watch, tx).await {
+                tracing::error!("error reading messages: {err}");
+            }
+        });
+        fake_messages = false;
+    }
+    #[cfg(feature = "irc")]
+    if let Some(path) = &args.irc_config {
+        connect_irc(path, tx.clone(), &mut outbox).await?;
+        fake_messages = false;
+    }
+    #[cfg(feature = "matrix")]
+    if let Some(user_id) = &args.matrix_user {
+        connect_matrix(user_id, tx.clone(), &mut outbox).await?;
+        fake_messages = false;
+    }
+    if fake_messages {
+        tokio::spawn(carrier_pigeon_fake_messages::message_sender(
+            tx.clone(),
+            args.seed_count,
+            args.change_probability,
+        ));
+    }
+    let announce = args.announce.map(|path| {
+        let (announce_tx, announcements) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(err) = write_announcements(&path, announcements).await {
+                tracing::error!("error writing announcements to {}: {err}", path.display());
+            }
+        });
+        announce_tx
+    });
+    carrier_pigeon_tui::run(
+        rx,
+        outbox,
+        args.username,
+        config,
+        key_bindings,
+        announce,
+        cancel,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Connects to IRC, and sends its messages to the channel in the background. Messages to IRC
+/// rooms in the outbox are sent over the connection.
+#[cfg(feature = "irc")]
+async fn connect_irc(
+    path: &Path,
+    channel: mpsc::UnboundedSender<StreamEvent>,
+    outbox: &mut Outbox,
+) -> color_eyre::Result<()> {
+    let config = carrier_pigeon_irc::Config::load(path)
+        .wrap_err_with(|| format!("error loading IRC config {}", path.display()))?;
+    let (sink, stream) = carrier_pigeon_irc::connect(config)
+        .await
+        .wrap_err("error connecting to IRC")?;
+    tokio::spawn(async move {
+        if let Err(err) = carrier_pigeon_irc::message_sender(stream, channel).await {
+            tracing::error!("IRC connection failed: {err}");
+        }
+    });
+    tokio::spawn(outbox.route(ServiceType::Irc, sink));
+    Ok(())
+}
+
+/// Logs in to Matrix, and sends its messages to the channel in the background. Messages to Matrix
+/// rooms in the outbox are sent as the user.
+#[cfg(feature = "matrix")]
+async fn connect_matrix(
+    user_id: &carrier_pigeon_matrix::OwnedUserId,
+    channel: mpsc::UnboundedSender<StreamEvent>,
+    outbox: &mut Outbox,
+) -> color_eyre::Result<()> {
+    let password = std::env::var("CARRIER_PIGEON_MATRIX_PASSWORD")
+        .wrap_err("$CARRIER_PIGEON_MATRIX_PASSWORD must be set to log in to Matrix")?;
+    let sink = carrier_pigeon_matrix::connect(user_id, &password).await?;
+    tokio::spawn(outbox.route(ServiceType::Matrix, sink.clone()));
+    tokio::spawn(async move {
+        if let Err(err) = carrier_pigeon_matrix::message_sender(sink, channel).await {
+            tracing::error!("Matrix sync failed: {err}");
+        }
+    });
+    Ok(())
+}
+
+/// Appends each announcement to the file as a line, until the channel is closed.
+async fn write_announcements(
+    path: &Path,
+    mut announcements: mpsc::UnboundedReceiver<String>,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    // opening a FIFO waits for a reader
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    while let Some(announcement) = announcements.recv().await {
+        file.write_all(format!("{announcement}\n").as_bytes())
+            .await?;
+        file.flush().await?;
+    }
     Ok(())
 }
 
-async fn _run(mut messages: mpsc::UnboundedReceiver<Message>) -> color_eyre::Result<()> {
-    while let Some(message) = messages.recv().await {
+/// Parses a probability, which must be between 0 and 1 (so not NaN).
+fn parse_probability(value: &str) -> Result<f64, String> {
+    let probability = value.parse::<f64>().map_err(|err| err.to_string())?;
+    if !(0.0..=1.0).contains(&probability) {
+        return Err("expected a probability between 0 and 1".into());
+    }
+    Ok(probability)
+}
+
+/// Parses a user identifier, which is either a name or `@name:server`. The name is used as the
+/// display name.
+fn parse_user(identifier: &str) -> Result<User, String> {
+    let name = match identifier.strip_prefix('@') {
+        Some(rest) => match rest.split_once(':') {
+            Some((name, server)) if !server.is_empty() => name,
+            _ => return Err("expected `@name:server`".into()),
+        },
+        None => identifier,
+    };
+    if name.is_empty() || identifier.contains(char::is_whitespace) {
+        return Err("expected a non-empty name without whitespace".into());
+    }
+    Ok(User {
+        display_name: name.into(),
+        identifier: identifier.into(),
+        service: ServiceType::Unknown,
+    })
+}
+
+fn default_config_dir() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("carrier-pigeon"))
+}
+
+/// The path of the file `<name>.toml` in the config directory, or `<name>.<profile>.toml` for a
+/// profile.
+fn profile_path(config_dir: &Path, name: &str, profile: Option<&str>) -> PathBuf {
+    match profile {
+        Some(profile) => config_dir.join(format!("{name}.{profile}.toml")),
+        None => config_dir.join(format!("{name}.toml")),
+    }
+}
+
+/// Like [`profile_path`], but falls back to the base file if the profile's doesn't exist.
+fn resolve_profile_path(config_dir: &Path, name: &str, profile: Option<&str>) -> PathBuf {
+    let path = profile_path(config_dir, name, profile);
+    if profile.is_some() && !path.exists() {
+        profile_path(config_dir, name, None)
+    } else {
+        path
+    }
+}
+
+fn generate_config(path: &Path, force: bool) -> color_eyre::Result<()> {
+    if !force && path.exists() {
+        bail!(
+            "{} already exists; pass --force to overwrite it",
+            path.display()
+        );
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, carrier_pigeon_tui::DEFAULT_CONFIG)
+        .wrap_err_with(|| format!("error writing config file {}", path.display()))?;
+    println!("wrote default config to {}", path.display());
+    Ok(())
+}
+
+/// Cancels the token on SIGINT or SIGTERM, so that the terminal is restored before exiting.
+/// Resizes (SIGWINCH) are handled by crossterm.
+async fn cancel_on_signal(cancel: CancellationToken) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())?.recv().await;
+        Ok::<_, std::io::Error>(())
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<std::io::Result<()>>();
+
+    let signal = async {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res,
+            res = terminate => res,
+        }
+    };
+    cancel_on(signal, cancel).await
+}
+
+/// Cancels the token once `signal` resolves, unless listening for the signal failed.
+async fn cancel_on(
+    signal: impl std::future::Future<Output = std::io::Result<()>>,
+    cancel: CancellationToken,
+) -> std::io::Result<()> {
+    signal.await?;
+    tracing::info!("received signal, shutting down");
+    cancel.cancel();
+    Ok(())
+}
+
+async fn _run(mut events: mpsc::UnboundedReceiver<StreamEvent>) -> color_eyre::Result<()> {
+    while let Some(event) = events.recv().await {
+        let message = match event {
+            StreamEvent::New(message) | StreamEvent::Edit(message) => message,
+            StreamEvent::Delete(key) => {
+                println!("{} deleted", key.identifier);
+                continue;
+            }
+        };
         println!(
             "{} / {} / {} ({})\n{:?}",
             message.key.timestamp,
@@ -37,3 +336,96 @@ async fn _run(mut messages: mpsc::UnboundedReceiver<Message>) -> color_eyre::Res
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn signal_cancels() {
+        let (signal, signals) = tokio::sync::oneshot::channel();
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(cancel_on(
+            async { signals.await.map_err(std::io::Error::other) },
+            cancel.clone(),
+        ));
+        tokio::task::yield_now().await;
+        assert!(!cancel.is_cancelled());
+        signal.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert!(cancel.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn signal_error_doesnt_cancel() {
+        let cancel = CancellationToken::new();
+        let signal = async { Err(std::io::Error::other("no signals here")) };
+        assert!(cancel_on(signal, cancel.clone()).await.is_err());
+        assert!(!cancel.is_cancelled());
+    }
+
+    #[test]
+    fn generated_config_is_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("carrier-pigeon/config.toml");
+        generate_config(&path, false).unwrap();
+        let config = Config::from_path(&path).unwrap();
+        assert_eq!(format!("{config:?}"), format!("{:?}", Config::default()));
+    }
+
+    #[test]
+    fn generate_config_needs_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "mouse = true\n").unwrap();
+        assert!(generate_config(&path, false).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "mouse = true\n");
+        generate_config(&path, true).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            carrier_pigeon_tui::DEFAULT_CONFIG
+        );
+    }
+
+    #[test]
+    fn profile_paths() {
+        let dir = Path::new("/config");
+        assert_eq!(
+            profile_path(dir, "config", None),
+            Path::new("/config/config.toml")
+        );
+        assert_eq!(
+            profile_path(dir, "keymap", Some("work")),
+            Path::new("/config/keymap.work.toml")
+        );
+    }
+
+    #[test]
+    fn missing_profile_falls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("config.work.toml"), "").unwrap();
+        assert_eq!(
+            resolve_profile_path(dir.path(), "config", Some("work")),
+            dir.path().join("config.work.toml")
+        );
+        // there's no keymap for the profile, so the base one is used
+        assert_eq!(
+            resolve_profile_path(dir.path(), "keymap", Some("work")),
+            dir.path().join("keymap.toml")
+        );
+        assert_eq!(
+            resolve_profile_path(dir.path(), "config", None),
+            dir.path().join("config.toml")
+        );
+    }
+
+    #[test]
+    fn probabilities_out_of_range_are_rejected() {
+        assert_eq!(parse_probability("0"), Ok(0.0));
+        assert_eq!(parse_probability("0.5"), Ok(0.5));
+        assert_eq!(parse_probability("1"), Ok(1.0));
+        for value in ["1.5", "-0.1", "NaN", "nope"] {
+            assert!(parse_probability(value).is_err(), "{value} was accepted");
+        }
+    }
+}